@@ -1,4 +1,4 @@
-use common::{self, api, service};
+use common::{self, api, audit, connector, limits, policy, service, upstream};
 use std::{ffi, fmt, mem, sync, thread, time};
 use svc::Handler;
 use windows_sys as ws;
@@ -8,25 +8,65 @@ mod svc;
 const TO_SVC_CHANNEL_SIZE: usize = 256;
 
 enum Error {
+    Audit(audit::Error),
+    Connector(connector::Error),
+    Limits(limits::Error),
+    Policy(policy::Error),
     Svc(svc::Error),
+    Upstream(upstream::Error),
     PipelineBroken,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
+            Self::Audit(e) => write!(f, "audit log error: {e}"),
+            Self::Connector(e) => write!(f, "connector error: {e}"),
+            Self::Limits(e) => write!(f, "limits error: {e}"),
+            Self::Policy(e) => write!(f, "policy error: {e}"),
             Self::Svc(e) => write!(f, "virtual channel error: {e}"),
+            Self::Upstream(e) => write!(f, "upstream proxy error: {e}"),
             Self::PipelineBroken => write!(f, "broken pipeline"),
         }
     }
 }
 
+impl From<audit::Error> for Error {
+    fn from(e: audit::Error) -> Self {
+        Self::Audit(e)
+    }
+}
+
+impl From<connector::Error> for Error {
+    fn from(e: connector::Error) -> Self {
+        Self::Connector(e)
+    }
+}
+
+impl From<limits::Error> for Error {
+    fn from(e: limits::Error) -> Self {
+        Self::Limits(e)
+    }
+}
+
+impl From<policy::Error> for Error {
+    fn from(e: policy::Error) -> Self {
+        Self::Policy(e)
+    }
+}
+
 impl From<svc::Error> for Error {
     fn from(e: svc::Error) -> Self {
         Self::Svc(e)
     }
 }
 
+impl From<upstream::Error> for Error {
+    fn from(e: upstream::Error) -> Self {
+        Self::Upstream(e)
+    }
+}
+
 impl From<crossbeam_channel::RecvError> for Error {
     fn from(_e: crossbeam_channel::RecvError) -> Self {
         Self::PipelineBroken
@@ -210,6 +250,12 @@ fn main_res() -> Result<(), Error> {
         }
     }
 
+    policy::init_from_env()?;
+    connector::init_from_env()?;
+    limits::init_from_env()?;
+    audit::init_from_env()?;
+    upstream::init_from_env()?;
+
     let lib = svc::Implementation::load()?;
     let svc = svc::Svc::load(&lib)?;
 
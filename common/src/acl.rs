@@ -0,0 +1,62 @@
+//! Access control lists of the frontend listeners, filtering clients
+//! by their IP address.
+
+use crate::cidr::Cidr;
+use std::{fmt, net, str::FromStr};
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidCidr(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::InvalidCidr(s) => write!(f, "invalid ACL CIDR {s:?}"),
+        }
+    }
+}
+
+fn parse_cidrs<S: AsRef<str>>(cidrs: &[S]) -> Result<Vec<Cidr>, Error> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            let cidr = cidr.as_ref().trim();
+            Cidr::from_str(cidr).map_err(|()| Error::InvalidCidr(cidr.to_string()))
+        })
+        .collect()
+}
+
+/// A client is accepted if its address matches no `deny` entry and,
+/// when `allow` is not empty, matches one of its entries.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn new<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Result<Self, Error> {
+        Ok(Self {
+            allow: parse_cidrs(allow)?,
+            deny: parse_cidrs(deny)?,
+        })
+    }
+
+    /// Completes a per service ACL with the global one: the global
+    /// allow list applies only if the service has none, deny lists
+    /// add up.
+    #[must_use]
+    pub fn or(mut self, global: &Self) -> Self {
+        if self.allow.is_empty() {
+            self.allow.clone_from(&global.allow);
+        }
+        self.deny.extend_from_slice(&global.deny);
+        self
+    }
+
+    pub(crate) fn is_allowed(&self, ip: net::IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
@@ -1,4 +1,3 @@
-use crate::service;
 #[cfg(feature = "frontend")]
 use std::sync;
 use std::{fmt, io};
@@ -55,6 +54,7 @@ pub enum ChunkType {
     Start,
     Data,
     End,
+    Credit,
 }
 
 impl ChunkType {
@@ -63,6 +63,7 @@ impl ChunkType {
             Self::Start => ID_START,
             Self::Data => ID_DATA,
             Self::End => ID_END,
+            Self::Credit => ID_CREDIT,
         }
     }
 }
@@ -73,13 +74,22 @@ impl fmt::Display for ChunkType {
             Self::Start => write!(fmt, "Start"),
             Self::Data => write!(fmt, "Data"),
             Self::End => write!(fmt, "End"),
+            Self::Credit => write!(fmt, "Credit"),
         }
     }
 }
 
+/// Version of the chunks, announced in the end chunks answering data
+/// for an unknown stream. Peers announcing none only understand start
+/// chunks carrying the bare name of the service; version 1 adds the
+/// forwarded options and the first data of the stream, version 2 the
+/// credit chunks bounding the data sent ahead of the reader of a stream.
+pub const VERSION: u8 = 2;
+
 const ID_START: u8 = 0x00;
 const ID_DATA: u8 = 0x01;
 const ID_END: u8 = 0x02;
+const ID_CREDIT: u8 = 0x03;
 
 pub type ClientId = u32;
 
@@ -122,8 +132,24 @@ impl Chunk {
         Ok(Self(content))
     }
 
-    pub fn start(client_id: ClientId, service: &service::Service) -> Result<Self, io::Error> {
-        Self::new(ChunkType::Start, client_id, Some(service.name().as_bytes()))
+    /// Start chunk of a stream, `header` being the name of the service
+    /// possibly followed by its forwarded options.
+    pub fn start(client_id: ClientId, header: &[u8]) -> Result<Self, io::Error> {
+        Self::new(ChunkType::Start, client_id, Some(header))
+    }
+
+    /// Start chunk also carrying the first data of the stream, after
+    /// the header and a NUL separator. Returns `None` if `data` does not
+    /// fit.
+    pub fn start_with_data(client_id: ClientId, header: &[u8], data: &[u8]) -> Option<Self> {
+        if Self::max_payload_length() < header.len() + 1 + data.len() {
+            return None;
+        }
+        let mut payload = Vec::with_capacity(header.len() + 1 + data.len());
+        payload.extend_from_slice(header);
+        payload.push(0x00);
+        payload.extend_from_slice(data);
+        Self::new(ChunkType::Start, client_id, Some(&payload)).ok()
     }
 
     pub fn data(client_id: ClientId, data: &[u8]) -> Result<Self, io::Error> {
@@ -135,6 +161,33 @@ impl Chunk {
         Self::new(ChunkType::End, client_id, None).expect("infaillible")
     }
 
+    /// End chunk answering data for a stream the sender does not know
+    /// of, announcing [`VERSION`].
+    #[allow(clippy::missing_panics_doc)]
+    pub fn end_unknown(client_id: ClientId) -> Self {
+        Self::new(ChunkType::End, client_id, Some(&[VERSION])).expect("infaillible")
+    }
+
+    /// Credit chunk allowing the other side to send `count` more data
+    /// chunks for the stream.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn credit(client_id: ClientId, count: u16) -> Self {
+        Self::new(ChunkType::Credit, client_id, Some(&count.to_le_bytes())).expect("infaillible")
+    }
+
+    /// Data chunks allowed by a credit chunk.
+    pub fn credits(&self) -> u16 {
+        match self.payload() {
+            [low, high, ..] => u16::from_le_bytes([*low, *high]),
+            _ => 0,
+        }
+    }
+
+    /// Version announced by an end chunk, 0 if there is none.
+    pub fn version(&self) -> u8 {
+        self.payload().first().copied().unwrap_or(0)
+    }
+
     pub fn client_id(&self) -> ClientId {
         let bytes = [self.0[0], self.0[1], self.0[2], self.0[3]];
         u32::from_le_bytes(bytes)
@@ -145,6 +198,7 @@ impl Chunk {
             Some(&ID_START) => Ok(ChunkType::Start),
             Some(&ID_DATA) => Ok(ChunkType::Data),
             Some(&ID_END) => Ok(ChunkType::End),
+            Some(&ID_CREDIT) => Ok(ChunkType::Credit),
             b => Err(Error::InvalidChunkType(b.copied())),
         }
     }
@@ -0,0 +1,122 @@
+//! Audit trail of the operations done through the services: shell
+//! sessions, file transfers, clipboard accesses, proxied destinations
+//! and stage0 pushes.
+//!
+//! Each operation is a JSON object on its own line, written to a file
+//! separate from the debug log. The frontend gets the path of this file
+//! from its configuration, the backend from the `SOXY_AUDIT_LOG`
+//! environment variable. Nothing is recorded by default.
+
+use crate::{api, json, service};
+#[cfg(feature = "backend")]
+use std::env;
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    net, process, sync, time,
+};
+
+#[cfg(feature = "backend")]
+const ENV_AUDIT_LOG: &str = "SOXY_AUDIT_LOG";
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyInitialized,
+    Io(String, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::AlreadyInitialized => write!(f, "audit log already initialized"),
+            Self::Io(path, e) => write!(f, "failed to open {path:?}: {e}"),
+        }
+    }
+}
+
+struct Log {
+    file: sync::Mutex<fs::File>,
+    // tells apart the runs of soxy writing to the same file
+    session: String,
+}
+
+static LOG: sync::OnceLock<Log> = sync::OnceLock::new();
+
+pub fn init(path: &str) -> Result<(), Error> {
+    let file = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| Error::Io(path.to_string(), e))?;
+
+    let started = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let session = format!("{:x}-{:x}", process::id(), started.as_millis());
+
+    crate::info!("audit log of session {session} in {path:?}");
+
+    LOG.set(Log {
+        file: sync::Mutex::new(file),
+        session,
+    })
+    .map_err(|_| Error::AlreadyInitialized)
+}
+
+/// Opens the audit log of the backend named by the environment
+/// variable at startup, or by the same variable at build time.
+#[cfg(feature = "backend")]
+pub fn init_from_env() -> Result<(), Error> {
+    match env::var(ENV_AUDIT_LOG)
+        .ok()
+        .or_else(|| option_env!("SOXY_AUDIT_LOG").map(ToString::to_string))
+    {
+        None => Ok(()),
+        Some(path) => init(&path),
+    }
+}
+
+pub(crate) enum Value<'a> {
+    Bool(bool),
+    Size(u64),
+    Text(&'a str),
+}
+
+/// Records an operation on a stream of `service`, along with its
+/// specific `fields`. `peer` is the address of the client when known.
+pub(crate) fn record(
+    kind: service::Kind,
+    service: &service::Service,
+    client_id: api::ClientId,
+    peer: Option<net::SocketAddr>,
+    event: &str,
+    fields: &[(&str, Value<'_>)],
+) {
+    let Some(log) = LOG.get() else {
+        return;
+    };
+
+    let mut line = json::Object::new();
+    line.string("time", &json::timestamp(time::SystemTime::now()))
+        .string("session", &log.session)
+        .string("side", &kind.to_string())
+        .string("service", service.name())
+        .string("client_id", &format!("{client_id:x}"));
+    if let Some(peer) = peer {
+        line.string("client", &peer.to_string());
+    }
+    line.string("event", event);
+    for (name, value) in fields {
+        match value {
+            Value::Bool(b) => line.bool(name, *b),
+            Value::Size(n) => line.number(name, *n),
+            Value::Text(s) => line.string(name, s),
+        };
+    }
+    let line = line.line();
+
+    let mut file = log.file.lock().unwrap();
+    if let Err(e) = file.write_all(line.as_bytes()) {
+        crate::error!("failed to write audit log: {e}");
+    }
+}
@@ -0,0 +1,99 @@
+//! Optional shared-secret login of the telnet-like services, so that
+//! other local users cannot use them.
+
+use crate::service;
+use std::{
+    io::{self, BufRead, Read, Write},
+    thread, time,
+};
+
+const AUTH_COMMAND: &str = "AUTH";
+// with the line break
+const MAX_LINE_LENGTH: u64 = 1024;
+// slows down guessing
+pub const FAILURE_DELAY: time::Duration = time::Duration::from_secs(1);
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, io::Error> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LENGTH).read_line(&mut line)?;
+    let Some(line) = line.strip_suffix('\n') else {
+        return Err(if line.len() as u64 == MAX_LINE_LENGTH {
+            io::Error::new(io::ErrorKind::InvalidData, "line too long")
+        } else {
+            io::Error::new(io::ErrorKind::BrokenPipe, "interrupted")
+        });
+    };
+    Ok(line.strip_suffix('\r').unwrap_or(line).to_string())
+}
+
+fn check<W: Write>(
+    server: &service::TcpFrontendServer,
+    token: &str,
+    given: &str,
+    writer: &mut W,
+) -> Result<bool, io::Error> {
+    if constant_time_eq(token.as_bytes(), given.as_bytes()) {
+        writeln!(writer, "ok")?;
+        writer.flush()?;
+        return Ok(true);
+    }
+
+    crate::warn!("authentication failed on {}", server.service());
+    thread::sleep(FAILURE_DELAY);
+    writeln!(writer, "authentication failed")?;
+    writer.flush()?;
+    Ok(false)
+}
+
+/// Expects an `AUTH <token>` line first when the service is protected
+/// by a token.
+pub(crate) fn auth_line<R, W>(
+    server: &service::TcpFrontendServer,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<bool, io::Error>
+where
+    R: BufRead,
+    W: Write,
+{
+    let Some(token) = server.token() else {
+        return Ok(true);
+    };
+
+    let line = read_line(reader)?;
+    let given = match line.split_once(' ') {
+        Some((command, given)) if command.eq_ignore_ascii_case(AUTH_COMMAND) => given,
+        _ => {
+            writeln!(writer, "authentication required")?;
+            writer.flush()?;
+            return Ok(false);
+        }
+    };
+
+    check(server, token, given, writer)
+}
+
+/// Prompts for the token when the service is protected by one.
+pub(crate) fn prompt<R, W>(
+    server: &service::TcpFrontendServer,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<bool, io::Error>
+where
+    R: BufRead,
+    W: Write,
+{
+    let Some(token) = server.token() else {
+        return Ok(true);
+    };
+
+    write!(writer, "token: ")?;
+    writer.flush()?;
+
+    let given = read_line(reader)?;
+    check(server, token, &given, writer)
+}
@@ -0,0 +1,45 @@
+//! Calendar dates of system times, as written in the logs and by the
+//! FTP service.
+
+use std::time;
+
+/// Calendar date and time of day in UTC.
+pub(crate) struct DateTime {
+    pub(crate) year: u64,
+    pub(crate) month: u64,
+    pub(crate) day: u64,
+    pub(crate) hour: u64,
+    pub(crate) minute: u64,
+    pub(crate) second: u64,
+    pub(crate) millis: u32,
+}
+
+impl DateTime {
+    pub(crate) fn utc(time: time::SystemTime) -> Self {
+        let elapsed = time.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let secs = elapsed.as_secs();
+        let (days, secs) = (secs / 86400, secs % 86400);
+
+        // civil date from the number of days since 1970-01-01
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            minute: (secs % 3600) / 60,
+            second: secs % 60,
+            millis: elapsed.subsec_millis(),
+        }
+    }
+}
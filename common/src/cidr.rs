@@ -0,0 +1,109 @@
+use std::{net, str::FromStr};
+
+/// IP network given as `address/length`, or a single address.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cidr {
+    net: net::IpAddr,
+    len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (net, len) = match s.split_once('/') {
+            None => {
+                let net = net::IpAddr::from_str(s).map_err(|_| ())?;
+                let len = if net.is_ipv4() { 32 } else { 128 };
+                (net, len)
+            }
+            Some((net, len)) => {
+                let net = net::IpAddr::from_str(net).map_err(|_| ())?;
+                let len = u8::from_str(len).map_err(|_| ())?;
+                let max = if net.is_ipv4() { 32 } else { 128 };
+                if max < len {
+                    return Err(());
+                }
+                (net, len)
+            }
+        };
+        Ok(Self { net, len })
+    }
+}
+
+impl Cidr {
+    /// IPv4-mapped IPv6 addresses match IPv4 networks.
+    pub(crate) fn contains(&self, ip: net::IpAddr) -> bool {
+        let ip = match ip {
+            net::IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map_or(net::IpAddr::V6(ip), net::IpAddr::V4),
+            ip @ net::IpAddr::V4(_) => ip,
+        };
+        match (self.net, ip) {
+            (net::IpAddr::V4(net), net::IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.len)).unwrap_or(0);
+                net.to_bits() & mask == ip.to_bits() & mask
+            }
+            (net::IpAddr::V6(net), net::IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.len))
+                    .unwrap_or(0);
+                net.to_bits() & mask == ip.to_bits() & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cidr;
+    use std::{net, str::FromStr};
+
+    fn contains(cidr: &str, ip: &str) -> bool {
+        Cidr::from_str(cidr)
+            .unwrap()
+            .contains(net::IpAddr::from_str(ip).unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        assert!(Cidr::from_str("10.0.0.0/8").is_ok());
+        assert!(Cidr::from_str("10.0.0.1").is_ok());
+        assert!(Cidr::from_str("fd00::/8").is_ok());
+        assert!(Cidr::from_str("0.0.0.0/0").is_ok());
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("fd00::/129").is_err());
+        assert!(Cidr::from_str("10.0.0.0/").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+        assert!(Cidr::from_str("example.org").is_err());
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.7", "192.168.1.7"));
+        assert!(!contains("192.168.1.7", "192.168.1.8"));
+        assert!(contains("0.0.0.0/0", "203.0.113.1"));
+        assert!(contains("192.168.1.0/31", "192.168.1.1"));
+        assert!(!contains("192.168.1.0/31", "192.168.1.2"));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("::1", "::1"));
+    }
+
+    #[test]
+    fn contains_mapped() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+        assert!(!contains("::/0", "10.1.2.3"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+    }
+}
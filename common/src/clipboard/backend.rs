@@ -1,11 +1,26 @@
 use super::protocol;
-use crate::service;
+use crate::{audit, service};
 use copyrs::Clipboard;
 use std::{borrow, io};
 
 pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
     crate::debug!("starting");
 
+    let client_id = stream.client_id();
+    let record = |event, ok, size| {
+        audit::record(
+            service::Kind::Backend,
+            &super::SERVICE,
+            client_id,
+            None,
+            event,
+            &[
+                ("ok", audit::Value::Bool(ok)),
+                ("size", audit::Value::Size(size)),
+            ],
+        );
+    };
+
     loop {
         let cmd = protocol::Command::receive(&mut stream)?;
 
@@ -16,19 +31,23 @@ pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Erro
                 match copyrs::clipboard() {
                     Err(e) => {
                         crate::error!("failed to get clipboard: {e}");
+                        record("read", false, 0);
                         protocol::Response::Failed.send(&mut stream)?;
                     }
                     Ok(clipboard) => match clipboard.get_content() {
                         Err(e) => {
                             crate::error!("failed to get clipboard content: {e}");
+                            record("read", false, 0);
                             protocol::Response::Failed.send(&mut stream)?;
                         }
                         Ok(content) => match content.kind {
                             copyrs::ClipboardContentKind::Image => {
                                 crate::error!("clipboard contrent is an image, not text");
+                                record("read", false, 0);
                                 protocol::Response::Failed.send(&mut stream)?;
                             }
                             copyrs::ClipboardContentKind::Text => {
+                                record("read", true, content.data.len() as u64);
                                 protocol::Response::Text(content.data).send(&mut stream)?;
                             }
                         },
@@ -39,22 +58,26 @@ pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Erro
             protocol::Command::WriteText(value) => {
                 crate::debug!("write_text {value:?}");
 
+                let size = value.len() as u64;
+
                 match copyrs::clipboard() {
                     Err(e) => {
                         crate::error!("failed to get clipboard: {e}");
+                        record("write", false, size);
                         protocol::Response::Failed.send(&mut stream)?;
                     }
                     Ok(mut clipboard) => {
                         let value = borrow::Cow::Borrowed(value.as_slice());
 
-                        match clipboard.set_content(value, copyrs::ClipboardContentKind::Text) {
-                            Err(e) => {
-                                crate::error!("failed to set clipboard: {e}");
-                                protocol::Response::Failed.send(&mut stream)?;
-                            }
-                            Ok(()) => {
-                                protocol::Response::WriteDone.send(&mut stream)?;
-                            }
+                        if let Err(e) =
+                            clipboard.set_content(value, copyrs::ClipboardContentKind::Text)
+                        {
+                            crate::error!("failed to set clipboard: {e}");
+                            record("write", false, size);
+                            protocol::Response::Failed.send(&mut stream)?;
+                        } else {
+                            record("write", true, size);
+                            protocol::Response::WriteDone.send(&mut stream)?;
                         }
                     }
                 }
@@ -1,22 +1,43 @@
 use super::protocol;
-use crate::service;
+use crate::{audit, auth, service};
 use std::{
     io::{self, BufRead, Write},
     net, thread,
 };
 
 pub(crate) fn tcp_handler<'a>(
-    _server: &service::TcpFrontendServer,
+    server: &service::TcpFrontendServer,
     _scope: &'a thread::Scope<'a, '_>,
-    stream: net::TcpStream,
+    stream: service::ClientStream,
     channel: &'a service::Channel,
 ) -> Result<(), io::Error> {
+    let peer = stream.peer_addr().ok();
     let lstream = stream.try_clone()?;
     let mut client_read = io::BufReader::new(lstream);
 
     let mut client_write = io::BufWriter::new(stream);
 
+    if !auth::auth_line(server, &mut client_read, &mut client_write)? {
+        let lstream = client_read.into_inner();
+        let _ = lstream.shutdown(net::Shutdown::Both);
+        return Ok(());
+    }
+
     let mut rdp = channel.connect(&super::SERVICE)?;
+    let client_id = rdp.client_id();
+    let record = |event, ok, size| {
+        audit::record(
+            service::Kind::Frontend,
+            &super::SERVICE,
+            client_id,
+            peer,
+            event,
+            &[
+                ("ok", audit::Value::Bool(ok)),
+                ("size", audit::Value::Size(size)),
+            ],
+        );
+    };
 
     let mut line = String::new();
 
@@ -48,22 +69,27 @@ pub(crate) fn tcp_handler<'a>(
                 protocol::Command::Read.send(&mut rdp)?;
                 match protocol::Response::receive(&mut rdp)? {
                     protocol::Response::Text(value) => {
+                        record("read", true, value.len() as u64);
                         let value = String::from_utf8_lossy(&value);
                         writeln!(client_write, "ok {value:?}")?;
                     }
                     protocol::Response::Failed => {
+                        record("read", false, 0);
                         writeln!(client_write, "KO")?;
                     }
                     protocol::Response::WriteDone => unreachable!(),
                 }
             }
             "WRITE" | "PUT" => {
+                let size = args.len() as u64;
                 protocol::Command::WriteText(args.into_bytes()).send(&mut rdp)?;
                 match protocol::Response::receive(&mut rdp)? {
                     protocol::Response::WriteDone => {
+                        record("write", true, size);
                         writeln!(client_write, "ok")?;
                     }
                     protocol::Response::Failed => {
+                        record("write", false, size);
                         writeln!(client_write, "KO")?;
                     }
                     protocol::Response::Text(_) => unreachable!(),
@@ -1,23 +1,34 @@
-use crate::service;
+use crate::{audit, options, service};
 use std::{io, process, thread};
 
 pub(crate) fn backend_handler(rdp_stream: service::RdpStream<'_>) -> Result<(), io::Error> {
     let client_id = rdp_stream.client_id();
 
     #[cfg(target_os = "windows")]
-    let cmd = "cmd.exe";
+    let (default_cmd, default_args): (_, &[&str]) = ("cmd.exe", &[]);
     #[cfg(not(target_os = "windows"))]
-    let cmd = "sh";
+    let (default_cmd, default_args): (_, &[&str]) = ("sh", &["-i"]);
 
-    #[cfg(target_os = "windows")]
-    let args: [String; 0] = [];
-    #[cfg(not(target_os = "windows"))]
-    let args = ["-i"];
+    // a configured shell is run without arguments
+    let (cmd, args) = match rdp_stream.options() {
+        options::Options::Command(options::Command { shell: Some(shell) }) => {
+            (shell.clone(), &[][..])
+        }
+        _ => (default_cmd.to_string(), default_args),
+    };
 
     crate::debug!("starting {cmd:?}");
 
-    thread::scope(|scope| {
-        let child = process::Command::new(cmd)
+    audit::record(
+        service::Kind::Backend,
+        &super::SERVICE,
+        client_id,
+        None,
+        "session_start",
+        &[("shell", audit::Value::Text(&cmd))],
+    );
+    let res = thread::scope(|scope| {
+        let child = process::Command::new(&cmd)
             .args(args)
             .stdin(process::Stdio::piped())
             .stdout(process::Stdio::piped())
@@ -75,5 +86,15 @@ pub(crate) fn backend_handler(rdp_stream: service::RdpStream<'_>) -> Result<(),
         rdp_stream_read.disconnect();
 
         Ok(())
-    })
+    });
+    audit::record(
+        service::Kind::Backend,
+        &super::SERVICE,
+        client_id,
+        None,
+        "session_end",
+        &[],
+    );
+
+    res
 }
@@ -1,12 +1,44 @@
-use crate::service;
+use crate::{audit, auth, service};
 use std::{io, net, thread};
 
 pub(crate) fn tcp_frontend_handler(
-    _server: &service::TcpFrontendServer,
+    server: &service::TcpFrontendServer,
     _scope: &thread::Scope,
-    client: net::TcpStream,
+    client: service::ClientStream,
     channel: &service::Channel,
 ) -> Result<(), io::Error> {
+    if server.token().is_some() {
+        // byte per byte reads not to consume what follows the token
+        let mut client_read = io::BufReader::with_capacity(1, client.try_clone()?);
+        let mut client_write = client.try_clone()?;
+        if !auth::prompt(server, &mut client_read, &mut client_write)? {
+            let _ = client.shutdown(net::Shutdown::Both);
+            return Ok(());
+        }
+    }
+
     let client_rdp = channel.connect(&super::SERVICE)?;
-    service::double_stream_copy(service::Kind::Frontend, &super::SERVICE, client_rdp, client)
+    let client_id = client_rdp.client_id();
+    let peer = client.peer_addr().ok();
+
+    audit::record(
+        service::Kind::Frontend,
+        &super::SERVICE,
+        client_id,
+        peer,
+        "session_start",
+        &[],
+    );
+    let res =
+        service::double_stream_copy(service::Kind::Frontend, &super::SERVICE, client_rdp, client);
+    audit::record(
+        service::Kind::Frontend,
+        &super::SERVICE,
+        client_id,
+        peer,
+        "session_end",
+        &[],
+    );
+
+    res
 }
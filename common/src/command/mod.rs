@@ -10,8 +10,15 @@ pub(crate) static SERVICE: service::Service = service::Service {
     #[cfg(feature = "frontend")]
     tcp_frontend: Some(service::TcpFrontend {
         default_port: 3031,
+        enabled_by_default: true,
+        transparent: false,
+        token: true,
         handler: frontend::tcp_frontend_handler,
     }),
+    #[cfg(feature = "frontend")]
+    udp_frontend: None,
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tun_frontend: None,
     #[cfg(feature = "backend")]
     backend: service::Backend {
         handler: backend::backend_handler,
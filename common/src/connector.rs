@@ -0,0 +1,208 @@
+//! Outgoing TCP connections of the backend, with a bounded connect
+//! time and parallel attempts over resolved addresses in the Happy
+//! Eyeballs style (RFC 8305).
+//!
+//! The timeouts are read from environment variables:
+//!
+//! - `SOXY_CONNECT_TIMEOUT`: maximum time in seconds to establish a
+//!   connection (default 10);
+//! - `SOXY_CONNECT_ATTEMPT_DELAY`: delay in milliseconds before
+//!   starting an attempt to the next address while the previous ones
+//!   are still pending (default 250).
+
+use std::{env, fmt, io, net, sync, thread, time};
+
+const ENV_CONNECT_TIMEOUT: &str = "SOXY_CONNECT_TIMEOUT";
+const ENV_CONNECT_ATTEMPT_DELAY: &str = "SOXY_CONNECT_ATTEMPT_DELAY";
+
+const DEFAULT_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const DEFAULT_CONNECT_ATTEMPT_DELAY: time::Duration = time::Duration::from_millis(250);
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyInitialized,
+    InvalidTimeout(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::AlreadyInitialized => write!(f, "connector already initialized"),
+            Self::InvalidTimeout(s) => write!(f, "invalid connect timeout {s:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub connect: time::Duration,
+    pub attempt_delay: time::Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+        }
+    }
+}
+
+static TIMEOUTS: sync::OnceLock<Timeouts> = sync::OnceLock::new();
+
+pub fn init(timeouts: Timeouts) -> Result<(), Error> {
+    crate::info!(
+        "connect timeout {:?}, attempt delay {:?}",
+        timeouts.connect,
+        timeouts.attempt_delay
+    );
+    TIMEOUTS
+        .set(timeouts)
+        .map_err(|_| Error::AlreadyInitialized)
+}
+
+fn env_duration(
+    name: &str,
+    build_value: Option<&str>,
+    from: fn(u64) -> time::Duration,
+) -> Result<Option<time::Duration>, Error> {
+    match env::var(name)
+        .ok()
+        .or_else(|| build_value.map(ToString::to_string))
+    {
+        None => Ok(None),
+        Some(value) => match value.trim().parse::<u64>() {
+            Ok(0) | Err(_) => Err(Error::InvalidTimeout(value)),
+            Ok(value) => Ok(Some(from(value))),
+        },
+    }
+}
+
+/// Loads the timeouts from the environment variables at startup, or
+/// from the same variables at build time.
+pub fn init_from_env() -> Result<(), Error> {
+    let mut timeouts = Timeouts::default();
+
+    if let Some(connect) = env_duration(
+        ENV_CONNECT_TIMEOUT,
+        option_env!("SOXY_CONNECT_TIMEOUT"),
+        time::Duration::from_secs,
+    )? {
+        timeouts.connect = connect;
+    }
+
+    if let Some(attempt_delay) = env_duration(
+        ENV_CONNECT_ATTEMPT_DELAY,
+        option_env!("SOXY_CONNECT_ATTEMPT_DELAY"),
+        time::Duration::from_millis,
+    )? {
+        timeouts.attempt_delay = attempt_delay;
+    }
+
+    init(timeouts)
+}
+
+pub(crate) fn timeouts() -> Timeouts {
+    TIMEOUTS.get().copied().unwrap_or_default()
+}
+
+/// Sorts addresses alternating families, starting with the family of
+/// the first address returned by the resolver.
+fn interleave(addrs: &[net::SocketAddr]) -> Vec<net::SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return vec![];
+    };
+
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv4() == first.is_ipv4());
+    preferred.reverse();
+    other.reverse();
+
+    let mut sorted = Vec::with_capacity(addrs.len());
+    while let Some(addr) = preferred.pop() {
+        sorted.push(addr);
+        if let Some(addr) = other.pop() {
+            sorted.push(addr);
+        }
+    }
+    sorted.extend(other.into_iter().rev());
+    sorted
+}
+
+/// Connects to the first reachable address of `addrs`. A new attempt
+/// is started every attempt delay or as soon as the previous attempt
+/// fails, and the first established connection wins.
+pub(crate) fn connect(addrs: &[net::SocketAddr]) -> Result<net::TcpStream, io::Error> {
+    let timeouts = timeouts();
+    let deadline = time::Instant::now() + timeouts.connect;
+
+    let mut pending = interleave(addrs).into_iter();
+    let (send, receive) = crossbeam_channel::unbounded();
+    let mut in_flight = 0usize;
+    let mut next_attempt = time::Instant::now();
+    let mut last_error = None;
+
+    loop {
+        let now = time::Instant::now();
+        if deadline <= now {
+            break;
+        }
+
+        let addr = if next_attempt <= now {
+            pending.next()
+        } else {
+            None
+        };
+
+        if let Some(addr) = addr {
+            crate::trace!("connecting to {addr}");
+            let send = send.clone();
+            let timeout = deadline - now;
+            thread::Builder::new()
+                .name(format!("connect {addr}"))
+                .spawn(move || {
+                    let res = net::TcpStream::connect_timeout(&addr, timeout);
+                    // late connections are dropped once a winner is found
+                    let _ = send.send((addr, res));
+                })?;
+            in_flight += 1;
+            next_attempt = now + timeouts.attempt_delay;
+            continue;
+        }
+
+        if in_flight == 0 {
+            break;
+        }
+
+        let wait = if pending.len() == 0 {
+            deadline
+        } else {
+            next_attempt.min(deadline)
+        };
+
+        match receive.recv_deadline(wait) {
+            Err(_) => (),
+            Ok((_, Ok(stream))) => return Ok(stream),
+            Ok((addr, Err(e))) => {
+                crate::debug!("failed to connect to {addr}: {e}");
+                in_flight -= 1;
+                last_error = Some(e);
+                // do not wait for the delay to try the next address
+                next_attempt = time::Instant::now();
+            }
+        }
+    }
+
+    if in_flight == 0 && pending.len() == 0 {
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        }))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection timed out",
+        ))
+    }
+}
@@ -0,0 +1,294 @@
+use super::{message, protocol};
+use crate::service;
+#[cfg(not(target_os = "windows"))]
+use std::fs;
+use std::{
+    collections::hash_map,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::{self, ToSocketAddrs},
+    str::FromStr,
+    time,
+};
+
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+const MAX_UDP_ANSWER_SIZE: usize = 4096;
+
+#[cfg(not(target_os = "windows"))]
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+#[cfg(not(target_os = "windows"))]
+fn nameservers() -> Vec<net::SocketAddr> {
+    fs::read_to_string(RESOLV_CONF)
+        .map(|content| {
+            content
+                .lines()
+                .filter_map(|line| line.trim().strip_prefix("nameserver"))
+                .filter_map(|server| {
+                    let server = server.trim();
+                    // drop the scope of link-local IPv6 addresses
+                    let server = server.split_once('%').map_or(server, |(ip, _)| ip);
+                    net::IpAddr::from_str(server).ok()
+                })
+                .map(|ip| net::SocketAddr::new(ip, DNS_PORT))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(target_os = "windows")]
+fn nameservers() -> Vec<net::SocketAddr> {
+    use std::{ffi, mem, ptr};
+    use windows_sys::Win32::{Foundation, NetworkManagement::IpHelper};
+
+    let mut len = 0u32;
+    let ret = unsafe { IpHelper::GetNetworkParams(ptr::null_mut(), &raw mut len) };
+    if ret != Foundation::ERROR_BUFFER_OVERFLOW {
+        return vec![];
+    }
+
+    // u64 elements to have a correctly aligned buffer
+    let mut buf = vec![0u64; (len as usize).div_ceil(mem::size_of::<u64>())];
+    let info = buf.as_mut_ptr().cast::<IpHelper::FIXED_INFO_W2KSP1>();
+    let ret = unsafe { IpHelper::GetNetworkParams(info, &raw mut len) };
+    if ret != Foundation::ERROR_SUCCESS {
+        return vec![];
+    }
+
+    let mut servers = vec![];
+    let mut server: *const IpHelper::IP_ADDR_STRING = unsafe { &raw const (*info).DnsServerList };
+    while !server.is_null() {
+        let address = unsafe { &(*server).IpAddress.String };
+        let address = unsafe { ffi::CStr::from_ptr(address.as_ptr()) };
+        if let Ok(ip) = net::IpAddr::from_str(&address.to_string_lossy()) {
+            servers.push(net::SocketAddr::new(ip, DNS_PORT));
+        }
+        server = unsafe { (*server).Next };
+    }
+    servers
+}
+
+fn query_tcp(server: net::SocketAddr, query: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut stream = net::TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+
+    let len = u16::try_from(query.len())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut buf = Vec::with_capacity(2 + query.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(query);
+    stream.write_all(&buf)?;
+
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    let len = u16::from_be_bytes(buf);
+
+    let mut answer = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut answer)?;
+
+    Ok(answer)
+}
+
+fn query_udp(server: net::SocketAddr, query: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let local: net::SocketAddr = if server.is_ipv4() {
+        (net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    let socket = net::UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(query)?;
+
+    let mut buf = vec![0u8; MAX_UDP_ANSWER_SIZE];
+    loop {
+        let read = socket.recv(&mut buf)?;
+        let answer = &buf[0..read];
+        if message::same_id(query, answer) {
+            if message::is_truncated(answer) {
+                crate::debug!("truncated answer from {server}, retrying over TCP");
+                return query_tcp(server, query);
+            }
+            return Ok(answer.to_vec());
+        }
+        crate::debug!("discarding unexpected answer from {server}");
+    }
+}
+
+/// Forwards the query to the DNS servers configured on the remote
+/// host.
+fn forward(query: &[u8]) -> Option<Vec<u8>> {
+    nameservers()
+        .into_iter()
+        .find_map(|server| match query_udp(server, query) {
+            Err(e) => {
+                crate::debug!("query to {server} failed: {e}");
+                None
+            }
+            Ok(answer) => Some(answer),
+        })
+}
+
+/// Falls back on the system resolver, which can only answer address
+/// queries.
+fn system_resolve(query: &[u8]) -> Option<Vec<u8>> {
+    let question = message::Question::parse(query)?;
+
+    crate::debug!(
+        "resolving {:?} (type {}) with system resolver",
+        question.name,
+        question.qtype
+    );
+
+    if !matches!(question.qtype, message::TYPE_A | message::TYPE_AAAA) {
+        return None;
+    }
+
+    match (question.name.as_str(), 0).to_socket_addrs() {
+        Err(e) => {
+            crate::debug!("failed to resolve {:?}: {e}", question.name);
+            message::error_reply(query, message::RCODE_NXDOMAIN)
+        }
+        Ok(addrs) => {
+            let ips = addrs.map(|addr| addr.ip()).collect::<Vec<_>>();
+            message::address_reply(query, &ips)
+        }
+    }
+}
+
+/// Unpredictable query ID, making forged answers harder to get
+/// accepted. The keys of the standard hasher are drawn from the
+/// randomness source of the system.
+fn query_id() -> u16 {
+    let mut hasher = hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    let [hi, lo, ..] = hasher.finish().to_be_bytes();
+    u16::from_be_bytes([hi, lo])
+}
+
+#[cfg(target_os = "linux")]
+fn system_reverse(ip: net::IpAddr) -> Option<String> {
+    use std::{ffi, mem, ptr};
+
+    let mut v4: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut v6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let (addr, len) = match ip {
+        net::IpAddr::V4(ip) => {
+            v4.sin_family = libc::sa_family_t::try_from(libc::AF_INET).ok()?;
+            v4.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+            (
+                (&raw const v4).cast::<libc::sockaddr>(),
+                mem::size_of::<libc::sockaddr_in>(),
+            )
+        }
+        net::IpAddr::V6(ip) => {
+            v6.sin6_family = libc::sa_family_t::try_from(libc::AF_INET6).ok()?;
+            v6.sin6_addr.s6_addr = ip.octets();
+            (
+                (&raw const v6).cast::<libc::sockaddr>(),
+                mem::size_of::<libc::sockaddr_in6>(),
+            )
+        }
+    };
+
+    let mut host = [0 as libc::c_char; 1025];
+    let ret = unsafe {
+        libc::getnameinfo(
+            addr,
+            libc::socklen_t::try_from(len).ok()?,
+            host.as_mut_ptr(),
+            libc::socklen_t::try_from(host.len()).ok()?,
+            ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        crate::debug!("no name for {ip} from system resolver ({ret})");
+        return None;
+    }
+
+    let host = unsafe { ffi::CStr::from_ptr(host.as_ptr()) };
+    Some(host.to_string_lossy().to_string())
+}
+
+#[cfg(target_os = "windows")]
+fn system_reverse(ip: net::IpAddr) -> Option<String> {
+    use std::{ffi, mem, ptr};
+    use windows_sys::Win32::Networking::WinSock;
+
+    let mut v4: WinSock::SOCKADDR_IN = unsafe { mem::zeroed() };
+    let mut v6: WinSock::SOCKADDR_IN6 = unsafe { mem::zeroed() };
+    let (addr, len) = match ip {
+        net::IpAddr::V4(ip) => {
+            v4.sin_family = WinSock::AF_INET;
+            v4.sin_addr.S_un.S_addr = u32::from_ne_bytes(ip.octets());
+            (
+                (&raw const v4).cast::<WinSock::SOCKADDR>(),
+                mem::size_of::<WinSock::SOCKADDR_IN>(),
+            )
+        }
+        net::IpAddr::V6(ip) => {
+            v6.sin6_family = WinSock::AF_INET6;
+            v6.sin6_addr.u.Byte = ip.octets();
+            (
+                (&raw const v6).cast::<WinSock::SOCKADDR>(),
+                mem::size_of::<WinSock::SOCKADDR_IN6>(),
+            )
+        }
+    };
+
+    let mut host = [0u8; WinSock::NI_MAXHOST as usize];
+    let ret = unsafe {
+        WinSock::getnameinfo(
+            addr,
+            i32::try_from(len).ok()?,
+            host.as_mut_ptr(),
+            u32::try_from(host.len()).ok()?,
+            ptr::null_mut(),
+            0,
+            i32::try_from(WinSock::NI_NAMEREQD).ok()?,
+        )
+    };
+    if ret != 0 {
+        crate::debug!("no name for {ip} from system resolver ({ret})");
+        return None;
+    }
+
+    let host = unsafe { ffi::CStr::from_ptr(host.as_ptr().cast()) };
+    Some(host.to_string_lossy().to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn system_reverse(_ip: net::IpAddr) -> Option<String> {
+    None
+}
+
+/// Reverse lookup of `ip` through the DNS servers configured on the
+/// remote host, falling back on its system resolver.
+pub(crate) fn reverse(ip: net::IpAddr) -> Option<String> {
+    message::ptr_query(query_id(), ip)
+        .and_then(|query| forward(&query))
+        .and_then(|answer| message::ptr_answer(&answer))
+        .or_else(|| system_reverse(ip))
+}
+
+pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
+    crate::debug!("starting");
+
+    let protocol::Command::Query(query) = protocol::Command::receive(&mut stream)?;
+
+    match forward(&query).or_else(|| system_resolve(&query)) {
+        None => protocol::Response::Failed.send(&mut stream)?,
+        Some(answer) => protocol::Response::Answer(answer).send(&mut stream)?,
+    }
+
+    stream.disconnect()
+}
@@ -0,0 +1,84 @@
+use super::{message, protocol};
+use crate::service;
+use std::{
+    io::{self, Read, Write},
+    net, thread,
+};
+
+fn forward(channel: &service::Channel, query: Vec<u8>) -> Result<protocol::Response, io::Error> {
+    let mut rdp = channel.connect(&super::SERVICE)?;
+
+    protocol::Command::Query(query).send(&mut rdp)?;
+    let response = protocol::Response::receive(&mut rdp)?;
+
+    let _ = rdp.disconnect();
+
+    Ok(response)
+}
+
+fn resolve(channel: &service::Channel, query: Vec<u8>) -> Option<Vec<u8>> {
+    let failed = message::error_reply(&query, message::RCODE_SERVFAIL);
+
+    match forward(channel, query) {
+        Ok(protocol::Response::Answer(answer)) => Some(answer),
+        Ok(protocol::Response::Failed) => {
+            crate::debug!("remote resolution failed");
+            failed
+        }
+        Err(e) => {
+            crate::debug!("error: {e}");
+            failed
+        }
+    }
+}
+
+pub(crate) fn tcp_handler(
+    _server: &service::TcpFrontendServer,
+    _scope: &thread::Scope,
+    stream: service::ClientStream,
+    channel: &service::Channel,
+) -> Result<(), io::Error> {
+    let lstream = stream.try_clone()?;
+    let mut client_read = io::BufReader::new(lstream);
+
+    let mut client_write = io::BufWriter::new(stream);
+
+    loop {
+        let mut buf = [0u8; 2];
+        if let Err(e) = client_read.read_exact(&mut buf) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                break;
+            }
+            return Err(e);
+        }
+        let len = u16::from_be_bytes(buf);
+
+        let mut query = vec![0u8; usize::from(len)];
+        client_read.read_exact(&mut query)?;
+
+        if let Some(answer) = resolve(channel, query) {
+            let len = u16::try_from(answer.len())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            client_write.write_all(&len.to_be_bytes())?;
+            client_write.write_all(&answer)?;
+            client_write.flush()?;
+        }
+    }
+
+    let lstream = client_read.into_inner();
+    let _ = lstream.shutdown(net::Shutdown::Both);
+
+    Ok(())
+}
+
+pub(crate) fn udp_handler(
+    server: &service::UdpFrontendServer,
+    _scope: &thread::Scope,
+    (query, peer): (Vec<u8>, net::SocketAddr),
+    channel: &service::Channel,
+) -> Result<(), io::Error> {
+    if let Some(answer) = resolve(channel, query) {
+        server.send_to(&answer, peer)?;
+    }
+    Ok(())
+}
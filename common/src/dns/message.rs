@@ -0,0 +1,441 @@
+//! Minimal DNS wire format helpers (RFC 1035), only what is needed
+//! to answer on behalf of the remote resolver.
+
+#[cfg(feature = "backend")]
+use std::net;
+
+const HEADER_LEN: usize = 12;
+
+#[cfg(feature = "backend")]
+pub(crate) const TYPE_A: u16 = 1;
+#[cfg(feature = "backend")]
+pub(crate) const TYPE_AAAA: u16 = 28;
+#[cfg(feature = "backend")]
+const TYPE_PTR: u16 = 12;
+#[cfg(feature = "backend")]
+const TYPE_ANY: u16 = 255;
+#[cfg(feature = "backend")]
+const CLASS_IN: u16 = 1;
+
+#[cfg(feature = "backend")]
+pub(crate) const RCODE_NXDOMAIN: u8 = 3;
+#[cfg(feature = "frontend")]
+pub(crate) const RCODE_SERVFAIL: u8 = 2;
+
+#[cfg(feature = "backend")]
+const FLAG_TRUNCATED: u8 = 0x02;
+
+#[cfg(feature = "backend")]
+const ANSWER_TTL: u32 = 60;
+
+pub(crate) struct Question {
+    #[cfg(feature = "backend")]
+    pub(crate) name: String,
+    #[cfg(feature = "backend")]
+    pub(crate) qtype: u16,
+    end: usize,
+}
+
+impl Question {
+    /// Parses the first question of a query. Queries do not use name
+    /// compression.
+    pub(crate) fn parse(msg: &[u8]) -> Option<Self> {
+        let qdcount = u16::from_be_bytes([*msg.get(4)?, *msg.get(5)?]);
+        if qdcount == 0 {
+            return None;
+        }
+
+        let mut labels = vec![];
+        let mut off = HEADER_LEN;
+        loop {
+            let len = usize::from(*msg.get(off)?);
+            off += 1;
+            if len == 0 {
+                break;
+            }
+            if 63 < len {
+                return None;
+            }
+            labels.push(String::from_utf8_lossy(msg.get(off..off + len)?).to_string());
+            off += len;
+        }
+
+        if msg.len() < off + 4 {
+            return None;
+        }
+
+        #[cfg(feature = "backend")]
+        let qtype = u16::from_be_bytes([msg[off], msg[off + 1]]);
+
+        Some(Self {
+            #[cfg(feature = "backend")]
+            name: labels.join("."),
+            #[cfg(feature = "backend")]
+            qtype,
+            end: off + 4,
+        })
+    }
+}
+
+/// Builds an empty reply to `query` with the given response code,
+/// echoing the question section.
+pub(crate) fn error_reply(query: &[u8], rcode: u8) -> Option<Vec<u8>> {
+    let question = Question::parse(query)?;
+    let mut reply = reply_header(query, rcode, 0)?;
+    reply.extend_from_slice(query.get(HEADER_LEN..question.end)?);
+    Some(reply)
+}
+
+fn reply_header(query: &[u8], rcode: u8, ancount: u16) -> Option<Vec<u8>> {
+    let header = query.get(0..HEADER_LEN)?;
+    let mut reply = Vec::with_capacity(512);
+    // identifier
+    reply.extend_from_slice(&header[0..2]);
+    // QR, copy of opcode and RD
+    reply.push(0x80 | (header[2] & 0x79));
+    // RA and response code
+    reply.push(0x80 | (rcode & 0x0f));
+    // one question
+    reply.extend_from_slice(&1u16.to_be_bytes());
+    reply.extend_from_slice(&ancount.to_be_bytes());
+    // no authority nor additional records
+    reply.extend_from_slice(&[0x00; 4]);
+    Some(reply)
+}
+
+/// Builds the reply to an A, AAAA or ANY `query` from addresses
+/// obtained through the system resolver.
+#[cfg(feature = "backend")]
+pub(crate) fn address_reply(query: &[u8], ips: &[net::IpAddr]) -> Option<Vec<u8>> {
+    let question = Question::parse(query)?;
+
+    let answers = ips
+        .iter()
+        .filter(|ip| match ip {
+            net::IpAddr::V4(_) => matches!(question.qtype, TYPE_A | TYPE_ANY),
+            net::IpAddr::V6(_) => matches!(question.qtype, TYPE_AAAA | TYPE_ANY),
+        })
+        .collect::<Vec<_>>();
+
+    let ancount = u16::try_from(answers.len()).ok()?;
+    let mut reply = reply_header(query, 0, ancount)?;
+    reply.extend_from_slice(query.get(HEADER_LEN..question.end)?);
+
+    for ip in answers {
+        // pointer to the name of the question
+        reply.extend_from_slice(&[0xc0, 0x0c]);
+        let (rtype, rdata) = match ip {
+            net::IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            net::IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+        reply.extend_from_slice(&rtype.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+        reply.extend_from_slice(&u16::try_from(rdata.len()).ok()?.to_be_bytes());
+        reply.extend_from_slice(&rdata);
+    }
+
+    Some(reply)
+}
+
+#[cfg(feature = "backend")]
+pub(crate) fn is_truncated(reply: &[u8]) -> bool {
+    reply
+        .get(2)
+        .is_some_and(|flags| flags & FLAG_TRUNCATED != 0)
+}
+
+#[cfg(feature = "backend")]
+pub(crate) fn same_id(query: &[u8], reply: &[u8]) -> bool {
+    HEADER_LEN <= reply.len() && query.get(0..2) == reply.get(0..2)
+}
+
+/// Builds a PTR query for the reverse lookup of `ip`.
+#[cfg(feature = "backend")]
+pub(crate) fn ptr_query(id: u16, ip: net::IpAddr) -> Option<Vec<u8>> {
+    let name = match ip {
+        net::IpAddr::V4(ip) => {
+            let mut labels = ip
+                .octets()
+                .iter()
+                .rev()
+                .map(u8::to_string)
+                .collect::<Vec<_>>();
+            labels.extend(["in-addr".to_string(), "arpa".to_string()]);
+            labels
+        }
+        net::IpAddr::V6(ip) => {
+            let mut labels = ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|b| [format!("{:x}", b & 0x0f), format!("{:x}", b >> 4)])
+                .collect::<Vec<_>>();
+            labels.extend(["ip6".to_string(), "arpa".to_string()]);
+            labels
+        }
+    };
+
+    let mut query = Vec::with_capacity(128);
+    query.extend_from_slice(&id.to_be_bytes());
+    // standard query, recursion desired
+    query.extend_from_slice(&[0x01, 0x00]);
+    // one question
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.extend_from_slice(&[0x00; 6]);
+    for label in name {
+        // labels of reverse names are at most 7 characters long
+        query.push(u8::try_from(label.len()).ok()?);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0x00);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(query)
+}
+
+/// Reads a possibly compressed name at `off`, returns the name and
+/// the offset following it in `msg`.
+#[cfg(feature = "backend")]
+fn read_name(msg: &[u8], mut off: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // bounds the number of followed pointers to avoid loops
+    for _ in 0..128 {
+        let len = *msg.get(off)?;
+        if len & 0xc0 == 0xc0 {
+            let pointer = usize::from(u16::from_be_bytes([len & 0x3f, *msg.get(off + 1)?]));
+            end.get_or_insert(off + 2);
+            off = pointer;
+            continue;
+        }
+        let len = usize::from(len);
+        off += 1;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(off)));
+        }
+        labels.push(String::from_utf8_lossy(msg.get(off..off + len)?).to_string());
+        off += len;
+    }
+    None
+}
+
+/// Extracts the first host name of the PTR records of a `reply`.
+#[cfg(feature = "backend")]
+pub(crate) fn ptr_answer(reply: &[u8]) -> Option<String> {
+    let qdcount = u16::from_be_bytes([*reply.get(4)?, *reply.get(5)?]);
+    let ancount = u16::from_be_bytes([*reply.get(6)?, *reply.get(7)?]);
+
+    let mut off = HEADER_LEN;
+    for _ in 0..qdcount {
+        let (_, next) = read_name(reply, off)?;
+        off = next + 4;
+    }
+
+    for _ in 0..ancount {
+        let (_, next) = read_name(reply, off)?;
+        let header = reply.get(next..next + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        off = next + 10;
+        if rtype == TYPE_PTR {
+            return read_name(reply, off).map(|(name, _)| name);
+        }
+        off += rdlength;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HEADER_LEN, Question};
+    #[cfg(feature = "backend")]
+    use std::{net, str::FromStr};
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        for label in name.split('.') {
+            query.push(u8::try_from(label.len()).unwrap());
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0x00);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&1u16.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn question() {
+        let msg = query(0x1234, "www.example.org", 1);
+        let question = Question::parse(&msg).unwrap();
+        assert_eq!(question.end, msg.len());
+        #[cfg(feature = "backend")]
+        {
+            assert_eq!(question.name, "www.example.org");
+            assert_eq!(question.qtype, super::TYPE_A);
+        }
+    }
+
+    #[test]
+    fn invalid_question() {
+        let msg = query(0x1234, "www.example.org", 1);
+
+        // truncated anywhere
+        for len in 0..msg.len() {
+            assert!(Question::parse(&msg[..len]).is_none(), "{len}");
+        }
+
+        let mut none = msg.clone();
+        none[5] = 0;
+        assert!(Question::parse(&none).is_none());
+
+        // compression is not expected in queries
+        let mut pointer = msg.clone();
+        pointer[HEADER_LEN] = 0xc0;
+        assert!(Question::parse(&pointer).is_none());
+
+        let long = query(1, &"a".repeat(64), 1);
+        assert!(Question::parse(&long).is_none());
+        let longest = query(1, &"a".repeat(63), 1);
+        assert!(Question::parse(&longest).is_some());
+    }
+
+    #[test]
+    fn error_reply() {
+        let msg = query(0x1234, "example.org", 1);
+        let reply = super::error_reply(&msg, 2).unwrap();
+
+        assert_eq!(reply[0..2], [0x12, 0x34]);
+        // response, recursion desired and available, SERVFAIL
+        assert_eq!(reply[2..4], [0x81, 0x82]);
+        assert_eq!(
+            reply[4..12],
+            [0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(reply[HEADER_LEN..], msg[HEADER_LEN..]);
+
+        assert!(super::error_reply(&msg[..HEADER_LEN], 2).is_none());
+    }
+
+    #[cfg(feature = "backend")]
+    #[test]
+    fn address_reply() {
+        let ips = [
+            net::IpAddr::from_str("192.0.2.1").unwrap(),
+            net::IpAddr::from_str("2001:db8::1").unwrap(),
+        ];
+
+        let msg = query(7, "example.org", super::TYPE_A);
+        let reply = super::address_reply(&msg, &ips).unwrap();
+        assert_eq!(reply[6..8], [0x00, 0x01]);
+        let answer = &reply[msg.len()..];
+        assert_eq!(
+            answer,
+            [
+                0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 192, 0, 2,
+                1
+            ]
+        );
+
+        let msg = query(7, "example.org", super::TYPE_AAAA);
+        let reply = super::address_reply(&msg, &ips).unwrap();
+        assert_eq!(reply[6..8], [0x00, 0x01]);
+        assert_eq!(reply.len(), msg.len() + 12 + 16);
+
+        let msg = query(7, "example.org", 255);
+        let reply = super::address_reply(&msg, &ips).unwrap();
+        assert_eq!(reply[6..8], [0x00, 0x02]);
+
+        // no record of the requested type
+        let msg = query(7, "example.org", 16);
+        let reply = super::address_reply(&msg, &ips).unwrap();
+        assert_eq!(reply[6..8], [0x00, 0x00]);
+        assert_eq!(reply.len(), msg.len());
+    }
+
+    #[cfg(feature = "backend")]
+    #[test]
+    fn header_flags() {
+        let msg = query(7, "example.org", 1);
+        let mut reply = super::error_reply(&msg, super::RCODE_NXDOMAIN).unwrap();
+
+        assert!(super::same_id(&msg, &reply));
+        assert!(!super::same_id(&query(8, "example.org", 1), &reply));
+        assert!(!super::same_id(&msg, &reply[..HEADER_LEN - 1]));
+
+        assert!(!super::is_truncated(&reply));
+        reply[2] |= 0x02;
+        assert!(super::is_truncated(&reply));
+        assert!(!super::is_truncated(&[]));
+    }
+
+    #[cfg(feature = "backend")]
+    #[test]
+    fn ptr_query() {
+        let msg = super::ptr_query(9, net::IpAddr::from_str("192.0.2.1").unwrap()).unwrap();
+        let question = Question::parse(&msg).unwrap();
+        assert_eq!(question.name, "1.2.0.192.in-addr.arpa");
+        assert_eq!(question.qtype, 12);
+        assert_eq!(msg[0..2], [0x00, 0x09]);
+
+        let msg =
+            super::ptr_query(9, net::IpAddr::from_str("2001:db8::567:89ab").unwrap()).unwrap();
+        assert_eq!(
+            Question::parse(&msg).unwrap().name,
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[cfg(feature = "backend")]
+    fn ptr_reply(answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let ip = net::IpAddr::from_str("192.0.2.1").unwrap();
+        let mut reply = super::ptr_query(9, ip).unwrap();
+        reply[2] = 0x81;
+        reply[7] = u8::try_from(answers.len()).unwrap();
+        for (rtype, rdata) in answers {
+            reply.extend_from_slice(&[0xc0, 0x0c]);
+            reply.extend_from_slice(&rtype.to_be_bytes());
+            reply.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+            reply.extend_from_slice(&u16::try_from(rdata.len()).unwrap().to_be_bytes());
+            reply.extend_from_slice(rdata);
+        }
+        reply
+    }
+
+    #[cfg(feature = "backend")]
+    #[test]
+    fn ptr_answer() {
+        // host.example.org
+        let name = b"\x04host\x07example\x03org\x00";
+        let reply = ptr_reply(&[(12, name)]);
+        assert_eq!(super::ptr_answer(&reply).unwrap(), "host.example.org");
+
+        // a CNAME first, then a name compressed against the first one
+        let cname = b"\x011\x03net\x00";
+        let offset = u8::try_from(ptr_reply(&[]).len() + 12).unwrap();
+        let reply = ptr_reply(&[(5, cname), (12, &[3, b'w', b'w', b'w', 0xc0, offset])]);
+        assert_eq!(super::ptr_answer(&reply).unwrap(), "www.1.net");
+
+        assert!(super::ptr_answer(&ptr_reply(&[])).is_none());
+        assert!(super::ptr_answer(&ptr_reply(&[(5, cname)])).is_none());
+    }
+
+    #[cfg(feature = "backend")]
+    #[test]
+    fn invalid_ptr_answer() {
+        let name = b"\x04host\x07example\x03org\x00";
+        let reply = ptr_reply(&[(12, name)]);
+        for len in 0..reply.len() {
+            assert!(super::ptr_answer(&reply[..len]).is_none(), "{len}");
+        }
+
+        // a pointer to itself
+        let reply = ptr_reply(&[(12, b"\xc0\x00")]);
+        let mut looping = reply.clone();
+        let off = looping.len() - 2;
+        looping[off + 1] = u8::try_from(off).unwrap();
+        assert!(super::ptr_answer(&looping).is_none());
+    }
+}
@@ -0,0 +1,30 @@
+use crate::service;
+
+#[cfg(feature = "backend")]
+pub(crate) mod backend;
+#[cfg(feature = "frontend")]
+mod frontend;
+mod message;
+mod protocol;
+
+pub(crate) static SERVICE: service::Service = service::Service {
+    name: "dns",
+    #[cfg(feature = "frontend")]
+    tcp_frontend: Some(service::TcpFrontend {
+        default_port: 1053,
+        enabled_by_default: false,
+        transparent: false,
+        token: false,
+        handler: frontend::tcp_handler,
+    }),
+    #[cfg(feature = "frontend")]
+    udp_frontend: Some(service::UdpFrontend {
+        handler: frontend::udp_handler,
+    }),
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tun_frontend: None,
+    #[cfg(feature = "backend")]
+    backend: service::Backend {
+        handler: backend::handler,
+    },
+};
@@ -0,0 +1,114 @@
+use std::io;
+
+const ID_QUERY: u8 = 0x0;
+
+pub enum Command {
+    Query(Vec<u8>),
+}
+
+impl Command {
+    #[cfg(feature = "frontend")]
+    pub(crate) fn send<W>(&self, stream: &mut W) -> Result<(), io::Error>
+    where
+        W: io::Write,
+    {
+        match self {
+            Self::Query(query) => {
+                let buf = [ID_QUERY; 1];
+                stream.write_all(&buf)?;
+
+                let len = u16::try_from(query.len())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                stream.write_all(&len.to_le_bytes())?;
+                stream.write_all(query)?;
+            }
+        }
+        stream.flush()
+    }
+
+    #[cfg(feature = "backend")]
+    pub(crate) fn receive<R>(stream: &mut R) -> Result<Self, io::Error>
+    where
+        R: io::Read,
+    {
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf)?;
+
+        match buf[0] {
+            ID_QUERY => {
+                let mut buf = [0u8; 2];
+                stream.read_exact(&mut buf)?;
+                let len = u16::from_le_bytes(buf);
+
+                let mut buf = vec![0u8; usize::from(len)];
+                stream.read_exact(&mut buf)?;
+
+                Ok(Self::Query(buf))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid command",
+            )),
+        }
+    }
+}
+
+const ID_ANSWER: u8 = 0x0;
+const ID_FAILED: u8 = 0x1;
+
+pub enum Response {
+    Answer(Vec<u8>),
+    Failed,
+}
+
+impl Response {
+    #[cfg(feature = "backend")]
+    pub(crate) fn send<W>(&self, stream: &mut W) -> Result<(), io::Error>
+    where
+        W: io::Write,
+    {
+        match self {
+            Self::Answer(answer) => {
+                let buf = [ID_ANSWER; 1];
+                stream.write_all(&buf)?;
+
+                let len = u16::try_from(answer.len())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                stream.write_all(&len.to_le_bytes())?;
+                stream.write_all(answer)?;
+            }
+            Self::Failed => {
+                let buf = [ID_FAILED; 1];
+                stream.write_all(&buf)?;
+            }
+        }
+        stream.flush()
+    }
+
+    #[cfg(feature = "frontend")]
+    pub(crate) fn receive<R>(stream: &mut R) -> Result<Self, io::Error>
+    where
+        R: io::Read,
+    {
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf)?;
+
+        match buf[0] {
+            ID_ANSWER => {
+                let mut buf = [0u8; 2];
+                stream.read_exact(&mut buf)?;
+                let len = u16::from_le_bytes(buf);
+
+                let mut buf = vec![0u8; usize::from(len)];
+                stream.read_exact(&mut buf)?;
+
+                Ok(Self::Answer(buf))
+            }
+            ID_FAILED => Ok(Self::Failed),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid response",
+            )),
+        }
+    }
+}
@@ -1,16 +1,50 @@
 use super::protocol;
-use crate::service;
+use crate::{audit, calendar, options, service};
 use std::{
     fs,
-    io::{self, Write},
-    path,
+    io::{self, Seek, Write},
+    path, time,
 };
 
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// older entries get their year instead of their time of day
+const RECENT: time::Duration = time::Duration::from_hours(180 * 24);
+
+fn audit(
+    stream: &service::RdpStream<'_>,
+    event: &str,
+    path: &path::Path,
+    ok: bool,
+    size: Option<u64>,
+) {
+    let path = path.to_string_lossy();
+    let mut fields = vec![
+        ("path", audit::Value::Text(&path)),
+        ("ok", audit::Value::Bool(ok)),
+    ];
+    if let Some(size) = size {
+        fields.push(("size", audit::Value::Size(size)));
+    }
+    audit::record(
+        service::Kind::Backend,
+        &super::SERVICE,
+        stream.client_id(),
+        None,
+        event,
+        &fields,
+    );
+}
+
 fn cmd_cwd(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
     crate::info!("change directory {path:?}");
 
     let path = path::PathBuf::from(path);
-    if path.exists() {
+    let ok = path.exists();
+    audit(stream, "cwd", &path, ok, None);
+    if ok {
         protocol::DataReply::CwdOk.send(stream)?;
     } else {
         protocol::DataReply::Ko.send(stream)?;
@@ -24,81 +58,326 @@ fn cmd_dele(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io:
 
     let path = path::PathBuf::from(path);
     if path.exists() {
-        if let Err(e) = fs::remove_file(path) {
+        if let Err(e) = fs::remove_file(&path) {
             crate::error!("failed to delete: {e}");
+            audit(stream, "dele", &path, false, None);
             protocol::DataReply::Ko.send(stream)?;
         } else {
+            audit(stream, "dele", &path, true, None);
             protocol::DataReply::DeleteOk.send(stream)?;
         }
     } else {
+        audit(stream, "dele", &path, false, None);
         protocol::DataReply::Ko.send(stream)?;
     }
 
     Ok(())
 }
 
+fn cmd_mkd(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
+    crate::info!("make directory {path:?}");
+
+    let path = path::PathBuf::from(path);
+    if let Err(e) = fs::create_dir(&path) {
+        crate::error!("failed to make directory: {e}");
+        audit(stream, "mkd", &path, false, None);
+        protocol::DataReply::Ko.send(stream)?;
+    } else {
+        audit(stream, "mkd", &path, true, None);
+        protocol::DataReply::MkdOk.send(stream)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_rmd(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
+    crate::info!("remove directory {path:?}");
+
+    let path = path::PathBuf::from(path);
+    if let Err(e) = fs::remove_dir(&path) {
+        crate::error!("failed to remove directory: {e}");
+        audit(stream, "rmd", &path, false, None);
+        protocol::DataReply::Ko.send(stream)?;
+    } else {
+        audit(stream, "rmd", &path, true, None);
+        protocol::DataReply::RmdOk.send(stream)?;
+    }
+
+    Ok(())
+}
+
+fn cmd_rename(stream: &mut service::RdpStream<'_>, from: &str, to: &str) -> Result<(), io::Error> {
+    crate::info!("rename {from:?} to {to:?}");
+
+    let result = fs::rename(from, to);
+    if let Err(e) = &result {
+        crate::error!("failed to rename: {e}");
+    }
+
+    audit::record(
+        service::Kind::Backend,
+        &super::SERVICE,
+        stream.client_id(),
+        None,
+        "rename",
+        &[
+            ("path", audit::Value::Text(from)),
+            ("to", audit::Value::Text(to)),
+            ("ok", audit::Value::Bool(result.is_ok())),
+        ],
+    );
+
+    if result.is_ok() {
+        protocol::DataReply::RenameOk.send(stream)?;
+    } else {
+        protocol::DataReply::Ko.send(stream)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    (0..9)
+        .rev()
+        .map(|bit| {
+            if mode & (1 << bit) == 0 {
+                '-'
+            } else {
+                ['x', 'w', 'r'][bit % 3]
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> String {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, true) => "r-xr-xr-x",
+        (true, false) => "rwxrwxrwx",
+        (false, true) => "r--r--r--",
+        (false, false) => "rw-rw-rw-",
+    }
+    .into()
+}
+
+fn list_time(time: time::SystemTime) -> String {
+    let calendar::DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        ..
+    } = calendar::DateTime::utc(time);
+    let month = MONTHS[usize::try_from(month - 1).unwrap_or_default()];
+
+    let recent = time::SystemTime::now()
+        .duration_since(time)
+        .is_ok_and(|age| age < RECENT);
+    if recent {
+        format!("{month} {day:>2} {hour:02}:{minute:02}")
+    } else {
+        format!("{month} {day:>2}  {year}")
+    }
+}
+
+/// Line of `ls -l`, describing symbolic links themselves.
+fn list_line(entry: &fs::DirEntry) -> Option<String> {
+    let metadata = entry.path().symlink_metadata().ok()?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else {
+        '-'
+    };
+    let modified = metadata
+        .modified()
+        .map_or_else(|_| list_time(time::UNIX_EPOCH), list_time);
+
+    let target = if file_type.is_symlink() {
+        fs::read_link(entry.path())
+            .map(|target| format!(" -> {}", target.to_string_lossy()))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    Some(format!(
+        "{kind}{} 1 ftp ftp {} {modified} {}{target}",
+        mode(&metadata),
+        metadata.len(),
+        entry.file_name().to_string_lossy()
+    ))
+}
+
+/// Facts of RFC 3659 about `path`, following symbolic links.
+fn facts(path: &path::Path) -> Option<String> {
+    let metadata = path.metadata().or_else(|_| path.symlink_metadata()).ok()?;
+    let readonly = metadata.permissions().readonly();
+
+    let (kind, perm) = if metadata.is_dir() {
+        ("dir", if readonly { "el" } else { "elcmdf" })
+    } else if metadata.is_symlink() {
+        // dangling
+        ("OS.unix=symlink", "df")
+    } else {
+        ("file", if readonly { "r" } else { "rwadf" })
+    };
+
+    let modify = metadata
+        .modified()
+        .map(|modified| format!("modify={};", super::time_val(modified)))
+        .unwrap_or_default();
+
+    Some(format!(
+        "type={kind};size={};{modify}perm={perm};",
+        metadata.len()
+    ))
+}
+
+/// Answers a transfer before its data, for frontends expecting it.
+/// Older ones only see the stream end.
+fn transfer_status(
+    stream: &mut service::RdpStream<'_>,
+    reply: &protocol::DataReply,
+) -> Result<(), io::Error> {
+    if stream.version() < 2 {
+        return Ok(());
+    }
+    reply.send(stream)
+}
+
+/// Answers a listing of `path`, which must exist.
+fn list_status(stream: &mut service::RdpStream<'_>, path: &path::Path) -> Result<bool, io::Error> {
+    let ok = path.exists();
+    if ok {
+        transfer_status(stream, &protocol::DataReply::DataTransferOk)?;
+    } else {
+        transfer_status(stream, &protocol::DataReply::Ko)?;
+    }
+    Ok(ok)
+}
+
 fn cmd_list(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
     crate::info!("list {path:?}");
 
     let path = path::PathBuf::from(path);
-    if path.exists() {
-        if let Ok(dir) = path.read_dir() {
-            dir.into_iter().try_for_each(|entry| {
-                if let Ok(entry) = entry {
-                    if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_dir() {
-                            write!(stream, "d")?;
-                        } else if file_type.is_file() {
-                            write!(stream, "-")?;
-                        } else {
-                            write!(stream, "l")?;
-                        }
-                        let _ = write!(stream, "rwxrwxrwx 1 ftp ftp ");
-                        if let Ok(metadata) = entry.metadata() {
-                            write!(stream, "{}", metadata.len())?;
-                        } else {
-                            write!(stream, "0")?;
-                        }
-                        write!(stream, " {}\r\n", entry.file_name().into_string().unwrap())?;
-                    }
-                }
-                Ok::<(), io::Error>(())
-            })?;
+    if !list_status(stream, &path)? {
+        return Ok(());
+    }
+    if let Ok(dir) = path.read_dir() {
+        for entry in dir.flatten() {
+            if let Some(line) = list_line(&entry) {
+                write!(stream, "{line}\r\n")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_mlsd(stream: &mut service::RdpStream<'_>, path: &str) -> Result<(), io::Error> {
+    crate::info!("machine list {path:?}");
+
+    let path = path::Path::new(path);
+    if !list_status(stream, path)? {
+        return Ok(());
+    }
+    if let Ok(dir) = path.read_dir() {
+        for entry in dir.flatten() {
+            if let Some(facts) = facts(&entry.path()) {
+                write!(
+                    stream,
+                    "{facts} {}\r\n",
+                    entry.file_name().to_string_lossy()
+                )?;
+            }
         }
     }
 
     Ok(())
 }
 
+fn cmd_mlst(stream: &mut service::RdpStream<'_>, path: &str) -> Result<(), io::Error> {
+    crate::info!("machine list entry {path:?}");
+
+    match facts(path::Path::new(path)) {
+        Some(facts) => protocol::DataReply::MlstOk(facts).send(stream),
+        None => protocol::DataReply::Ko.send(stream),
+    }
+}
+
+fn cmd_mdtm(stream: &mut service::RdpStream<'_>, path: &str) -> Result<(), io::Error> {
+    crate::info!("modification time {path:?}");
+
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(time::UNIX_EPOCH).ok());
+    match modified {
+        Some(modified) => protocol::DataReply::MdtmOk(modified.as_secs()).send(stream),
+        None => protocol::DataReply::Ko.send(stream),
+    }
+}
+
 fn cmd_nlst(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
     crate::info!("name list {path:?}");
 
     let path = path::PathBuf::from(path);
-    if path.exists() {
-        if let Ok(dir) = path.read_dir() {
-            dir.into_iter().try_for_each(|entry| {
-                if let Ok(entry) = entry {
-                    write!(stream, "{}\r\n", entry.file_name().into_string().unwrap())?;
-                }
-                Ok::<(), io::Error>(())
-            })?;
-        }
+    if !list_status(stream, &path)? {
+        return Ok(());
+    }
+    if let Ok(dir) = path.read_dir() {
+        dir.into_iter().try_for_each(|entry| {
+            if let Ok(entry) = entry {
+                write!(stream, "{}\r\n", entry.file_name().into_string().unwrap())?;
+            }
+            Ok::<(), io::Error>(())
+        })?;
     }
     Ok(())
 }
 
-fn cmd_retr(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
-    crate::info!("downloading {path:?}");
+fn cmd_retr(
+    stream: &mut service::RdpStream<'_>,
+    path: String,
+    offset: u64,
+) -> Result<(), io::Error> {
+    crate::info!("downloading {path:?} from {offset}");
 
     let path = path::PathBuf::from(path);
-    if path.exists() && path.is_file() {
-        let file = fs::File::options().read(true).write(false).open(path)?;
-        let mut file = io::BufReader::new(file);
+    let mut file = match fs::File::options().read(true).write(false).open(&path) {
+        Ok(file) if path.is_file() => file,
+        _ => {
+            audit(stream, "retr", &path, false, None);
+            return transfer_status(stream, &protocol::DataReply::Ko);
+        }
+    };
+
+    if file.metadata()?.len() < offset {
+        crate::debug!("offset {offset} beyond the end of {path:?}");
+        audit(stream, "retr", &path, false, None);
+        return transfer_status(stream, &protocol::DataReply::RestartKo);
+    }
+
+    file.seek(io::SeekFrom::Start(offset))?;
+    let mut file = io::BufReader::new(file);
+    transfer_status(stream, &protocol::DataReply::DataTransferOk)?;
 
-        if let Err(e) = service::stream_copy(&mut file, stream) {
+    match service::stream_copy(&mut file, stream) {
+        Err(e) => {
             crate::debug!("error: {e}");
-        } else {
+            audit(stream, "retr", &path, false, None);
+        }
+        Ok(size) => {
             crate::debug!("stopped");
+            audit(stream, "retr", &path, true, Some(size));
         }
     }
 
@@ -123,75 +402,349 @@ fn cmd_size(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io:
     Ok(())
 }
 
-fn cmd_stor(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
-    crate::info!("uploading {path:?}");
+fn cmd_stor(
+    stream: &mut service::RdpStream<'_>,
+    path: String,
+    offset: u64,
+) -> Result<(), io::Error> {
+    crate::info!("uploading {path:?} from {offset}");
 
     let path = path::PathBuf::from(path);
-    let file = fs::File::options()
+    let Ok(mut file) = fs::File::options()
         .create(true)
-        .truncate(true)
+        .truncate(offset == 0)
         .write(true)
-        .open(path)?;
+        .open(&path)
+    else {
+        audit(stream, "stor", &path, false, None);
+        return transfer_status(stream, &protocol::DataReply::Ko);
+    };
+
+    if 0 < offset {
+        // resuming must not leave a hole in the file
+        if file.metadata()?.len() < offset {
+            crate::debug!("offset {offset} beyond the end of {path:?}");
+            audit(stream, "stor", &path, false, None);
+            return transfer_status(stream, &protocol::DataReply::RestartKo);
+        }
+        file.set_len(offset)?;
+        file.seek(io::SeekFrom::Start(offset))?;
+    }
+
+    upload(stream, "stor", &path, file)
+}
+
+fn cmd_appe(stream: &mut service::RdpStream<'_>, path: String) -> Result<(), io::Error> {
+    crate::info!("appending to {path:?}");
+
+    let path = path::PathBuf::from(path);
+    let Ok(file) = fs::File::options().create(true).append(true).open(&path) else {
+        audit(stream, "appe", &path, false, None);
+        return transfer_status(stream, &protocol::DataReply::Ko);
+    };
+
+    upload(stream, "appe", &path, file)
+}
+
+fn upload(
+    stream: &mut service::RdpStream<'_>,
+    event: &str,
+    path: &path::Path,
+    file: fs::File,
+) -> Result<(), io::Error> {
+    transfer_status(stream, &protocol::DataReply::DataTransferOk)?;
+
     let mut file = io::BufWriter::new(file);
 
-    if let Err(e) = service::stream_copy(stream, &mut file) {
-        crate::debug!("error: {e}");
-    } else {
-        crate::debug!("stopped");
+    match service::stream_copy(stream, &mut file) {
+        Err(e) => {
+            crate::debug!("error: {e}");
+            audit(stream, event, path, false, None);
+        }
+        Ok(size) => {
+            crate::debug!("stopped");
+            audit(stream, event, path, true, Some(size));
+        }
     }
 
     Ok(())
 }
 
-pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
-    crate::debug!("starting");
+/// Path on the backend of the absolute `path` given by the frontend.
+/// With a `root`, `path` is taken relatively to it and refused if it
+/// resolves out of it once symbolic links are followed. This is checked
+/// when the command is received, a link created in the meantime by
+/// another program is not noticed.
+fn local_path(root: Option<&str>, path: &str) -> Result<String, io::Error> {
+    let Some(root) = root else {
+        #[cfg(target_os = "windows")]
+        return Ok("C:".to_string() + path);
+        #[cfg(not(target_os = "windows"))]
+        return Ok(path.to_string());
+    };
+
+    let mut components = vec![];
+    for component in path::Path::new(path).components() {
+        match component {
+            path::Component::Normal(name) => components.push(name),
+            path::Component::ParentDir => {
+                components.pop();
+            }
+            _ => (),
+        }
+    }
 
-    let cmd = protocol::DataCommand::receive(&mut stream)?;
+    let mut local = path::PathBuf::from(root);
+    local.extend(components);
+
+    // a path to be created is checked through its closest existing
+    // ancestor, a dangling link cannot be resolved and is refused
+    let mut existing = local.as_path();
+    while let (Err(_), Some(parent)) = (fs::symlink_metadata(existing), existing.parent()) {
+        existing = parent;
+    }
+    let inside = fs::canonicalize(existing)
+        .and_then(|existing| Ok(existing.starts_with(fs::canonicalize(root)?)))
+        .unwrap_or(false);
+    if !inside {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{path:?} is out of the root"),
+        ));
+    }
 
+    Ok(local.to_string_lossy().into())
+}
+
+fn execute(
+    stream: &mut service::RdpStream<'_>,
+    root: Option<&str>,
+    cmd: protocol::DataCommand,
+) -> Result<(), io::Error> {
     match cmd {
         protocol::DataCommand::Cwd(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+            let path = local_path(root, &path)?;
 
-            cmd_cwd(&mut stream, path)?;
+            cmd_cwd(stream, path)?;
         }
         protocol::DataCommand::Dele(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+            let path = local_path(root, &path)?;
 
-            cmd_dele(&mut stream, path)?;
+            cmd_dele(stream, path)?;
         }
         protocol::DataCommand::List(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+            let path = local_path(root, &path)?;
 
-            cmd_list(&mut stream, path)?;
+            cmd_list(stream, path)?;
         }
         protocol::DataCommand::NLst(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+            let path = local_path(root, &path)?;
 
-            cmd_nlst(&mut stream, path)?;
+            cmd_nlst(stream, path)?;
         }
-        protocol::DataCommand::Retr(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+        protocol::DataCommand::Retr(path, offset) => {
+            let path = local_path(root, &path)?;
 
-            cmd_retr(&mut stream, path)?;
+            cmd_retr(stream, path, offset)?;
         }
         protocol::DataCommand::Size(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+            let path = local_path(root, &path)?;
+
+            cmd_size(stream, path)?;
+        }
+        protocol::DataCommand::Stor(path, offset) => {
+            let path = local_path(root, &path)?;
+
+            cmd_stor(stream, path, offset)?;
+        }
+        protocol::DataCommand::Appe(path) => {
+            let path = local_path(root, &path)?;
+
+            cmd_appe(stream, path)?;
+        }
+        protocol::DataCommand::Mkd(path) => {
+            let path = local_path(root, &path)?;
+
+            cmd_mkd(stream, path)?;
+        }
+        protocol::DataCommand::Rmd(path) => {
+            let path = local_path(root, &path)?;
 
-            cmd_size(&mut stream, path)?;
+            cmd_rmd(stream, path)?;
         }
-        protocol::DataCommand::Stor(path) => {
-            #[cfg(target_os = "windows")]
-            let path = "C:".to_string() + path.as_str();
+        protocol::DataCommand::Rename(from, to) => {
+            let from = local_path(root, &from)?;
+            let to = local_path(root, &to)?;
 
-            cmd_stor(&mut stream, path)?;
+            cmd_rename(stream, &from, &to)?;
         }
+        protocol::DataCommand::Mlsd(path) => {
+            let path = local_path(root, &path)?;
+
+            cmd_mlsd(stream, &path)?;
+        }
+        protocol::DataCommand::Mlst(path) => {
+            let path = local_path(root, &path)?;
+
+            cmd_mlst(stream, &path)?;
+        }
+        protocol::DataCommand::Mdtm(path) => {
+            let path = local_path(root, &path)?;
+
+            cmd_mdtm(stream, &path)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
+    crate::debug!("starting");
+
+    let cmd = protocol::DataCommand::receive(&mut stream)?;
+
+    let root = match stream.options() {
+        options::Options::Ftp(options::Ftp { root }) => root.clone(),
+        _ => None,
+    };
+
+    // transfers get no reply from older frontends, they end with the
+    // stream
+    let reply = cmd.is_ftp_control() || 2 <= stream.version();
+    match execute(&mut stream, root.as_deref(), cmd) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            crate::warn!("refused: {e}");
+            if reply {
+                protocol::DataReply::Ko.send(&mut stream)?;
+            }
+        }
+        result => result?,
     }
 
     stream.disconnect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::local_path;
+    use std::{fs, io, path, process};
+
+    struct Root(path::PathBuf);
+
+    impl Root {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("soxy-ftp-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("sub")).unwrap();
+            fs::write(root.join("sub").join("f.txt"), b"f").unwrap();
+            Self(root)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn join(&self, path: &str) -> String {
+            self.0.join(path).to_string_lossy().into()
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn denied(result: Result<String, io::Error>) -> bool {
+        result.is_err_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn no_root() {
+        assert_eq!(local_path(None, "/etc/passwd").unwrap(), "/etc/passwd");
+    }
+
+    #[test]
+    fn inside_root() {
+        let root = Root::new("inside");
+
+        assert_eq!(local_path(Some(root.path()), "/").unwrap(), root.path());
+        assert_eq!(
+            local_path(Some(root.path()), "/sub/f.txt").unwrap(),
+            root.join("sub/f.txt")
+        );
+        assert_eq!(
+            local_path(Some(root.path()), "sub/./f.txt").unwrap(),
+            root.join("sub/f.txt")
+        );
+        // created later, checked through the existing ancestor
+        assert_eq!(
+            local_path(Some(root.path()), "/sub/new/file").unwrap(),
+            root.join("sub/new/file")
+        );
+    }
+
+    #[test]
+    fn parent_components() {
+        let root = Root::new("parent");
+
+        // never above the root, as for the root directory of a system
+        assert_eq!(
+            local_path(Some(root.path()), "/../../etc/passwd").unwrap(),
+            root.join("etc/passwd")
+        );
+        assert_eq!(
+            local_path(Some(root.path()), "/sub/../../sub/f.txt").unwrap(),
+            root.join("sub/f.txt")
+        );
+        assert_eq!(local_path(Some(root.path()), "..").unwrap(), root.path());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let root = Root::new("symlinks");
+        symlink("/etc", root.join("escape")).unwrap();
+        symlink("/nonexistent", root.join("dangling")).unwrap();
+        symlink("sub", root.join("inner")).unwrap();
+
+        assert!(denied(local_path(Some(root.path()), "/escape")));
+        assert!(denied(local_path(Some(root.path()), "/escape/passwd")));
+        assert!(denied(local_path(Some(root.path()), "/escape/new")));
+        assert!(denied(local_path(Some(root.path()), "/dangling")));
+        assert!(denied(local_path(Some(root.path()), "/dangling/new")));
+        assert_eq!(
+            local_path(Some(root.path()), "/inner/f.txt").unwrap(),
+            root.join("inner/f.txt")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_root() {
+        use std::os::unix::fs::symlink;
+
+        let root = Root::new("target");
+        let link = Root::new("link");
+        fs::remove_dir_all(&link.0).unwrap();
+        symlink(&root.0, &link.0).unwrap();
+
+        assert_eq!(
+            local_path(Some(link.path()), "/sub/f.txt").unwrap(),
+            link.join("sub/f.txt")
+        );
+        // removes the link only
+        fs::remove_file(&link.0).unwrap();
+    }
+
+    #[test]
+    fn missing_root() {
+        let root = Root::new("missing");
+        let missing = root.join("missing");
+
+        assert!(denied(local_path(Some(&missing), "/file")));
+    }
+}
@@ -1,27 +1,42 @@
 use super::protocol;
-use crate::{api, service};
+use crate::{api, audit, service, tls};
 use std::{
     io::{self, Write},
-    net, path, thread,
+    net, path, sync, thread, time,
 };
 
 const SERVICE_KIND: service::Kind = service::Kind::Frontend;
 
+const ACTIVE_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
 #[derive(Debug)]
 enum Command {
+    Appe(String),
     Cdup,
     Cwd(String),
     Dele(String),
+    Eprt(String),
     Epsv,
     Feat,
     List,
+    Mdtm(String),
+    Mkd(String),
+    Mlsd(String),
+    Mlst(String),
     Nlst,
     Opts,
     Pass,
     Pasv,
+    Pbsz,
+    Port(String),
+    Prot(String),
     Pwd,
     Quit,
+    Rest(String),
     Retr(String),
+    Rmd(String),
+    Rnfr(String),
+    Rnto(String),
     Stor(String),
     Size(String),
     Type,
@@ -53,19 +68,32 @@ impl Command {
         let command = command.to_uppercase();
 
         let command = match command.as_str() {
+            "APPE" => Self::Appe(args),
             "CDUP" => Self::Cdup,
             "CWD" => Self::Cwd(args),
             "DELE" => Self::Dele(args),
+            "EPRT" => Self::Eprt(args),
             "EPSV" => Self::Epsv,
             "FEAT" => Self::Feat,
             "LIST" => Self::List,
+            "MDTM" => Self::Mdtm(args),
+            "MKD" | "XMKD" => Self::Mkd(args),
+            "MLSD" => Self::Mlsd(args),
+            "MLST" => Self::Mlst(args),
             "NLST" => Self::Nlst,
             "OPTS" => Self::Opts,
             "PASS" => Self::Pass,
             "PASV" => Self::Pasv,
+            "PBSZ" => Self::Pbsz,
+            "PORT" => Self::Port(args),
+            "PROT" => Self::Prot(args),
             "PWD" => Self::Pwd,
             "QUIT" => Self::Quit,
+            "REST" => Self::Rest(args),
             "RETR" => Self::Retr(args),
+            "RMD" | "XRMD" => Self::Rmd(args),
+            "RNFR" => Self::Rnfr(args),
+            "RNTO" => Self::Rnto(args),
             "SIZE" => Self::Size(args),
             "STOR" => Self::Stor(args),
             "TYPE" => Self::Type,
@@ -96,44 +124,182 @@ fn cmd_dele(
     to_data.send(protocol::DataCommand::Dele(fpath.to_string_lossy().into()))?;
     let reply = from_data.recv()?;
     let res = if reply.is_ok() {
-        vec!["200 Command okay".into()]
+        vec!["250 Requested file action okay, completed".into()]
     } else {
         vec!["550 Delete failed".into()]
     };
     Ok(res)
 }
 
-fn cmd_list(
+/// `path` between double quotes, the ones it contains being doubled as
+/// in RFC 959.
+fn quoted(path: &str) -> String {
+    format!("\"{}\"", path.replace('"', "\"\""))
+}
+
+fn cmd_mkd(
     to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    from_data: &crossbeam_channel::Receiver<protocol::DataReply>,
     current_path: &path::Path,
+    path: String,
 ) -> Result<Vec<String>, api::Error> {
-    to_data.send(protocol::DataCommand::List(
-        current_path.to_string_lossy().into(),
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    let fpath = fpath.to_string_lossy().to_string();
+    to_data.send(protocol::DataCommand::Mkd(fpath.clone()))?;
+    let reply = from_data.recv()?;
+    let res = if reply.is_ok() {
+        vec![format!("257 {} created", quoted(&fpath))]
+    } else {
+        vec!["550 Create directory operation failed".into()]
+    };
+    Ok(res)
+}
+
+fn cmd_rmd(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    from_data: &crossbeam_channel::Receiver<protocol::DataReply>,
+    current_path: &path::Path,
+    path: String,
+) -> Result<Vec<String>, api::Error> {
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    to_data.send(protocol::DataCommand::Rmd(fpath.to_string_lossy().into()))?;
+    let reply = from_data.recv()?;
+    let res = if reply.is_ok() {
+        vec!["250 Remove directory operation successful".into()]
+    } else {
+        vec!["550 Remove directory operation failed".into()]
+    };
+    Ok(res)
+}
+
+fn cmd_rnto(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    from_data: &crossbeam_channel::Receiver<protocol::DataReply>,
+    current_path: &path::Path,
+    from: Option<path::PathBuf>,
+    path: String,
+) -> Result<Vec<String>, api::Error> {
+    let Some(from) = from else {
+        return Ok(vec!["503 RNFR required first".into()]);
+    };
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    to_data.send(protocol::DataCommand::Rename(
+        from.to_string_lossy().into(),
+        fpath.to_string_lossy().into(),
     ))?;
-    Ok(vec!["150 Here comes the directory listing".into()])
+    let reply = from_data.recv()?;
+    let res = if reply.is_ok() {
+        vec!["250 Rename successful".into()]
+    } else {
+        vec!["550 Rename failed".into()]
+    };
+    Ok(res)
+}
+
+/// Hands `cmd` over to the data thread, which replies once the data
+/// connection is open and again at the end of the transfer.
+fn transfer(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    cmd: protocol::DataCommand,
+) -> Result<Vec<String>, api::Error> {
+    to_data.send(cmd)?;
+    Ok(vec![])
+}
+
+fn cmd_list(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    current_path: &path::Path,
+) -> Result<Vec<String>, api::Error> {
+    transfer(
+        to_data,
+        protocol::DataCommand::List(current_path.to_string_lossy().into()),
+    )
 }
 
 fn cmd_nlst(
     to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
     current_path: &path::Path,
 ) -> Result<Vec<String>, api::Error> {
-    to_data.send(protocol::DataCommand::NLst(
-        current_path.to_string_lossy().into(),
-    ))?;
-    Ok(vec!["150 Here comes the directory listing".into()])
+    transfer(
+        to_data,
+        protocol::DataCommand::NLst(current_path.to_string_lossy().into()),
+    )
+}
+
+fn cmd_mlsd(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    current_path: &path::Path,
+    path: String,
+) -> Result<Vec<String>, api::Error> {
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    transfer(
+        to_data,
+        protocol::DataCommand::Mlsd(fpath.to_string_lossy().into()),
+    )
+}
+
+fn cmd_mlst(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    from_data: &crossbeam_channel::Receiver<protocol::DataReply>,
+    current_path: &path::Path,
+    path: String,
+) -> Result<Vec<String>, api::Error> {
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    let fpath = fpath.to_string_lossy().to_string();
+    to_data.send(protocol::DataCommand::Mlst(fpath.clone()))?;
+    let reply = from_data.recv()?;
+
+    let res = if let protocol::DataReply::MlstOk(facts) = reply {
+        vec![
+            format!("250-Listing {fpath}"),
+            format!(" {facts} {fpath}"),
+            "250 End".into(),
+        ]
+    } else {
+        vec!["550 No such file or directory".into()]
+    };
+
+    Ok(res)
+}
+
+fn cmd_mdtm(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    from_data: &crossbeam_channel::Receiver<protocol::DataReply>,
+    current_path: &path::Path,
+    path: String,
+) -> Result<Vec<String>, api::Error> {
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    to_data.send(protocol::DataCommand::Mdtm(fpath.to_string_lossy().into()))?;
+    let reply = from_data.recv()?;
+
+    let res = if let protocol::DataReply::MdtmOk(secs) = reply {
+        let modified = time::UNIX_EPOCH + time::Duration::from_secs(secs);
+        vec![format!("213 {}", super::time_val(modified))]
+    } else {
+        vec!["550 Could not get file modification time".into()]
+    };
+
+    Ok(res)
 }
 
 fn cmd_retr(
     to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
     current_path: &path::Path,
     path: String,
+    offset: u64,
 ) -> Result<Vec<String>, api::Error> {
     let mut fpath = current_path.to_path_buf();
     fpath.push(path);
-    to_data.send(protocol::DataCommand::Retr(fpath.to_string_lossy().into()))?;
-    Ok(vec![
-        "125 Data connection already open; transfer starting".into(),
-    ])
+    transfer(
+        to_data,
+        protocol::DataCommand::Retr(fpath.to_string_lossy().into(), offset),
+    )
 }
 
 fn cmd_size(
@@ -160,13 +326,131 @@ fn cmd_stor(
     to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
     current_path: &path::Path,
     path: String,
+    offset: u64,
+) -> Result<Vec<String>, api::Error> {
+    let mut fpath = current_path.to_path_buf();
+    fpath.push(path);
+    transfer(
+        to_data,
+        protocol::DataCommand::Stor(fpath.to_string_lossy().into(), offset),
+    )
+}
+
+fn cmd_appe(
+    to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
+    current_path: &path::Path,
+    path: String,
 ) -> Result<Vec<String>, api::Error> {
     let mut fpath = current_path.to_path_buf();
     fpath.push(path);
-    to_data.send(protocol::DataCommand::Stor(fpath.to_string_lossy().into()))?;
-    Ok(vec![
-        "125 Data connection already open; transfer starting".into(),
-    ])
+    transfer(
+        to_data,
+        protocol::DataCommand::Appe(fpath.to_string_lossy().into()),
+    )
+}
+
+fn cmd_feat(tls: bool) -> Vec<String> {
+    let mut features = vec![
+        "211-Features:".into(),
+        " EPRT".into(),
+        " EPSV".into(),
+        " MDTM".into(),
+        " MLST type*;size*;modify*;perm*;".into(),
+        " PASV".into(),
+    ];
+    if tls {
+        features.push(" PBSZ".into());
+        features.push(" PROT".into());
+    }
+    features.extend([
+        " REST STREAM".into(),
+        " SIZE".into(),
+        " TVFS".into(),
+        " UTF8".into(),
+        "211 End".into(),
+    ]);
+    features
+}
+
+/// Address of a `PORT` argument, `h1,h2,h3,h4,p1,p2`.
+fn parse_port(args: &str) -> Option<net::SocketAddr> {
+    let bytes = args
+        .trim()
+        .split(',')
+        .map(|byte| byte.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let [h1, h2, h3, h4, p1, p2] = bytes[..] else {
+        return None;
+    };
+    Some(net::SocketAddr::from((
+        [h1, h2, h3, h4],
+        u16::from_be_bytes([p1, p2]),
+    )))
+}
+
+/// Address of an `EPRT` argument, `|1|ipv4|port|` or `|2|ipv6|port|`
+/// with any delimiter (RFC 2428).
+fn parse_eprt(args: &str) -> Option<net::SocketAddr> {
+    let args = args.trim();
+    let delimiter = args.chars().next()?;
+    let fields: Vec<_> = args[delimiter.len_utf8()..].split(delimiter).collect();
+    let [protocol, ip, port, ""] = fields[..] else {
+        return None;
+    };
+    let ip = match protocol {
+        "1" => net::IpAddr::V4(ip.parse().ok()?),
+        "2" => net::IpAddr::V6(ip.parse().ok()?),
+        _ => return None,
+    };
+    Some(net::SocketAddr::new(ip, port.parse().ok()?))
+}
+
+/// Data connections go back to the client, but never to another host.
+fn cmd_active(data_server: &DataServer, addr: Option<net::SocketAddr>) -> Vec<String> {
+    let Some(addr) = addr else {
+        return vec!["501 Syntax error in parameters".into()];
+    };
+    if data_server.is_peer(addr) {
+        *data_server.active.lock().unwrap() = Some(addr);
+        vec!["200 Command okay".into()]
+    } else {
+        crate::warn!("refusing data connections to {addr}");
+        vec!["500 Illegal PORT command".into()]
+    }
+}
+
+fn cmd_pasv(data_server: &DataServer, passive: net::SocketAddr) -> Vec<String> {
+    *data_server.active.lock().unwrap() = None;
+    match passive.ip() {
+        net::IpAddr::V4(ip) => {
+            let ip = ip.octets();
+            let port = passive.port().to_be_bytes();
+            vec![format!(
+                "227 Entering Passive Mode ({},{},{},{},{},{})",
+                ip[0], ip[1], ip[2], ip[3], port[0], port[1]
+            )]
+        }
+        // not expressible in a PASV reply
+        net::IpAddr::V6(_) => vec!["425 Use EPSV with IPv6".into()],
+    }
+}
+
+fn cmd_epsv(data_server: &DataServer, passive: net::SocketAddr) -> Vec<String> {
+    *data_server.active.lock().unwrap() = None;
+    vec![format!(
+        "229 Entering Extended Passive Mode (|||{}|)",
+        passive.port()
+    )]
+}
+
+/// Data connections are protected when the control connection is.
+fn cmd_prot(tls: bool, level: &str) -> Vec<String> {
+    let private = level.eq_ignore_ascii_case("P");
+    if private == tls && (private || level.eq_ignore_ascii_case("C")) {
+        vec!["200 Command okay".into()]
+    } else {
+        vec!["536 Requested protection level not supported".into()]
+    }
 }
 
 fn control_loop<R>(
@@ -174,22 +458,30 @@ fn control_loop<R>(
     to_control: &crossbeam_channel::Sender<Vec<String>>,
     to_data: &crossbeam_channel::Sender<protocol::DataCommand>,
     from_data: &crossbeam_channel::Receiver<protocol::DataReply>,
-    server_ip: net::IpAddr,
-    data_port: u16,
+    data_server: &DataServer,
+    passive: net::SocketAddr,
 ) -> Result<(), api::Error>
 where
     R: io::BufRead,
 {
+    let tls = data_server.tls.is_some();
     let mut current_path = path::PathBuf::from("/");
+    // set by RNFR and REST for the next command only
+    let mut rename_from = None;
+    let mut restart = None;
 
     to_control.send(vec!["220 Welcome".into()])?;
 
     loop {
+        let pending_rename = rename_from.take();
+        let offset = restart.take().unwrap_or_default();
+
         let reply = match Command::read(client)? {
             None => vec!["500 Syntax error".into()],
             Some(command) => {
                 crate::trace!("{command:?}");
                 match command {
+                    Command::Appe(path) => cmd_appe(to_data, &current_path, path)?,
                     Command::Cdup => cmd_cdup(&mut current_path),
                     Command::Cwd(path) => {
                         let new_path = if path.starts_with('/') {
@@ -211,45 +503,46 @@ where
                         }
                     }
                     Command::Dele(path) => cmd_dele(to_data, from_data, &current_path, path)?,
-                    Command::Epsv => vec![format!(
-                        "229 Entering Extended Passive Mode (|||{data_port}|)"
-                    )],
-                    Command::Feat => vec![
-                        "211-Features:".into(),
-                        " EPRT".into(),
-                        " EPSV".into(),
-                        " PASV".into(),
-                        " REST STREAM".into(),
-                        " SIZE".into(),
-                        " TVFS".into(),
-                        " UTF8".into(),
-                        "211 End".into(),
-                    ],
+                    Command::Eprt(args) => cmd_active(data_server, parse_eprt(&args)),
+                    Command::Epsv => cmd_epsv(data_server, passive),
+                    Command::Feat => cmd_feat(tls),
                     Command::List => cmd_list(to_data, &current_path)?,
+                    Command::Mdtm(path) => cmd_mdtm(to_data, from_data, &current_path, path)?,
+                    Command::Mkd(path) => cmd_mkd(to_data, from_data, &current_path, path)?,
+                    Command::Mlsd(path) => cmd_mlsd(to_data, &current_path, path)?,
+                    Command::Mlst(path) => cmd_mlst(to_data, from_data, &current_path, path)?,
                     Command::Nlst => cmd_nlst(to_data, &current_path)?,
                     Command::Opts | Command::Type => vec!["200 Command okay".into()],
                     Command::Pass => vec!["220 Login successful".into()],
-                    Command::Pasv => match &server_ip {
-                        net::IpAddr::V4(ip) => {
-                            let ip = ip.to_bits().to_be_bytes();
-                            let port = data_port.to_be_bytes();
-                            vec![format!(
-                                "227 Entering Passive Mode ({},{},{},{},{},{})",
-                                ip[0], ip[1], ip[2], ip[3], port[0], port[1]
-                            )]
-                        }
-                        net::IpAddr::V6(_) => {
-                            vec!["425 Can't open data connection".into()]
-                        }
-                    },
+                    Command::Pasv => cmd_pasv(data_server, passive),
+                    Command::Pbsz => vec!["200 PBSZ=0".into()],
+                    Command::Port(args) => cmd_active(data_server, parse_port(&args)),
+                    Command::Prot(level) => cmd_prot(tls, &level),
                     Command::Pwd => vec![format!(
-                        "257 {:?} is the current directory",
-                        current_path.as_os_str().to_string_lossy()
+                        "257 {} is the current directory",
+                        quoted(&current_path.as_os_str().to_string_lossy())
                     )],
                     Command::Quit => return Ok(()),
-                    Command::Retr(path) => cmd_retr(to_data, &current_path, path)?,
+                    Command::Rest(offset) => match offset.trim().parse::<u64>() {
+                        Ok(offset) => {
+                            restart = Some(offset);
+                            vec![format!("350 Restarting at {offset}")]
+                        }
+                        Err(_) => vec!["501 Syntax error in parameters".into()],
+                    },
+                    Command::Retr(path) => cmd_retr(to_data, &current_path, path, offset)?,
+                    Command::Rmd(path) => cmd_rmd(to_data, from_data, &current_path, path)?,
+                    Command::Rnfr(path) => {
+                        let mut fpath = current_path.clone();
+                        fpath.push(path);
+                        rename_from = Some(fpath);
+                        vec!["350 Ready for RNTO".into()]
+                    }
+                    Command::Rnto(path) => {
+                        cmd_rnto(to_data, from_data, &current_path, pending_rename, path)?
+                    }
                     Command::Size(path) => cmd_size(to_data, from_data, &current_path, path)?,
-                    Command::Stor(path) => cmd_stor(to_data, &current_path, path)?,
+                    Command::Stor(path) => cmd_stor(to_data, &current_path, path, offset)?,
                     Command::User => vec!["331 Provide password".into()],
                 }
             }
@@ -259,22 +552,27 @@ where
     }
 }
 
+/// Returns the number of bytes transferred, or `None` if the transfer
+/// was aborted.
 fn data_transfer(
-    client: net::TcpStream,
+    client: service::ClientStream,
     mut rdp: service::RdpStream,
     cmd: &protocol::DataCommand,
-) -> Result<bool, io::Error> {
-    let mut status = false;
+) -> Result<Option<u64>, io::Error> {
+    let mut status = None;
 
     if cmd.is_upload() {
         let _ = client.shutdown(net::Shutdown::Write);
 
         let mut client = io::BufReader::new(client);
-        if let Err(e) = service::stream_copy(&mut client, &mut rdp) {
-            crate::debug!("error: {e}");
-        } else {
-            crate::debug!("stopped");
-            status = true;
+        match service::stream_copy(&mut client, &mut rdp) {
+            Err(e) => {
+                crate::debug!("error: {e}");
+            }
+            Ok(size) => {
+                crate::debug!("stopped");
+                status = Some(size);
+            }
         }
         let client = client.into_inner();
         let _ = client.shutdown(net::Shutdown::Both);
@@ -282,11 +580,14 @@ fn data_transfer(
         let _ = client.shutdown(net::Shutdown::Read);
 
         let mut client = io::BufWriter::new(client);
-        if let Err(e) = service::stream_copy(&mut rdp, &mut client) {
-            crate::debug!("error: {e}");
-        } else {
-            crate::debug!("stopped");
-            status = true;
+        match service::stream_copy(&mut rdp, &mut client) {
+            Err(e) => {
+                crate::debug!("error: {e}");
+            }
+            Ok(size) => {
+                crate::debug!("stopped");
+                status = Some(size);
+            }
         }
         let _ = client.flush();
         if let Ok(client) = client.into_inner() {
@@ -299,8 +600,98 @@ fn data_transfer(
     Ok(status)
 }
 
+fn audit(
+    client_id: api::ClientId,
+    peer: Option<net::SocketAddr>,
+    cmd: &protocol::DataCommand,
+    ok: bool,
+    size: Option<u64>,
+) {
+    let Some((event, path)) = cmd.audited() else {
+        return;
+    };
+    let mut fields = vec![("path", audit::Value::Text(path))];
+    if let protocol::DataCommand::Rename(_, to) = cmd {
+        fields.push(("to", audit::Value::Text(to)));
+    }
+    if let protocol::DataCommand::Retr(_, offset @ 1..)
+    | protocol::DataCommand::Stor(_, offset @ 1..) = cmd
+    {
+        fields.push(("offset", audit::Value::Size(*offset)));
+    }
+    fields.push(("ok", audit::Value::Bool(ok)));
+    if let Some(size) = size {
+        fields.push(("size", audit::Value::Size(size)));
+    }
+    audit::record(
+        SERVICE_KIND,
+        &super::SERVICE,
+        client_id,
+        peer,
+        event,
+        &fields,
+    );
+}
+
+/// Data connections of a client, accepted on the passive mode listener
+/// or opened to the address given by `PORT` or `EPRT`.
+struct DataServer {
+    listener: net::TcpListener,
+    tls: Option<tls::Config>,
+    // of the control connection
+    peer: Option<net::SocketAddr>,
+    active: sync::Mutex<Option<net::SocketAddr>>,
+}
+
+impl DataServer {
+    /// Whether `addr` is on the host of the client.
+    fn is_peer(&self, addr: net::SocketAddr) -> bool {
+        self.peer
+            .is_some_and(|peer| peer.ip().to_canonical() == addr.ip().to_canonical())
+    }
+
+    fn open(&self) -> Result<(net::TcpStream, net::SocketAddr), io::Error> {
+        // given again by the client before each transfer
+        let active = self.active.lock().unwrap().take();
+        match active {
+            None => loop {
+                let (client, addr) = self.listener.accept()?;
+                // clients of a Unix domain socket have no address, they
+                // connect from the loopback
+                if self.peer.is_none() || self.is_peer(addr) {
+                    return Ok((client, addr));
+                }
+                crate::warn!("refusing data connection from {addr}");
+            },
+            Some(addr) => {
+                let client = net::TcpStream::connect_timeout(&addr, ACTIVE_CONNECT_TIMEOUT)?;
+                Ok((client, addr))
+            }
+        }
+    }
+}
+
+fn preliminary_reply(cmd: &protocol::DataCommand) -> &'static str {
+    match cmd {
+        protocol::DataCommand::List(_)
+        | protocol::DataCommand::NLst(_)
+        | protocol::DataCommand::Mlsd(_) => "150 Here comes the directory listing",
+        _ => "150 Data connection open; transfer starting",
+    }
+}
+
+fn failed_transfer_reply(status: &protocol::DataReply) -> Option<&'static str> {
+    match status {
+        protocol::DataReply::RestartKo => {
+            Some("554 Requested action not taken: invalid REST parameter")
+        }
+        status if status.is_ok() => None,
+        _ => Some("550 Requested action not taken"),
+    }
+}
+
 fn data_loop<'a>(
-    data_server: &net::TcpListener,
+    data_server: &DataServer,
     from_control: &crossbeam_channel::Receiver<protocol::DataCommand>,
     to_control: &crossbeam_channel::Sender<protocol::DataReply>,
     to_client: &crossbeam_channel::Sender<Vec<String>>,
@@ -310,37 +701,84 @@ fn data_loop<'a>(
     loop {
         let cmd = from_control.recv()?;
 
-        let mut rdp = channel.connect(&super::SERVICE)?;
-
-        cmd.send(&mut rdp)?;
-
-        crate::trace!("{cmd}");
-
         if cmd.is_ftp_control() {
-            to_control.send(protocol::DataReply::receive(&mut rdp)?)?;
+            let mut rdp = channel.connect(&super::SERVICE)?;
+            cmd.send(&mut rdp)?;
+            crate::trace!("{cmd}");
+
+            // e.g. from another version of the backend, failing this
+            // command only
+            let reply = protocol::DataReply::receive(&mut rdp).unwrap_or_else(|e| {
+                crate::error!("invalid reply to {cmd}: {e}");
+                protocol::DataReply::Ko
+            });
+            audit(rdp.client_id(), data_server.peer, &cmd, reply.is_ok(), None);
+            to_control.send(reply)?;
 
             let _ = rdp.disconnect();
         } else {
-            let (client, client_addr) = data_server.accept()?;
+            // before replying and reaching the backend, which starts the
+            // transfer
+            let (client, client_addr) = match data_server.open() {
+                Ok(client) => client,
+                Err(e) => {
+                    crate::debug!("data connection error: {e}");
+                    to_client.send(vec!["425 Can't open data connection".into()])?;
+                    continue;
+                }
+            };
+
+            crate::debug!("data connection with {client_addr}");
 
-            crate::debug!("connection from {client_addr}");
+            let mut rdp = channel.connect(&super::SERVICE)?;
+            cmd.send(&mut rdp)?;
+            crate::trace!("{cmd}");
+
+            // older backends only end the stream when failing
+            let status = if 2 <= rdp.version() {
+                protocol::DataReply::receive(&mut rdp).unwrap_or_else(|e| {
+                    crate::error!("invalid status of {cmd}: {e}");
+                    protocol::DataReply::Ko
+                })
+            } else {
+                protocol::DataReply::DataTransferOk
+            };
+            if let Some(reply) = failed_transfer_reply(&status) {
+                // closes the data connection
+                drop(client);
+                audit(rdp.client_id(), data_server.peer, &cmd, false, None);
+                let _ = rdp.disconnect();
+                to_client.send(vec![reply.into()])?;
+                continue;
+            }
+
+            to_client.send(vec![preliminary_reply(&cmd).into()])?;
 
             let to_client = to_client.clone();
+            let client_id = rdp.client_id();
+            let tls = data_server.tls.clone();
+            let peer = data_server.peer;
             thread::Builder::new()
                 .name(format!(
                     "{SERVICE_KIND} {} data {client_addr}",
                     super::SERVICE
                 ))
-                .spawn_scoped(scope, move || match data_transfer(client, rdp, &cmd) {
-                    Err(e) => {
-                        crate::debug!("error {e}");
-                    }
-                    Ok(status) => {
-                        if status {
-                            let _ = to_client.send(vec!["226 Closing data connection".into()]);
-                        } else {
-                            let _ = to_client
-                                .send(vec!["426 Connection closed; transfer aborted".into()]);
+                .spawn_scoped(scope, move || {
+                    match service::ClientStream::accept(tls.as_ref(), client)
+                        .and_then(|client| data_transfer(client, rdp, &cmd))
+                    {
+                        Err(e) => {
+                            crate::debug!("error {e}");
+                            audit(client_id, peer, &cmd, false, None);
+                        }
+                        Ok(size) => {
+                            audit(client_id, peer, &cmd, size.is_some(), size);
+                            if size.is_some() {
+                                let _ = to_client.send(vec!["226 Closing data connection".into()]);
+                            } else {
+                                let _ = to_client
+                                    .send(vec!["426 Connection closed; transfer aborted".into()]);
+                            }
                         }
                     }
                 })
@@ -368,11 +806,22 @@ where
 pub(crate) fn tcp_handler<'a>(
     server: &service::TcpFrontendServer,
     scope: &'a thread::Scope<'a, '_>,
-    stream: net::TcpStream,
+    stream: service::ClientStream,
     channel: &'a service::Channel,
 ) -> Result<(), io::Error> {
-    let data_server = net::TcpListener::bind((server.ip, 0))?;
-    let data_port = data_server.local_addr().unwrap().port();
+    let data_server = sync::Arc::new(DataServer {
+        listener: net::TcpListener::bind((server.ip, 0))?,
+        tls: server.tls().cloned(),
+        peer: stream.peer_addr().ok(),
+        active: sync::Mutex::new(None),
+    });
+    // as reached by the client, IPv4 ones included on a dual-stack listener
+    let passive = net::SocketAddr::new(
+        stream
+            .local_addr()
+            .map_or(server.ip, |addr| addr.ip().to_canonical()),
+        data_server.listener.local_addr().unwrap().port(),
+    );
 
     let (control_to_data_send, control_to_data_receive) = crossbeam_channel::bounded(1);
     let (data_to_control_send, data_to_control_receive) = crossbeam_channel::bounded(1);
@@ -402,6 +851,7 @@ pub(crate) fn tcp_handler<'a>(
         .unwrap();
 
     let lto_control_send = to_control_send.clone();
+    let ldata_server = data_server.clone();
     thread::Builder::new()
         .name(format!(
             "{SERVICE_KIND} {} data {}",
@@ -412,7 +862,7 @@ pub(crate) fn tcp_handler<'a>(
         ))
         .spawn_scoped(scope, move || {
             if let Err(e) = data_loop(
-                &data_server,
+                &ldata_server,
                 &control_to_data_receive,
                 &data_to_control_send,
                 &lto_control_send,
@@ -432,8 +882,8 @@ pub(crate) fn tcp_handler<'a>(
         &to_control_send,
         &control_to_data_send,
         &data_to_control_receive,
-        server.ip,
-        data_port,
+        &data_server,
+        passive,
     ) {
         crate::debug!("error: {e}");
     } else {
@@ -444,3 +894,170 @@ pub(crate) fn tcp_handler<'a>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, DataServer, protocol};
+    use std::{io, net, str::FromStr, sync};
+
+    fn addr(s: &str) -> net::SocketAddr {
+        net::SocketAddr::from_str(s).unwrap()
+    }
+
+    fn server(peer: Option<&str>) -> DataServer {
+        DataServer {
+            listener: net::TcpListener::bind("127.0.0.1:0").unwrap(),
+            tls: None,
+            peer: peer.map(addr),
+            active: sync::Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn parse_port() {
+        assert_eq!(
+            super::parse_port("192,168,1,2,4,1"),
+            Some(addr("192.168.1.2:1025"))
+        );
+        assert_eq!(
+            super::parse_port(" 10, 0, 0, 1, 0, 21 "),
+            Some(addr("10.0.0.1:21"))
+        );
+        assert_eq!(super::parse_port("192,168,1,2,4"), None);
+        assert_eq!(super::parse_port("192,168,1,2,4,1,0"), None);
+        assert_eq!(super::parse_port("192,168,1,256,4,1"), None);
+        assert_eq!(super::parse_port("192,168,1,-2,4,1"), None);
+        assert_eq!(super::parse_port(""), None);
+    }
+
+    #[test]
+    fn parse_eprt() {
+        assert_eq!(
+            super::parse_eprt("|1|132.235.1.2|6275|"),
+            Some(addr("132.235.1.2:6275"))
+        );
+        assert_eq!(
+            super::parse_eprt("|2|1080::8:800:200C:417A|5282|"),
+            Some(addr("[1080::8:800:200C:417A]:5282"))
+        );
+        // any delimiter, even outside of ASCII
+        assert_eq!(
+            super::parse_eprt("!1!10.0.0.1!21!"),
+            Some(addr("10.0.0.1:21"))
+        );
+        assert_eq!(
+            super::parse_eprt("§1§10.0.0.1§21§"),
+            Some(addr("10.0.0.1:21"))
+        );
+        // the protocol must match the address
+        assert_eq!(super::parse_eprt("|1|::1|21|"), None);
+        assert_eq!(super::parse_eprt("|2|10.0.0.1|21|"), None);
+        assert_eq!(super::parse_eprt("|3|10.0.0.1|21|"), None);
+        assert_eq!(super::parse_eprt("|1|10.0.0.1|21"), None);
+        assert_eq!(super::parse_eprt("|1|10.0.0.1|65536|"), None);
+        assert_eq!(super::parse_eprt("|1|10.0.0.1|21|x"), None);
+        assert_eq!(super::parse_eprt(""), None);
+    }
+
+    #[test]
+    fn read_command() {
+        let read = |line: &[u8]| Command::read(&mut io::BufReader::new(line));
+
+        assert!(matches!(read(b"epsv\r\n"), Ok(Some(Command::Epsv))));
+        assert!(matches!(read(b"EPSV ALL\r\n"), Ok(Some(Command::Epsv))));
+        assert!(matches!(
+            read(b"EPRT |1|10.0.0.1|21|\r\n"),
+            Ok(Some(Command::Eprt(args))) if args == "|1|10.0.0.1|21|"
+        ));
+        assert!(matches!(
+            read(b"PORT 10,0,0,1,0,21\r\n"),
+            Ok(Some(Command::Port(args))) if args == "10,0,0,1,0,21"
+        ));
+        assert!(matches!(read(b"XMKD dir\r\n"), Ok(Some(Command::Mkd(_)))));
+        assert!(matches!(read(b"NOOP\r\n"), Ok(None)));
+        // lines must end with CRLF
+        assert!(matches!(read(b"EPSV\n"), Ok(None)));
+        assert!(matches!(read(b""), Err(e) if e.kind() == io::ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn passive_replies() {
+        let data_server = server(Some("127.0.0.1:5000"));
+
+        *data_server.active.lock().unwrap() = Some(addr("127.0.0.1:6000"));
+        assert_eq!(
+            super::cmd_epsv(&data_server, addr("10.0.0.1:50000")),
+            ["229 Entering Extended Passive Mode (|||50000|)"]
+        );
+        assert_eq!(*data_server.active.lock().unwrap(), None);
+
+        *data_server.active.lock().unwrap() = Some(addr("127.0.0.1:6000"));
+        assert_eq!(
+            super::cmd_pasv(&data_server, addr("10.0.0.1:50000")),
+            ["227 Entering Passive Mode (10,0,0,1,195,80)"]
+        );
+        assert_eq!(*data_server.active.lock().unwrap(), None);
+
+        assert_eq!(
+            super::cmd_pasv(&data_server, addr("[::1]:50000")),
+            ["425 Use EPSV with IPv6"]
+        );
+    }
+
+    #[test]
+    fn active_peer_only() {
+        let data_server = server(Some("127.0.0.1:5000"));
+
+        assert_eq!(
+            super::cmd_active(&data_server, Some(addr("127.0.0.1:6000"))),
+            ["200 Command okay"]
+        );
+        assert_eq!(
+            *data_server.active.lock().unwrap(),
+            Some(addr("127.0.0.1:6000"))
+        );
+        // the same host, as an IPv4-mapped address
+        assert_eq!(
+            super::cmd_active(&data_server, Some(addr("[::ffff:127.0.0.1]:6001"))),
+            ["200 Command okay"]
+        );
+        assert_eq!(
+            super::cmd_active(&data_server, Some(addr("10.0.0.1:6000"))),
+            ["500 Illegal PORT command"]
+        );
+        assert_eq!(
+            super::cmd_active(&data_server, None),
+            ["501 Syntax error in parameters"]
+        );
+
+        // no address to compare to through a Unix domain socket
+        let data_server = server(None);
+        assert_eq!(
+            super::cmd_active(&data_server, Some(addr("127.0.0.1:6000"))),
+            ["500 Illegal PORT command"]
+        );
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(super::quoted("/"), "\"/\"");
+        assert_eq!(super::quoted("/a \"b\""), "\"/a \"\"b\"\"\"");
+        assert_eq!(super::quoted("\""), "\"\"\"\"");
+    }
+
+    #[test]
+    fn failed_transfer_reply() {
+        assert_eq!(
+            super::failed_transfer_reply(&protocol::DataReply::DataTransferOk),
+            None
+        );
+        assert_eq!(
+            super::failed_transfer_reply(&protocol::DataReply::Ko),
+            Some("550 Requested action not taken")
+        );
+        assert_eq!(
+            super::failed_transfer_reply(&protocol::DataReply::RestartKo),
+            Some("554 Requested action not taken: invalid REST parameter")
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use crate::service;
+use crate::{calendar, service};
+use std::time;
 
 #[cfg(feature = "backend")]
 mod backend;
@@ -6,13 +7,35 @@ mod backend;
 mod frontend;
 mod protocol;
 
+/// Time value of RFC 3659, as in `MDTM` replies and `modify` facts.
+fn time_val(time: time::SystemTime) -> String {
+    let calendar::DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        ..
+    } = calendar::DateTime::utc(time);
+
+    format!("{year:04}{month:02}{day:02}{hour:02}{minute:02}{second:02}")
+}
+
 pub(crate) static SERVICE: service::Service = service::Service {
     name: "ftp",
     #[cfg(feature = "frontend")]
     tcp_frontend: Some(service::TcpFrontend {
         default_port: 2021,
+        enabled_by_default: true,
+        transparent: false,
+        token: false,
         handler: frontend::tcp_handler,
     }),
+    #[cfg(feature = "frontend")]
+    udp_frontend: None,
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tun_frontend: None,
     #[cfg(feature = "backend")]
     backend: service::Backend {
         handler: backend::handler,
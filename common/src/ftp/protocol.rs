@@ -7,6 +7,16 @@ const ID_NLST: u8 = 0x3;
 const ID_RETR: u8 = 0x4;
 const ID_SIZE: u8 = 0x5;
 const ID_STOR: u8 = 0x6;
+const ID_MKD: u8 = 0x7;
+const ID_RMD: u8 = 0x8;
+const ID_RENAME: u8 = 0x9;
+// followed by the offset to restart from
+const ID_RETR_AT: u8 = 0xa;
+const ID_STOR_AT: u8 = 0xb;
+const ID_APPE: u8 = 0xc;
+const ID_MLSD: u8 = 0xd;
+const ID_MLST: u8 = 0xe;
+const ID_MDTM: u8 = 0xf;
 
 #[derive(Debug)]
 pub enum DataCommand {
@@ -14,23 +24,66 @@ pub enum DataCommand {
     Dele(String),
     List(String),
     NLst(String),
-    Retr(String),
+    /// Path and offset to start from
+    Retr(String, u64),
     Size(String),
-    Stor(String),
+    /// Path and offset to start from
+    Stor(String, u64),
+    Appe(String),
+    Mkd(String),
+    Rmd(String),
+    Rename(String, String),
+    Mlsd(String),
+    Mlst(String),
+    Mdtm(String),
 }
 
 impl DataCommand {
-    #[cfg(feature = "frontend")]
+    /// Whether the command is answered by a reply rather than done over
+    /// a data connection.
     pub(crate) const fn is_ftp_control(&self) -> bool {
         match self {
-            Self::Dele(_) | Self::Cwd(_) | Self::Size(_) => true,
-            Self::List(_) | Self::NLst(_) | Self::Retr(_) | Self::Stor(_) => false,
+            Self::Dele(_)
+            | Self::Cwd(_)
+            | Self::Size(_)
+            | Self::Mkd(_)
+            | Self::Rmd(_)
+            | Self::Rename(_, _)
+            | Self::Mlst(_)
+            | Self::Mdtm(_) => true,
+            Self::List(_)
+            | Self::NLst(_)
+            | Self::Retr(_, _)
+            | Self::Stor(_, _)
+            | Self::Appe(_)
+            | Self::Mlsd(_) => false,
         }
     }
 
     #[cfg(feature = "frontend")]
     pub(crate) const fn is_upload(&self) -> bool {
-        matches!(self, Self::Stor(_))
+        matches!(self, Self::Stor(_, _) | Self::Appe(_))
+    }
+
+    /// Event and path of the command in the audit log, if recorded.
+    #[cfg(feature = "frontend")]
+    pub(crate) fn audited(&self) -> Option<(&'static str, &str)> {
+        match self {
+            Self::Cwd(path) => Some(("cwd", path)),
+            Self::Dele(path) => Some(("dele", path)),
+            Self::Retr(path, _) => Some(("retr", path)),
+            Self::Stor(path, _) => Some(("stor", path)),
+            Self::Appe(path) => Some(("appe", path)),
+            Self::Mkd(path) => Some(("mkd", path)),
+            Self::Rmd(path) => Some(("rmd", path)),
+            Self::Rename(from, _) => Some(("rename", from)),
+            Self::List(_)
+            | Self::NLst(_)
+            | Self::Size(_)
+            | Self::Mlsd(_)
+            | Self::Mlst(_)
+            | Self::Mdtm(_) => None,
+        }
     }
 
     #[cfg(feature = "frontend")]
@@ -38,14 +91,29 @@ impl DataCommand {
     where
         W: io::Write,
     {
-        let (code, value) = match self {
-            Self::Cwd(s) => (ID_CWD, s),
-            Self::Dele(s) => (ID_DELE, s),
-            Self::List(s) => (ID_LIST, s),
-            Self::NLst(s) => (ID_NLST, s),
-            Self::Retr(s) => (ID_RETR, s),
-            Self::Size(s) => (ID_SIZE, s),
-            Self::Stor(s) => (ID_STOR, s),
+        let rename;
+        let (code, value, offset) = match self {
+            Self::Cwd(s) => (ID_CWD, s, None),
+            Self::Dele(s) => (ID_DELE, s, None),
+            Self::List(s) => (ID_LIST, s, None),
+            Self::NLst(s) => (ID_NLST, s, None),
+            // without offset, as understood by older backends
+            Self::Retr(s, 0) => (ID_RETR, s, None),
+            Self::Retr(s, offset) => (ID_RETR_AT, s, Some(offset)),
+            Self::Size(s) => (ID_SIZE, s, None),
+            Self::Stor(s, 0) => (ID_STOR, s, None),
+            Self::Stor(s, offset) => (ID_STOR_AT, s, Some(offset)),
+            Self::Appe(s) => (ID_APPE, s, None),
+            Self::Mkd(s) => (ID_MKD, s, None),
+            Self::Rmd(s) => (ID_RMD, s, None),
+            Self::Rename(from, to) => {
+                // both paths separated by a NUL
+                rename = format!("{from}\0{to}");
+                (ID_RENAME, &rename, None)
+            }
+            Self::Mlsd(s) => (ID_MLSD, s, None),
+            Self::Mlst(s) => (ID_MLST, s, None),
+            Self::Mdtm(s) => (ID_MDTM, s, None),
         };
 
         let buf = [code; 1];
@@ -53,6 +121,9 @@ impl DataCommand {
         let len = value.len() as u64;
         stream.write_all(&len.to_le_bytes())?;
         stream.write_all(value.as_bytes())?;
+        if let Some(offset) = offset {
+            stream.write_all(&offset.to_le_bytes())?;
+        }
         stream.flush()
     }
 
@@ -75,15 +146,39 @@ impl DataCommand {
         stream.read_exact(&mut buf)?;
         let value = String::from_utf8_lossy(&buf).to_string();
 
+        let mut read_offset = || {
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf)?;
+            Ok::<_, io::Error>(u64::from_le_bytes(buf))
+        };
+
         match code {
             ID_CWD => Ok(Self::Cwd(value)),
             ID_DELE => Ok(Self::Dele(value)),
             ID_LIST => Ok(Self::List(value)),
             ID_NLST => Ok(Self::NLst(value)),
-            ID_RETR => Ok(Self::Retr(value)),
+            ID_RETR => Ok(Self::Retr(value, 0)),
+            ID_RETR_AT => Ok(Self::Retr(value, read_offset()?)),
             ID_SIZE => Ok(Self::Size(value)),
-            ID_STOR => Ok(Self::Stor(value)),
-            v => unimplemented!("unsupported ftp data command {v}"),
+            ID_STOR => Ok(Self::Stor(value, 0)),
+            ID_STOR_AT => Ok(Self::Stor(value, read_offset()?)),
+            ID_APPE => Ok(Self::Appe(value)),
+            ID_MKD => Ok(Self::Mkd(value)),
+            ID_RMD => Ok(Self::Rmd(value)),
+            ID_RENAME => match value.split_once('\0') {
+                Some((from, to)) => Ok(Self::Rename(from.to_string(), to.to_string())),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid rename command",
+                )),
+            },
+            ID_MLSD => Ok(Self::Mlsd(value)),
+            ID_MLST => Ok(Self::Mlst(value)),
+            ID_MDTM => Ok(Self::Mdtm(value)),
+            v => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported ftp data command {v}"),
+            )),
         }
     }
 }
@@ -95,36 +190,69 @@ impl fmt::Display for DataCommand {
             Self::Dele(path) => write!(f, "delete {path:?}"),
             Self::List(path) => write!(f, "list {path:?}"),
             Self::NLst(path) => write!(f, "nlst {path:?}"),
-            Self::Retr(path) => write!(f, "download {path:?}"),
+            Self::Retr(path, 0) => write!(f, "download {path:?}"),
+            Self::Retr(path, offset) => write!(f, "download {path:?} from {offset}"),
             Self::Size(path) => write!(f, "size {path:?}"),
-            Self::Stor(path) => write!(f, "upload {path:?}"),
+            Self::Stor(path, 0) => write!(f, "upload {path:?}"),
+            Self::Stor(path, offset) => write!(f, "upload {path:?} from {offset}"),
+            Self::Appe(path) => write!(f, "append to {path:?}"),
+            Self::Mkd(path) => write!(f, "make directory {path:?}"),
+            Self::Rmd(path) => write!(f, "remove directory {path:?}"),
+            Self::Rename(from, to) => write!(f, "rename {from:?} to {to:?}"),
+            Self::Mlsd(path) => write!(f, "machine list {path:?}"),
+            Self::Mlst(path) => write!(f, "machine list entry {path:?}"),
+            Self::Mdtm(path) => write!(f, "modification time {path:?}"),
         }
     }
 }
 
-#[cfg(feature = "frontend")]
 const ID_DATA_TRANSFER_OK: u8 = 0x0;
 const ID_CWD_OK: u8 = 0x1;
 const ID_SIZE_OK: u8 = 0x2;
 const ID_DELETE_OK: u8 = 0x3;
 const ID_KO: u8 = 0x4;
+const ID_MKD_OK: u8 = 0x5;
+const ID_RMD_OK: u8 = 0x6;
+const ID_RENAME_OK: u8 = 0x7;
+const ID_MDTM_OK: u8 = 0x8;
+const ID_MLST_OK: u8 = 0x9;
+const ID_RESTART_KO: u8 = 0xa;
 
+/// Answer to a command. Backends of version 2 or later also answer
+/// transfers, before their data, with [`DataReply::DataTransferOk`],
+/// [`DataReply::Ko`] or [`DataReply::RestartKo`].
 #[derive(Debug)]
 pub enum DataReply {
-    #[cfg(feature = "frontend")]
     DataTransferOk,
     CwdOk,
     SizeOk(u64),
     DeleteOk,
     Ko,
+    MkdOk,
+    RmdOk,
+    RenameOk,
+    /// Seconds since the Unix epoch
+    MdtmOk(u64),
+    /// Facts of the entry, as in an `MLSD` line
+    MlstOk(String),
+    /// Offset to restart a transfer from beyond the end of the file
+    RestartKo,
 }
 
 impl DataReply {
     #[cfg(feature = "frontend")]
     pub(crate) const fn is_ok(&self) -> bool {
         match self {
-            Self::DataTransferOk | Self::CwdOk | Self::SizeOk(_) | Self::DeleteOk => true,
-            Self::Ko => false,
+            Self::DataTransferOk
+            | Self::CwdOk
+            | Self::SizeOk(_)
+            | Self::DeleteOk
+            | Self::MkdOk
+            | Self::RmdOk
+            | Self::RenameOk
+            | Self::MdtmOk(_)
+            | Self::MlstOk(_) => true,
+            Self::Ko | Self::RestartKo => false,
         }
     }
 
@@ -133,13 +261,18 @@ impl DataReply {
     where
         W: io::Write,
     {
-        let (code, value) = match self {
-            #[cfg(feature = "frontend")]
-            Self::DataTransferOk => (ID_DATA_TRANSFER_OK, None),
-            Self::CwdOk => (ID_CWD_OK, None),
-            Self::SizeOk(size) => (ID_SIZE_OK, Some(size)),
-            Self::DeleteOk => (ID_DELETE_OK, None),
-            Self::Ko => (ID_KO, None),
+        let (code, value, text) = match self {
+            Self::DataTransferOk => (ID_DATA_TRANSFER_OK, None, None),
+            Self::CwdOk => (ID_CWD_OK, None, None),
+            Self::SizeOk(size) => (ID_SIZE_OK, Some(size), None),
+            Self::DeleteOk => (ID_DELETE_OK, None, None),
+            Self::Ko => (ID_KO, None, None),
+            Self::MkdOk => (ID_MKD_OK, None, None),
+            Self::RmdOk => (ID_RMD_OK, None, None),
+            Self::RenameOk => (ID_RENAME_OK, None, None),
+            Self::MdtmOk(time) => (ID_MDTM_OK, Some(time), None),
+            Self::MlstOk(facts) => (ID_MLST_OK, None, Some(facts)),
+            Self::RestartKo => (ID_RESTART_KO, None, None),
         };
 
         let mut buf = [0u8; 1];
@@ -148,6 +281,11 @@ impl DataReply {
         if let Some(value) = value {
             stream.write_all(&value.to_le_bytes())?;
         }
+        if let Some(text) = text {
+            let len = text.len() as u64;
+            stream.write_all(&len.to_le_bytes())?;
+            stream.write_all(text.as_bytes())?;
+        }
         stream.flush()
     }
 
@@ -160,19 +298,34 @@ impl DataReply {
         stream.read_exact(&mut buf)?;
         let code = buf[0];
 
+        let mut read_u64 = || {
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf)?;
+            Ok::<_, io::Error>(u64::from_le_bytes(buf))
+        };
+
         match code {
-            #[cfg(feature = "frontend")]
             ID_DATA_TRANSFER_OK => Ok(Self::DataTransferOk),
             ID_CWD_OK => Ok(Self::CwdOk),
-            ID_SIZE_OK => {
-                let mut buf = [0u8; 8];
-                stream.read_exact(&mut buf)?;
-                let size = u64::from_le_bytes(buf);
-                Ok(Self::SizeOk(size))
-            }
+            ID_SIZE_OK => Ok(Self::SizeOk(read_u64()?)),
             ID_DELETE_OK => Ok(Self::DeleteOk),
             ID_KO => Ok(Self::Ko),
-            v => unimplemented!("unsupported ftp data reply {v}"),
+            ID_MKD_OK => Ok(Self::MkdOk),
+            ID_RMD_OK => Ok(Self::RmdOk),
+            ID_RENAME_OK => Ok(Self::RenameOk),
+            ID_MDTM_OK => Ok(Self::MdtmOk(read_u64()?)),
+            ID_MLST_OK => {
+                let len = usize::try_from(read_u64()?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let mut buf = vec![0u8; len];
+                stream.read_exact(&mut buf)?;
+                Ok(Self::MlstOk(String::from_utf8_lossy(&buf).to_string()))
+            }
+            ID_RESTART_KO => Ok(Self::RestartKo),
+            v => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported ftp data reply {v}"),
+            )),
         }
     }
 }
@@ -180,12 +333,17 @@ impl DataReply {
 impl fmt::Display for DataReply {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            #[cfg(feature = "frontend")]
             Self::DataTransferOk => write!(f, "data transfer ok"),
             Self::CwdOk => write!(f, "change directory ok"),
             Self::SizeOk(size) => write!(f, "size ok ({size})"),
             Self::DeleteOk => write!(f, "delete ok"),
             Self::Ko => write!(f, "KO"),
+            Self::MkdOk => write!(f, "make directory ok"),
+            Self::RmdOk => write!(f, "remove directory ok"),
+            Self::RenameOk => write!(f, "rename ok"),
+            Self::MdtmOk(time) => write!(f, "modification time ok ({time})"),
+            Self::MlstOk(facts) => write!(f, "machine list entry ok ({facts})"),
+            Self::RestartKo => write!(f, "restart KO"),
         }
     }
 }
@@ -0,0 +1,80 @@
+//! Minimal JSON output of the audit log and of the JSON log format,
+//! one object per line.
+
+use crate::calendar::DateTime;
+use std::time;
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    out.extend(format!("\\u{unit:04x}").chars());
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+pub(crate) struct Object(String);
+
+impl Object {
+    pub(crate) fn new() -> Self {
+        Self(String::from("{"))
+    }
+
+    fn key(&mut self, name: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        write_string(&mut self.0, name);
+        self.0.push(':');
+    }
+
+    pub(crate) fn string(&mut self, name: &str, value: &str) -> &mut Self {
+        self.key(name);
+        write_string(&mut self.0, value);
+        self
+    }
+
+    pub(crate) fn number(&mut self, name: &str, value: u64) -> &mut Self {
+        self.key(name);
+        self.0.push_str(&value.to_string());
+        self
+    }
+
+    pub(crate) fn bool(&mut self, name: &str, value: bool) -> &mut Self {
+        self.key(name);
+        self.0.push_str(if value { "true" } else { "false" });
+        self
+    }
+
+    /// Closes the object and ends the line.
+    pub(crate) fn line(mut self) -> String {
+        self.0.push_str("}\n");
+        self.0
+    }
+}
+
+/// Formats `time` in RFC 3339 in UTC, with milliseconds.
+pub(crate) fn timestamp(time: time::SystemTime) -> String {
+    let DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        millis,
+    } = DateTime::utc(time);
+
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
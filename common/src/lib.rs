@@ -1,17 +1,38 @@
-use std::ffi;
-#[cfg(feature = "log")]
-use std::fs;
+use std::{ffi, time};
 
+#[cfg(feature = "frontend")]
+pub mod acl;
 pub mod api;
+pub mod audit;
+#[cfg(feature = "frontend")]
+pub mod auth;
+#[cfg(feature = "backend")]
+pub mod connector;
+pub mod limits;
+pub mod options;
+#[cfg(feature = "backend")]
+pub mod policy;
 pub mod service;
+#[cfg(feature = "frontend")]
+pub mod tls;
+#[cfg(feature = "backend")]
+pub mod upstream;
 
+mod calendar;
+mod cidr;
 mod clipboard;
 mod command;
+mod dns;
 mod ftp;
+mod json;
 mod socks5;
 mod stage0;
+mod transparent;
+mod tun;
 
 mod log;
+#[cfg(feature = "log")]
+mod logfile;
 #[cfg(feature = "backend")]
 mod util;
 
@@ -42,8 +63,39 @@ impl<'a> TryFrom<&'a str> for Level {
     }
 }
 
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl<'a> TryFrom<&'a str> for LogFormat {
+    type Error = String;
+
+    fn try_from(s: &'a str) -> Result<Self, <Self as TryFrom<&'a str>>::Error> {
+        match s.to_uppercase().as_ref() {
+            "TEXT" => Ok(Self::Text),
+            "JSON" => Ok(Self::Json),
+            _ => Err("invalid log format".into()),
+        }
+    }
+}
+
+/// File receiving the logs. It is rotated once it would exceed
+/// `max_size` bytes or is older than `rotate_interval`, and only the
+/// `max_files` most recent rotated files are kept.
+pub struct LogFile {
+    pub path: String,
+    pub format: LogFormat,
+    pub max_size: Option<u64>,
+    pub rotate_interval: Option<time::Duration>,
+    pub max_files: usize,
+}
+
 #[cfg(not(feature = "log"))]
-pub const fn init_logs(_level: Level, _file: Option<&String>) {}
+pub const fn init_logs(_level: Level, _file: Option<&LogFile>) {}
+
+#[cfg(not(feature = "log"))]
+pub const fn set_log_level(_level: Level) {}
 
 #[cfg(feature = "log")]
 impl Into<simplelog::LevelFilter> for Level {
@@ -59,9 +111,12 @@ impl Into<simplelog::LevelFilter> for Level {
     }
 }
 
+/// Initializes the logs on the terminal and in `file`. Loggers let
+/// everything through, the level is enforced globally so that it can
+/// be changed later with [`set_log_level`].
 #[cfg(feature = "log")]
-pub fn init_logs(level: Level, file: Option<&String>) {
-    let level_filter = level.into();
+pub fn init_logs(level: Level, file: Option<&LogFile>) {
+    let level_filter = simplelog::LevelFilter::Trace;
 
     let config = simplelog::ConfigBuilder::new()
         .set_level_padding(simplelog::LevelPadding::Right)
@@ -79,16 +134,17 @@ pub fn init_logs(level: Level, file: Option<&String>) {
     )];
 
     if let Some(file) = file {
-        if let Ok(file) = fs::File::options()
-            .create(true)
-            .append(false)
-            .truncate(true)
-            .write(true)
-            .open(file)
-        {
-            loggers.push(simplelog::WriteLogger::new(level_filter, config, file));
+        if let Ok(logger) = logfile::Logger::new(level_filter, config, file) {
+            loggers.push(logger);
         }
     }
 
-    let _ = simplelog::CombinedLogger::init(loggers);
+    if simplelog::CombinedLogger::init(loggers).is_ok() {
+        set_log_level(level);
+    }
+}
+
+#[cfg(feature = "log")]
+pub fn set_log_level(level: Level) {
+    ::log::set_max_level(level.into());
 }
@@ -0,0 +1,151 @@
+//! Bounds on the streams of a service: number of concurrent
+//! connections, time without data in either direction and total
+//! lifetime.
+//!
+//! The frontend reads them per service from its configuration file.
+//! The backend applies the same limits to all services, read from
+//! environment variables:
+//!
+//! - `SOXY_MAX_CONNECTIONS`: maximum number of concurrent streams of
+//!   a service;
+//! - `SOXY_IDLE_TIMEOUT`: time in seconds without data after which a
+//!   stream is closed;
+//! - `SOXY_MAX_LIFETIME`: time in seconds after which a stream is
+//!   closed.
+//!
+//! Nothing is bounded by default on both sides.
+
+#[cfg(feature = "backend")]
+use std::env;
+use std::{
+    fmt,
+    sync::{self, atomic},
+    time,
+};
+
+#[cfg(feature = "backend")]
+const ENV_MAX_CONNECTIONS: &str = "SOXY_MAX_CONNECTIONS";
+#[cfg(feature = "backend")]
+const ENV_IDLE_TIMEOUT: &str = "SOXY_IDLE_TIMEOUT";
+#[cfg(feature = "backend")]
+const ENV_MAX_LIFETIME: &str = "SOXY_MAX_LIFETIME";
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyInitialized,
+    InvalidValue(&'static str, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::AlreadyInitialized => write!(f, "limits already initialized"),
+            Self::InvalidValue(name, s) => write!(f, "invalid value {s:?} for {name}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_connections: Option<usize>,
+    pub idle_timeout: Option<time::Duration>,
+    pub max_lifetime: Option<time::Duration>,
+}
+
+impl Limits {
+    /// Time at which a stream opened at `started`, with data seen
+    /// last at `active`, must be closed.
+    pub(crate) fn deadline(
+        &self,
+        started: time::Instant,
+        active: time::Instant,
+    ) -> Option<time::Instant> {
+        let idle = self.idle_timeout.map(|timeout| active + timeout);
+        let lifetime = self.max_lifetime.map(|lifetime| started + lifetime);
+        match (idle, lifetime) {
+            (Some(idle), Some(lifetime)) => Some(idle.min(lifetime)),
+            (deadline, None) | (None, deadline) => deadline,
+        }
+    }
+}
+
+#[cfg(feature = "backend")]
+static BACKEND: sync::OnceLock<Limits> = sync::OnceLock::new();
+
+#[cfg(feature = "backend")]
+pub fn init(limits: Limits) -> Result<(), Error> {
+    crate::info!(
+        "max connections {:?}, idle timeout {:?}, max lifetime {:?}",
+        limits.max_connections,
+        limits.idle_timeout,
+        limits.max_lifetime
+    );
+    BACKEND.set(limits).map_err(|_| Error::AlreadyInitialized)
+}
+
+#[cfg(feature = "backend")]
+fn env_value(name: &'static str, build_value: Option<&str>) -> Result<Option<u64>, Error> {
+    match env::var(name)
+        .ok()
+        .or_else(|| build_value.map(ToString::to_string))
+    {
+        None => Ok(None),
+        Some(value) => match value.trim().parse::<u64>() {
+            Ok(0) | Err(_) => Err(Error::InvalidValue(name, value)),
+            Ok(value) => Ok(Some(value)),
+        },
+    }
+}
+
+/// Loads the limits of the backend from the environment variables at
+/// startup, or from the same variables at build time.
+#[cfg(feature = "backend")]
+pub fn init_from_env() -> Result<(), Error> {
+    let max_connections = env_value(ENV_MAX_CONNECTIONS, option_env!("SOXY_MAX_CONNECTIONS"))?
+        .map(|max| usize::try_from(max).unwrap_or(usize::MAX));
+    let idle_timeout = env_value(ENV_IDLE_TIMEOUT, option_env!("SOXY_IDLE_TIMEOUT"))?
+        .map(time::Duration::from_secs);
+    let max_lifetime = env_value(ENV_MAX_LIFETIME, option_env!("SOXY_MAX_LIFETIME"))?
+        .map(time::Duration::from_secs);
+
+    init(Limits {
+        max_connections,
+        idle_timeout,
+        max_lifetime,
+    })
+}
+
+#[cfg(feature = "backend")]
+pub(crate) fn backend() -> Limits {
+    BACKEND.get().copied().unwrap_or_default()
+}
+
+/// Number of open connections of a service.
+#[derive(Clone, Default)]
+pub(crate) struct Counter(sync::Arc<atomic::AtomicUsize>);
+
+impl Counter {
+    /// Counts a new connection, unless `max` connections are already
+    /// open. The connection is counted until the returned slot is
+    /// dropped.
+    pub(crate) fn acquire(&self, max: Option<usize>) -> Option<Slot> {
+        self.0
+            .fetch_update(atomic::Ordering::AcqRel, atomic::Ordering::Acquire, |n| {
+                if max.is_some_and(|max| max <= n) {
+                    None
+                } else {
+                    Some(n + 1)
+                }
+            })
+            .ok()
+            .map(|_| Slot(self.0.clone()))
+    }
+}
+
+pub(crate) struct Slot(sync::Arc<atomic::AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::AcqRel);
+    }
+}
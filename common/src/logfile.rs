@@ -0,0 +1,234 @@
+//! Log file written in text or JSON lines, rotated when it gets too
+//! large or too old.
+
+use crate::{LogFile, LogFormat, json, service};
+use std::{
+    fs,
+    io::{self, Write},
+    mem, net, sync, thread, time,
+};
+
+struct Rotating {
+    path: String,
+    file: fs::File,
+    size: u64,
+    opened: time::Instant,
+    max_size: Option<u64>,
+    rotate_interval: Option<time::Duration>,
+    max_files: usize,
+}
+
+impl Rotating {
+    fn open(config: &LogFile) -> Result<Self, io::Error> {
+        let rotated = config.max_size.is_some() || config.rotate_interval.is_some();
+
+        // without rotation, the log only covers the current run
+        let file = fs::File::options()
+            .create(true)
+            .append(rotated)
+            .truncate(!rotated)
+            .write(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: config.path.clone(),
+            file,
+            size,
+            opened: time::Instant::now(),
+            max_size: config.max_size,
+            rotate_interval: config.rotate_interval,
+            max_files: config.max_files,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> String {
+        format!("{}.{n}", self.path)
+    }
+
+    /// Shifts the previous files, the most recent one being suffixed
+    /// with `.1`, and drops the oldest one beyond the retained count.
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        if 0 < self.max_files {
+            match fs::remove_file(self.rotated_path(self.max_files)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            for n in (1..self.max_files).rev() {
+                match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = time::Instant::now();
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> Result<(), io::Error> {
+        let len = record.len() as u64;
+        let too_large = self.max_size.is_some_and(|max| max < self.size + len);
+        let too_old = self
+            .rotate_interval
+            .is_some_and(|interval| interval <= self.opened.elapsed());
+
+        if 0 < self.size && (too_large || too_old) {
+            self.rotate()?;
+        }
+
+        self.file.write_all(record)?;
+        self.size += len;
+
+        Ok(())
+    }
+}
+
+/// Output of the text logger, holding a record until it is written to
+/// the file as a whole.
+#[derive(Clone, Default)]
+struct Buffer(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+impl io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+enum Layout {
+    Text(Box<simplelog::WriteLogger<Buffer>>, Buffer),
+    Json,
+}
+
+/// Service, client ID and client address of a thread of a stream,
+/// named as `<kind> <service> <client ID or address> ...`.
+fn context(thread: &str) -> Option<(&'static str, Option<&str>, Option<&str>)> {
+    let mut words = thread.split(' ');
+    let mut word = words.next();
+    if matches!(word, Some("frontend" | "backend")) {
+        word = words.next();
+    }
+    let service = service::lookup(word?)?.name();
+
+    let mut client_id = None;
+    let mut client = None;
+    for word in words {
+        if client.is_none() && word.parse::<net::SocketAddr>().is_ok() {
+            client = Some(word);
+        } else if client_id.is_none()
+            && !word.is_empty()
+            && word.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            client_id = Some(word);
+        }
+    }
+
+    Some((service, client_id, client))
+}
+
+fn json_record(record: &log::Record<'_>) -> Vec<u8> {
+    let mut line = json::Object::new();
+    line.string("time", &json::timestamp(time::SystemTime::now()))
+        .string("level", record.level().as_str());
+
+    let thread = thread::current();
+    if let Some(name) = thread.name() {
+        line.string("thread", name);
+        if let Some((service, client_id, client)) = context(name) {
+            line.string("service", service);
+            if let Some(client_id) = client_id {
+                line.string("client_id", client_id);
+            }
+            if let Some(client) = client {
+                line.string("client", client);
+            }
+        }
+    }
+
+    line.string("message", &record.args().to_string());
+    line.line().into_bytes()
+}
+
+pub(crate) struct Logger {
+    level: log::LevelFilter,
+    layout: Layout,
+    file: sync::Mutex<Rotating>,
+}
+
+impl Logger {
+    pub(crate) fn new(
+        level: log::LevelFilter,
+        config: simplelog::Config,
+        file: &LogFile,
+    ) -> Result<Box<Self>, io::Error> {
+        let layout = match file.format {
+            LogFormat::Text => {
+                let buffer = Buffer::default();
+                Layout::Text(
+                    simplelog::WriteLogger::new(level, config, buffer.clone()),
+                    buffer,
+                )
+            }
+            LogFormat::Json => Layout::Json,
+        };
+
+        Ok(Box::new(Self {
+            level,
+            layout,
+            file: sync::Mutex::new(Rotating::open(file)?),
+        }))
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut file = self.file.lock().unwrap();
+        let record = match &self.layout {
+            Layout::Text(logger, buffer) => {
+                logger.log(record);
+                mem::take(&mut *buffer.0.lock().unwrap())
+            }
+            Layout::Json => json_record(record),
+        };
+        let _ = file.write_record(&record);
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().file.flush();
+    }
+}
+
+impl simplelog::SharedLogger for Logger {
+    fn level(&self) -> log::LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        Box::new(*self)
+    }
+}
@@ -0,0 +1,275 @@
+//! Settings of the services, given by the `options` table of their
+//! configuration. Some of them are forwarded to the backend in the
+//! start chunk of each stream, after the name of the service.
+
+use crate::{command, ftp, service, socks5};
+use std::{collections, fmt};
+
+pub enum Error {
+    Unknown(String),
+    Invalid(String, &'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Unknown(name) => write!(f, "unknown option {name:?}"),
+            Self::Invalid(name, reason) => write!(f, "invalid option {name:?}: {reason}"),
+        }
+    }
+}
+
+/// Options as text, by name.
+pub type Raw = collections::BTreeMap<String, String>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Command {
+    /// Program run by the backend instead of the default shell
+    pub shell: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ftp {
+    /// Directory of the backend the clients are confined to
+    pub root: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Socks5 {
+    /// User name and password required from the clients
+    pub credentials: Option<(String, String)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Options {
+    #[default]
+    None,
+    Command(Command),
+    Ftp(Ftp),
+    Socks5(Socks5),
+}
+
+fn take(raw: &mut Raw, name: &str) -> Result<Option<String>, Error> {
+    match raw.remove(name) {
+        None => Ok(None),
+        Some(value) if value.is_empty() => Err(Error::Invalid(name.into(), "empty value")),
+        // values are sent one per line to the backend
+        Some(value) if value.contains(['\n', '\0']) => {
+            Err(Error::Invalid(name.into(), "line breaks are not allowed"))
+        }
+        Some(value) => Ok(Some(value)),
+    }
+}
+
+impl Options {
+    /// Checks and types the options of `service`.
+    pub fn parse(service: &service::Service, raw: &Raw) -> Result<Self, Error> {
+        let mut raw = raw.clone();
+
+        let options = if service.name() == command::SERVICE.name() {
+            Self::Command(Command {
+                shell: take(&mut raw, "shell")?,
+            })
+        } else if service.name() == ftp::SERVICE.name() {
+            Self::Ftp(Ftp {
+                root: take(&mut raw, "root")?,
+            })
+        } else if service.name() == socks5::SERVICE.name() {
+            let username = take(&mut raw, "username")?;
+            let password = take(&mut raw, "password")?;
+            let credentials = match (username, password) {
+                (None, None) => None,
+                (Some(username), Some(password)) => Some((username, password)),
+                (Some(_), None) => {
+                    return Err(Error::Invalid("password".into(), "missing"));
+                }
+                (None, Some(_)) => {
+                    return Err(Error::Invalid("username".into(), "missing"));
+                }
+            };
+            Self::Socks5(Socks5 { credentials })
+        } else {
+            Self::None
+        };
+
+        match raw.into_keys().next() {
+            Some(name) => Err(Error::Unknown(name)),
+            None => Ok(options),
+        }
+    }
+
+    /// Options needed by the backend.
+    pub fn forwarded(&self) -> Raw {
+        let mut raw = Raw::new();
+        match self {
+            Self::Command(Command { shell: Some(shell) }) => {
+                raw.insert("shell".into(), shell.clone());
+            }
+            Self::Ftp(Ftp { root: Some(root) }) => {
+                raw.insert("root".into(), root.clone());
+            }
+            _ => (),
+        }
+        raw
+    }
+}
+
+// announces the version of the frontend, among the options
+const VERSION: &str = "version";
+
+/// Name of the service followed by the `raw` options, one `name=value`
+/// per line. Backends of `version` 2 or later are also told the version of
+/// the frontend.
+#[cfg(feature = "frontend")]
+pub(crate) fn header(service: &service::Service, version: u8, raw: &Raw) -> Vec<u8> {
+    let mut header = service.name().as_bytes().to_vec();
+    for (name, value) in raw {
+        header.push(b'\n');
+        header.extend_from_slice(name.as_bytes());
+        header.push(b'=');
+        header.extend_from_slice(value.as_bytes());
+    }
+    if 2 <= version {
+        header.extend_from_slice(format!("\n{VERSION}={}", crate::api::VERSION).as_bytes());
+    }
+    header
+}
+
+/// Splits a header built by [`header`] into the name of the service, the
+/// version of the frontend (0 if not told) and the options.
+#[cfg(feature = "backend")]
+pub(crate) fn parse_header(header: &[u8]) -> (&[u8], u8, Raw) {
+    let mut lines = header.split(|b| *b == b'\n');
+    let name = lines.next().unwrap_or_default();
+    let mut raw: Raw = lines
+        .filter_map(|line| {
+            let line = String::from_utf8_lossy(line);
+            line.split_once('=')
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        })
+        .collect();
+    let version = raw
+        .remove(VERSION)
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0);
+    (name, version, raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Error, Ftp, Options, Raw, Socks5};
+    use crate::{clipboard, command, ftp, socks5};
+
+    fn raw(options: &[(&str, &str)]) -> Raw {
+        options
+            .iter()
+            .map(|(name, value)| ((*name).to_string(), (*value).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Options::parse(&command::SERVICE, &raw(&[("shell", "/bin/zsh")])).ok(),
+            Some(Options::Command(Command {
+                shell: Some("/bin/zsh".into())
+            }))
+        );
+        assert_eq!(
+            Options::parse(&ftp::SERVICE, &Raw::new()).ok(),
+            Some(Options::Ftp(Ftp { root: None }))
+        );
+        assert_eq!(
+            Options::parse(
+                &socks5::SERVICE,
+                &raw(&[("username", "user"), ("password", "secret")])
+            )
+            .ok(),
+            Some(Options::Socks5(Socks5 {
+                credentials: Some(("user".into(), "secret".into()))
+            }))
+        );
+        assert_eq!(
+            Options::parse(&clipboard::SERVICE, &Raw::new()).ok(),
+            Some(Options::None)
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(matches!(
+            Options::parse(&ftp::SERVICE, &raw(&[("root", "/srv"), ("rot", "/srv")])),
+            Err(Error::Unknown(name)) if name == "rot"
+        ));
+        assert!(matches!(
+            Options::parse(&clipboard::SERVICE, &raw(&[("root", "/srv")])),
+            Err(Error::Unknown(_))
+        ));
+        assert!(matches!(
+            Options::parse(&ftp::SERVICE, &raw(&[("root", "")])),
+            Err(Error::Invalid(name, _)) if name == "root"
+        ));
+        assert!(matches!(
+            Options::parse(&command::SERVICE, &raw(&[("shell", "sh\nid")])),
+            Err(Error::Invalid(name, _)) if name == "shell"
+        ));
+        assert!(matches!(
+            Options::parse(&socks5::SERVICE, &raw(&[("username", "user")])),
+            Err(Error::Invalid(name, _)) if name == "password"
+        ));
+        assert!(matches!(
+            Options::parse(&socks5::SERVICE, &raw(&[("password", "secret")])),
+            Err(Error::Invalid(name, _)) if name == "username"
+        ));
+    }
+
+    #[test]
+    fn forwarded() {
+        let options = Options::parse(&ftp::SERVICE, &raw(&[("root", "/srv")])).ok();
+        assert_eq!(
+            options.map(|o| o.forwarded()),
+            Some(raw(&[("root", "/srv")]))
+        );
+
+        // credentials are checked by the frontend only
+        let options = Options::parse(
+            &socks5::SERVICE,
+            &raw(&[("username", "user"), ("password", "secret")]),
+        )
+        .ok();
+        assert_eq!(options.map(|o| o.forwarded()), Some(Raw::new()));
+    }
+
+    #[cfg(feature = "backend")]
+    #[test]
+    fn parse_header() {
+        assert_eq!(super::parse_header(b"ftp"), (&b"ftp"[..], 0, Raw::new()));
+        assert_eq!(
+            super::parse_header(b"ftp\nroot=/srv/a=b\nversion=2"),
+            (&b"ftp"[..], 2, raw(&[("root", "/srv/a=b")]))
+        );
+        // lines without a value are ignored, as is an invalid version
+        assert_eq!(
+            super::parse_header(b"command\nshell\nversion=x"),
+            (&b"command"[..], 0, Raw::new())
+        );
+        assert_eq!(super::parse_header(b""), (&b""[..], 0, Raw::new()));
+    }
+
+    #[cfg(all(feature = "backend", feature = "frontend"))]
+    #[test]
+    fn header() {
+        let options = raw(&[("root", "/srv")]);
+
+        let header = super::header(&ftp::SERVICE, 2, &options);
+        assert_eq!(
+            super::parse_header(&header),
+            (&b"ftp"[..], crate::api::VERSION, options.clone())
+        );
+
+        // older backends would take the version for an unknown option
+        let header = super::header(&ftp::SERVICE, 1, &options);
+        assert_eq!(header, b"ftp\nroot=/srv");
+        assert_eq!(super::parse_header(&header), (&b"ftp"[..], 0, options));
+    }
+}
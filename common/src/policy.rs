@@ -0,0 +1,442 @@
+//! Destination policy enforced by the backend on every outgoing
+//! connection, every bind and every lookup.
+//!
+//! A policy is a list of rules separated by `;` or new lines:
+//!
+//! ```text
+//! allow *.corp.local 443
+//! deny 10.0.0.0/8
+//! allow 192.168.1.0/24 22,80,8000-8999
+//! deny *
+//! ```
+//!
+//! Rules are evaluated in order and the first matching rule decides. A
+//! destination matching no rule is allowed.
+
+use crate::cidr::Cidr;
+use std::{env, fmt, net, str::FromStr, sync};
+
+const ENV_POLICY: &str = "SOXY_POLICY";
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyInitialized,
+    InvalidAction(String),
+    InvalidCidr(String),
+    InvalidPort(String),
+    InvalidRule(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::AlreadyInitialized => write!(f, "policy already initialized"),
+            Self::InvalidAction(s) => write!(f, "invalid policy action {s:?}"),
+            Self::InvalidCidr(s) => write!(f, "invalid policy CIDR {s:?}"),
+            Self::InvalidPort(s) => write!(f, "invalid policy port {s:?}"),
+            Self::InvalidRule(s) => write!(f, "invalid policy rule {s:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "allow" | "accept" => Ok(Self::Allow),
+            "deny" | "reject" => Ok(Self::Deny),
+            _ => Err(Error::InvalidAction(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Host {
+    Any,
+    Cidr(Cidr),
+    Domain(String),
+    DomainSuffix(String),
+}
+
+impl Host {
+    fn parse(s: &str) -> Result<Self, Error> {
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+
+        if s.contains('/') || net::IpAddr::from_str(s).is_ok() {
+            return Cidr::from_str(s)
+                .map(Self::Cidr)
+                .map_err(|()| Error::InvalidCidr(s.to_string()));
+        }
+
+        match s.strip_prefix("*.") {
+            Some(suffix) => Ok(Self::DomainSuffix(suffix.to_lowercase())),
+            None => Ok(Self::Domain(s.to_lowercase())),
+        }
+    }
+
+    fn matches(&self, name: Option<&str>, ip: Option<net::IpAddr>) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Cidr(cidr) => ip.is_some_and(|ip| cidr.contains(ip)),
+            Self::Domain(domain) => name.is_some_and(|name| name.eq_ignore_ascii_case(domain)),
+            Self::DomainSuffix(suffix) => name.is_some_and(|name| {
+                let name = name.to_lowercase();
+                name.strip_suffix(suffix.as_str())
+                    .is_some_and(|head| head.ends_with('.'))
+            }),
+        }
+    }
+}
+
+fn parse_ports(s: &str) -> Result<Vec<(u16, u16)>, Error> {
+    s.split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start = u16::from_str(start).map_err(|_| Error::InvalidPort(s.to_string()))?;
+            let end = u16::from_str(end).map_err(|_| Error::InvalidPort(s.to_string()))?;
+            if end < start {
+                return Err(Error::InvalidPort(s.to_string()));
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct Rule {
+    text: String,
+    action: Action,
+    host: Host,
+    ports: Option<Vec<(u16, u16)>>,
+}
+
+impl Rule {
+    fn parse(s: &str) -> Result<Self, Error> {
+        let mut words = s.split_whitespace();
+
+        let action = words
+            .next()
+            .ok_or_else(|| Error::InvalidRule(s.to_string()))?
+            .parse()?;
+        let host = Host::parse(
+            words
+                .next()
+                .ok_or_else(|| Error::InvalidRule(s.to_string()))?,
+        )?;
+        let ports = match words.next() {
+            None | Some("*") => None,
+            Some(ports) => Some(parse_ports(ports)?),
+        };
+
+        if words.next().is_some() {
+            return Err(Error::InvalidRule(s.to_string()));
+        }
+
+        Ok(Self {
+            text: s.split_whitespace().collect::<Vec<_>>().join(" "),
+            action,
+            host,
+            ports,
+        })
+    }
+
+    fn matches(&self, name: Option<&str>, ip: Option<net::IpAddr>, port: u16) -> bool {
+        self.host.matches(name, ip)
+            && self.ports.as_ref().is_none_or(|ports| {
+                ports
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&port))
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let rules = s
+            .split([';', '\n'])
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty() && !rule.starts_with('#'))
+            .map(Rule::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Checks a destination, `name` being the host name requested by
+    /// the client (if any) and `addr` the resolved address. Returns
+    /// the matching deny rule on refusal.
+    pub(crate) fn check(&self, name: Option<&str>, addr: net::SocketAddr) -> Result<(), &str> {
+        self.check_rules(name, Some(addr.ip()), addr.port())
+    }
+
+    /// Checks a destination which was not resolved locally, e.g. when
+    /// the upstream proxy resolves it. CIDR rules never match.
+    pub(crate) fn check_unresolved(&self, name: &str, port: u16) -> Result<(), &str> {
+        self.check_rules(Some(name), None, port)
+    }
+
+    /// Checks a name or an address looked up by the client without
+    /// connecting, which is allowed when at least one port of it is.
+    pub(crate) fn check_lookup(
+        &self,
+        name: Option<&str>,
+        ip: Option<net::IpAddr>,
+    ) -> Result<(), &str> {
+        // decisions only change at the bounds of the port ranges
+        let ports = self
+            .rules
+            .iter()
+            .filter_map(|rule| rule.ports.as_ref())
+            .flatten()
+            .flat_map(|(start, end)| [Some(*start), end.checked_add(1)])
+            .flatten();
+
+        let mut denied = Ok(());
+        for port in std::iter::once(0).chain(ports) {
+            match self.check_rules(name, ip, port) {
+                Ok(()) => return Ok(()),
+                Err(rule) => denied = denied.and(Err(rule)),
+            }
+        }
+        denied
+    }
+
+    fn check_rules(
+        &self,
+        name: Option<&str>,
+        ip: Option<net::IpAddr>,
+        port: u16,
+    ) -> Result<(), &str> {
+        match self.rules.iter().find(|rule| rule.matches(name, ip, port)) {
+            Some(rule) if rule.action == Action::Deny => Err(rule.text.as_str()),
+            _ => Ok(()),
+        }
+    }
+}
+
+static POLICY: sync::OnceLock<Policy> = sync::OnceLock::new();
+
+pub fn init(policy: Policy) -> Result<(), Error> {
+    crate::info!("destination policy has {} rule(s)", policy.rules.len());
+    POLICY.set(policy).map_err(|_| Error::AlreadyInitialized)
+}
+
+/// Loads the policy from the `SOXY_POLICY` environment variable at
+/// startup, or from the same variable at build time.
+pub fn init_from_env() -> Result<(), Error> {
+    let rules = env::var(ENV_POLICY)
+        .ok()
+        .or_else(|| option_env!("SOXY_POLICY").map(ToString::to_string));
+
+    match rules {
+        None => Ok(()),
+        Some(rules) => init(Policy::parse(&rules)?),
+    }
+}
+
+pub(crate) fn check(name: Option<&str>, addr: net::SocketAddr) -> Result<(), String> {
+    match POLICY.get() {
+        None => Ok(()),
+        Some(policy) => policy.check(name, addr).map_err(|rule| {
+            crate::warn!("{addr} ({name:?}) denied by policy rule {rule:?}");
+            rule.to_string()
+        }),
+    }
+}
+
+pub(crate) fn check_lookup(name: Option<&str>, ip: Option<net::IpAddr>) -> Result<(), String> {
+    match POLICY.get() {
+        None => Ok(()),
+        Some(policy) => policy.check_lookup(name, ip).map_err(|rule| {
+            match ip {
+                Some(ip) => {
+                    crate::warn!("lookup of {ip} ({name:?}) denied by policy rule {rule:?}");
+                }
+                None => {
+                    crate::warn!("lookup of {name:?} denied by policy rule {rule:?}");
+                }
+            }
+            rule.to_string()
+        }),
+    }
+}
+
+pub(crate) fn check_unresolved(name: &str, port: u16) -> Result<(), String> {
+    match POLICY.get() {
+        None => Ok(()),
+        Some(policy) => policy.check_unresolved(name, port).map_err(|rule| {
+            crate::warn!("{name}:{port} denied by policy rule {rule:?}");
+            rule.to_string()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Policy};
+    use std::{net, str::FromStr};
+
+    const POLICY: &str = "
+        # corporate network
+        allow *.corp.local 443
+        deny 10.0.0.0/8
+        allow 192.168.1.0/24 22,80,8000-8999
+        deny blocked.example.org
+        deny 192.168.0.0/16
+    ";
+
+    fn addr(s: &str) -> net::SocketAddr {
+        net::SocketAddr::from_str(s).unwrap()
+    }
+
+    fn ip(s: &str) -> net::IpAddr {
+        net::IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Policy::parse(POLICY).unwrap().rules.len(), 5);
+        assert_eq!(Policy::parse("allow *; deny *").unwrap().rules.len(), 2);
+        assert_eq!(Policy::parse("").unwrap().rules.len(), 0);
+        assert!(Policy::parse("ACCEPT example.org *").is_ok());
+        assert!(matches!(
+            Policy::parse("permit *"),
+            Err(Error::InvalidAction(_))
+        ));
+        assert!(matches!(
+            Policy::parse("deny 10.0.0.0/40"),
+            Err(Error::InvalidCidr(_))
+        ));
+        assert!(matches!(
+            Policy::parse("deny * 80-22"),
+            Err(Error::InvalidPort(_))
+        ));
+        assert!(matches!(
+            Policy::parse("deny * 65536"),
+            Err(Error::InvalidPort(_))
+        ));
+        assert!(matches!(Policy::parse("deny"), Err(Error::InvalidRule(_))));
+        assert!(matches!(
+            Policy::parse("deny * 80 443"),
+            Err(Error::InvalidRule(_))
+        ));
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let policy = Policy::parse(POLICY).unwrap();
+
+        assert!(
+            policy
+                .check(Some("git.corp.local"), addr("10.1.2.3:443"))
+                .is_ok()
+        );
+        assert_eq!(
+            policy.check(Some("git.corp.local"), addr("10.1.2.3:22")),
+            Err("deny 10.0.0.0/8")
+        );
+        assert!(policy.check(None, addr("192.168.1.5:8080")).is_ok());
+        assert!(policy.check(None, addr("192.168.1.5:22")).is_ok());
+        assert_eq!(
+            policy.check(None, addr("192.168.1.5:443")),
+            Err("deny 192.168.0.0/16")
+        );
+        // no rule matches
+        assert!(policy.check(None, addr("203.0.113.1:443")).is_ok());
+    }
+
+    #[test]
+    fn names() {
+        let policy = Policy::parse(POLICY).unwrap();
+
+        assert!(
+            policy
+                .check(Some("GIT.Corp.Local"), addr("10.1.2.3:443"))
+                .is_ok()
+        );
+        // the suffix needs a label in front of it
+        assert!(
+            policy
+                .check(Some("corp.local"), addr("10.1.2.3:443"))
+                .is_err()
+        );
+        assert!(
+            policy
+                .check(Some("evilcorp.local"), addr("10.1.2.3:443"))
+                .is_err()
+        );
+        assert!(
+            policy
+                .check(Some("blocked.example.org"), addr("203.0.113.1:80"))
+                .is_err()
+        );
+        assert!(
+            policy
+                .check(Some("www.blocked.example.org"), addr("203.0.113.1:80"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        let policy = Policy::parse("deny 10.0.0.0/8").unwrap();
+
+        assert!(policy.check(None, addr("[::ffff:10.0.0.1]:80")).is_err());
+        assert!(policy.check(None, addr("[fd00::1]:80")).is_ok());
+    }
+
+    #[test]
+    fn unresolved() {
+        let policy = Policy::parse(POLICY).unwrap();
+
+        // CIDR rules cannot match a name alone
+        assert!(policy.check_unresolved("intranet", 22).is_ok());
+        assert!(policy.check_unresolved("blocked.example.org", 80).is_err());
+        assert!(policy.check_unresolved("git.corp.local", 443).is_ok());
+
+        let policy = Policy::parse("allow *.corp.local 443; deny *").unwrap();
+        assert!(policy.check_unresolved("git.corp.local", 80).is_err());
+    }
+
+    #[test]
+    fn lookup() {
+        let policy = Policy::parse(POLICY).unwrap();
+
+        assert!(policy.check_lookup(Some("git.corp.local"), None).is_ok());
+        assert!(
+            policy
+                .check_lookup(Some("blocked.example.org"), None)
+                .is_err()
+        );
+        assert!(policy.check_lookup(None, Some(ip("10.1.2.3"))).is_err());
+        // some ports are allowed before the whole network is denied
+        assert!(policy.check_lookup(None, Some(ip("192.168.1.5"))).is_ok());
+        assert!(policy.check_lookup(None, Some(ip("192.168.2.5"))).is_err());
+
+        let policy = Policy::parse("allow * 80; deny *").unwrap();
+        assert!(policy.check_lookup(Some("example.org"), None).is_ok());
+
+        let policy = Policy::parse("deny * 0-65535").unwrap();
+        assert_eq!(
+            policy.check_lookup(Some("example.org"), None),
+            Err("deny * 0-65535")
+        );
+
+        // the last port is allowed
+        let policy = Policy::parse("deny * 0-65534").unwrap();
+        assert!(policy.check_lookup(Some("example.org"), None).is_ok());
+    }
+}
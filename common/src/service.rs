@@ -1,19 +1,116 @@
-use crate::{api, clipboard, command, ftp, socks5, stage0};
+#[cfg(feature = "frontend")]
+use crate::{acl, tls};
+use crate::{api, clipboard, command, dns, ftp, limits, options, socks5, stage0, transparent, tun};
 #[cfg(feature = "backend")]
 use std::collections::hash_map;
+#[cfg(feature = "frontend")]
+use std::sync::atomic;
 use std::{
     collections, fmt,
     io::{self, Write},
-    net::{self, TcpStream},
-    sync, thread,
+    net, sync, thread, time,
+};
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+use std::{
+    ffi,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    process,
+    str::FromStr,
 };
+#[cfg(all(feature = "frontend", unix))]
+use std::{fs, os::unix, path};
 
 const CLIENT_CHUNK_BUFFER_SIZE: usize = 16;
+// data chunks sent for a stream and not read yet, about 400 KB
+const WINDOW: u16 = 256;
+// read chunks granted back at once to the other side
+const WINDOW_GRANT: u16 = WINDOW / 4;
+#[cfg(feature = "frontend")]
+const WAKE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+// for the first probe of the backend to be answered
+#[cfg(feature = "frontend")]
+const VERSION_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
+/// Data chunks a stream can still send, granted by the other side as its
+/// client reads them.
+struct Window {
+    // credits, and whether the stream is over
+    state: sync::Mutex<(u32, bool)>,
+    changed: sync::Condvar,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            state: sync::Mutex::new((u32::from(WINDOW), false)),
+            changed: sync::Condvar::new(),
+        }
+    }
+
+    fn grant(&self, count: u16) {
+        self.state.lock().unwrap().0 += u32::from(count);
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+
+    /// Waits for a credit to send a data chunk. Returns `false` if the
+    /// stream is over.
+    fn take(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let mut state = self
+            .changed
+            .wait_while(state, |(credits, closed)| *credits == 0 && !*closed)
+            .unwrap();
+        if state.1 {
+            return false;
+        }
+        state.0 -= 1;
+        true
+    }
+}
+
+/// Whether a peer of `version` grants credits for the data it reads.
+const fn windowed(version: u8) -> bool {
+    2 <= version
+}
+
+struct Client {
+    #[cfg(feature = "frontend")]
+    service: &'static Service,
+    to_stream: crossbeam_channel::Sender<api::Chunk>,
+    // of the stream, if the other side grants credits
+    window: Option<sync::Arc<Window>>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(window) = &self.window {
+            window.close();
+        }
+    }
+}
 
 pub struct Channel {
-    clients:
-        sync::RwLock<collections::HashMap<api::ClientId, crossbeam_channel::Sender<api::Chunk>>>,
+    clients: sync::RwLock<collections::HashMap<api::ClientId, Client>>,
     to_rdp: crossbeam_channel::Sender<api::ChunkControl>,
+    // per service, set by the servers of the frontend
+    #[cfg(feature = "frontend")]
+    limits: sync::RwLock<collections::HashMap<&'static str, limits::Limits>>,
+    #[cfg(feature = "frontend")]
+    options: sync::RwLock<collections::HashMap<&'static str, options::Raw>>,
+    // waiting for the backend to end them
+    probes: sync::Mutex<collections::HashMap<api::ClientId, crossbeam_channel::Sender<u8>>>,
+    // announced in the answer to the last probe, unknown until then
+    #[cfg(feature = "frontend")]
+    backend_version: sync::Mutex<Option<u8>>,
+    #[cfg(feature = "frontend")]
+    backend_version_known: sync::Condvar,
+    #[cfg(feature = "backend")]
+    connections: sync::Mutex<collections::HashMap<&'static str, limits::Counter>>,
 }
 
 impl Channel {
@@ -21,17 +118,34 @@ impl Channel {
         Self {
             clients: sync::RwLock::new(collections::HashMap::new()),
             to_rdp,
+            #[cfg(feature = "frontend")]
+            limits: sync::RwLock::new(collections::HashMap::new()),
+            #[cfg(feature = "frontend")]
+            options: sync::RwLock::new(collections::HashMap::new()),
+            probes: sync::Mutex::new(collections::HashMap::new()),
+            #[cfg(feature = "frontend")]
+            backend_version: sync::Mutex::new(None),
+            #[cfg(feature = "frontend")]
+            backend_version_known: sync::Condvar::new(),
+            #[cfg(feature = "backend")]
+            connections: sync::Mutex::new(collections::HashMap::new()),
         }
     }
 
+    #[allow(clippy::missing_panics_doc)]
     pub fn shutdown(&self) {
+        // the next backend may be another one
+        #[cfg(feature = "frontend")]
+        {
+            *self.backend_version.lock().unwrap() = None;
+        }
         match self.clients.write() {
             sync::LockResult::Err(e) => {
                 crate::error!("failed to acquire lock to shutdown channel: {e}");
             }
             sync::LockResult::Ok(mut clients) => {
                 clients.iter().for_each(|(client_id, client)| {
-                    let _ = client.send(api::Chunk::end(*client_id));
+                    let _ = client.to_stream.try_send(api::Chunk::end(*client_id));
                 });
                 clients.clear();
             }
@@ -42,23 +156,166 @@ impl Channel {
         let _ = self.clients.write().unwrap().remove(&client_id);
     }
 
+    /// Open streams, with the name of their service.
+    #[allow(clippy::missing_panics_doc)]
+    #[cfg(feature = "frontend")]
+    pub fn streams(&self) -> Vec<(api::ClientId, &'static str)> {
+        self.clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(client_id, client)| (*client_id, client.service.name))
+            .collect()
+    }
+
+    /// Ends the stream of `client_id` on both sides. Returns `false` if
+    /// there is no such stream.
+    #[allow(clippy::missing_panics_doc)]
+    #[cfg(feature = "frontend")]
+    pub fn close(&self, client_id: api::ClientId) -> bool {
+        let Some(client) = self.clients.write().unwrap().remove(&client_id) else {
+            return false;
+        };
+
+        crate::info!("closing {} stream {client_id:x}", client.service);
+
+        // if the stream is not reading, dropping the sender ends it
+        let _ = client.to_stream.try_send(api::Chunk::end(client_id));
+        let _ = self.send(api::Chunk::end(client_id));
+
+        true
+    }
+
+    /// Ends all the streams of `service`.
+    #[cfg(feature = "frontend")]
+    pub fn close_service(&self, service: &Service) {
+        for (client_id, name) in self.streams() {
+            if name == service.name {
+                self.close(client_id);
+            }
+        }
+    }
+
     fn send(&self, chunk: api::Chunk) -> Result<(), api::Error> {
         self.to_rdp.send(api::ChunkControl::Chunk(chunk))?;
         Ok(())
     }
 
+    /// Checks that a backend answers on the other side of the channel,
+    /// recording the version it announces. Any backend ends a stream it
+    /// does not know of when it gets data for it.
+    #[allow(clippy::missing_panics_doc)]
+    #[cfg(feature = "frontend")]
+    pub fn probe(&self, timeout: time::Duration) -> bool {
+        let client_id = api::new_client_id();
+        let (answer_send, answer_recv) = crossbeam_channel::bounded(1);
+        self.probes.lock().unwrap().insert(client_id, answer_send);
+
+        let answer = api::Chunk::data(client_id, &[])
+            .map_err(api::Error::from)
+            .and_then(|chunk| self.send(chunk))
+            .ok()
+            .and_then(|()| answer_recv.recv_timeout(timeout).ok());
+
+        self.probes.lock().unwrap().remove(&client_id);
+        match answer {
+            None => false,
+            Some(version) => {
+                *self.backend_version.lock().unwrap() = Some(version);
+                self.backend_version_known.notify_all();
+                true
+            }
+        }
+    }
+
+    #[cfg(feature = "frontend")]
+    fn set_limits(&self, service: &'static Service, limits: limits::Limits) {
+        self.limits.write().unwrap().insert(service.name, limits);
+    }
+
+    fn answer_probe(&self, client_id: api::ClientId, version: u8) -> bool {
+        self.probes
+            .lock()
+            .unwrap()
+            .remove(&client_id)
+            .is_some_and(|answer| answer.send(version).is_ok())
+    }
+
+    #[cfg(feature = "frontend")]
+    fn set_options(&self, service: &'static Service, options: &options::Options) {
+        self.options
+            .write()
+            .unwrap()
+            .insert(service.name, options.forwarded());
+    }
+
+    /// Whether a probe was answered since the channel connected.
+    #[allow(clippy::missing_panics_doc)]
+    #[cfg(feature = "frontend")]
+    pub fn knows_backend(&self) -> bool {
+        self.backend_version.lock().unwrap().is_some()
+    }
+
+    /// Version announced by the backend, waiting for the first probe
+    /// after a connection to be answered. Backends which do not answer
+    /// in time are taken for the oldest ones.
+    #[cfg(feature = "frontend")]
+    fn backend_version(&self) -> u8 {
+        let version = self.backend_version.lock().unwrap();
+        let (version, _) = self
+            .backend_version_known
+            .wait_timeout_while(version, VERSION_TIMEOUT, |version| version.is_none())
+            .unwrap();
+        version.unwrap_or(0)
+    }
+
+    /// Name of `service` followed by the options forwarded to the
+    /// backend of `version`, which must understand them.
+    #[cfg(feature = "frontend")]
+    fn header(&self, service: &Service, version: u8) -> Result<Vec<u8>, io::Error> {
+        let options = self.options.read().unwrap();
+        let raw = options.get(service.name).cloned().unwrap_or_default();
+        if !raw.is_empty() && !extended_start(version) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the backend does not support the options of {service}"),
+            ));
+        }
+        Ok(options::header(service, version, &raw))
+    }
+
     #[cfg(feature = "frontend")]
-    pub(crate) fn connect<'a>(&'a self, service: &'a Service) -> Result<RdpStream<'a>, io::Error> {
+    pub(crate) fn connect<'a>(
+        &'a self,
+        service: &'static Service,
+    ) -> Result<RdpStream<'a>, io::Error> {
         let client_id = api::new_client_id();
+        let limits = self
+            .limits
+            .read()
+            .unwrap()
+            .get(service.name)
+            .copied()
+            .unwrap_or_default();
 
-        let (from_rdp_send, from_rdp_recv) = crossbeam_channel::bounded(CLIENT_CHUNK_BUFFER_SIZE);
+        let version = self.backend_version();
+        let window = windowed(version).then(|| sync::Arc::new(Window::new()));
+        let (from_rdp_send, from_rdp_recv) = crossbeam_channel::unbounded();
 
         self.clients
             .write()
             .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?
-            .insert(client_id, from_rdp_send);
-
-        let stream = RdpStream::new(self, service, client_id, from_rdp_recv);
+            .insert(
+                client_id,
+                Client {
+                    service,
+                    to_stream: from_rdp_send,
+                    window: window.clone(),
+                },
+            );
+
+        let stream = RdpStream::new(self, service, client_id, from_rdp_recv, limits)
+            .with_version(version, window);
         match stream.connect() {
             Err(e) => {
                 self.forget(client_id);
@@ -75,6 +332,13 @@ impl Channel {
         payload: &[u8],
         scope: &'a thread::Scope<'a, '_>,
     ) -> Result<(), api::Error> {
+        // the first data of the stream may follow the header
+        let (header, data) = match payload.iter().position(|b| *b == 0x00) {
+            None => (payload, &payload[payload.len()..]),
+            Some(i) => (&payload[..i], &payload[i + 1..]),
+        };
+        let (name, version, raw) = options::parse_header(header);
+
         match self
             .clients
             .write()
@@ -84,7 +348,7 @@ impl Channel {
             hash_map::Entry::Occupied(_) => {
                 crate::error!("discarding start for already existing client {client_id:x}");
             }
-            hash_map::Entry::Vacant(ve) => match lookup_bytes(payload) {
+            hash_map::Entry::Vacant(ve) => match lookup_bytes(name) {
                 Err(service) => {
                     crate::error!("new client for unknown service {service}!");
                     self.send(api::Chunk::end(client_id))?;
@@ -92,11 +356,44 @@ impl Channel {
                 Ok(service) => {
                     crate::debug!("new {service} client {client_id:x}");
 
-                    let (from_rdp_send, from_rdp_recv) =
-                        crossbeam_channel::bounded(CLIENT_CHUNK_BUFFER_SIZE);
-                    ve.insert(from_rdp_send);
-
-                    let stream = RdpStream::new(self, service, client_id, from_rdp_recv);
+                    let options = match options::Options::parse(service, &raw) {
+                        Ok(options) => options,
+                        Err(e) => {
+                            crate::error!("rejected {service} client {client_id:x}: {e}");
+                            self.send(api::Chunk::end(client_id))?;
+                            return Ok(());
+                        }
+                    };
+
+                    let limits = limits::backend();
+                    let counter = self
+                        .connections
+                        .lock()
+                        .unwrap()
+                        .entry(service.name)
+                        .or_default()
+                        .clone();
+                    let Some(slot) = counter.acquire(limits.max_connections) else {
+                        crate::warn!("too many {service} clients, rejected {client_id:x}");
+                        self.send(api::Chunk::end(client_id))?;
+                        return Ok(());
+                    };
+
+                    let window = windowed(version).then(|| sync::Arc::new(Window::new()));
+                    let (from_rdp_send, from_rdp_recv) = crossbeam_channel::unbounded();
+                    if !data.is_empty() {
+                        from_rdp_send.send(api::Chunk::data(client_id, data)?)?;
+                    }
+                    ve.insert(Client {
+                        #[cfg(feature = "frontend")]
+                        service,
+                        to_stream: from_rdp_send,
+                        window: window.clone(),
+                    });
+
+                    let stream = RdpStream::new(self, service, client_id, from_rdp_recv, limits)
+                        .with_version(version, window)
+                        .with_options(options);
                     stream.accept()?;
 
                     thread::Builder::new()
@@ -105,6 +402,7 @@ impl Channel {
                             if let Err(e) = (service.backend.handler)(stream) {
                                 crate::debug!("error: {e}");
                             }
+                            drop(slot);
                         })
                         .unwrap();
                 }
@@ -114,6 +412,49 @@ impl Channel {
         Ok(())
     }
 
+    /// Queues data for the stream of `client_id` without waiting, so
+    /// that a stream which does not read cannot hold the other ones. The
+    /// other side sends no more than the window of the stream ahead of its
+    /// reader, older ones excepted.
+    fn route_data(&self, client_id: api::ClientId, chunk: api::Chunk) -> Result<(), api::Error> {
+        let to_stream = self
+            .clients
+            .read()
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?
+            .get(&client_id)
+            .map(|client| client.to_stream.clone());
+
+        let Some(to_stream) = to_stream else {
+            // probes carry no data
+            if !chunk.payload().is_empty() {
+                crate::debug!("discarding chunk for unknown client {client_id:x}");
+            }
+            let _ = self.send(api::Chunk::end_unknown(client_id));
+            return Ok(());
+        };
+
+        if to_stream.try_send(chunk).is_err() {
+            crate::warn!("error sending to disconnected client {client_id:x}");
+        }
+
+        Ok(())
+    }
+
+    /// Lets the stream of `client_id` send `count` more data chunks.
+    fn route_credit(&self, client_id: api::ClientId, count: u16) -> Result<(), api::Error> {
+        // the stream may be over already
+        if let Some(window) = self
+            .clients
+            .read()
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?
+            .get(&client_id)
+            .and_then(|client| client.window.as_ref())
+        {
+            window.grant(count);
+        }
+        Ok(())
+    }
+
     pub fn start(
         &self,
         service_kind: Kind,
@@ -148,25 +489,10 @@ impl Channel {
                                     }
                                 },
                                 api::ChunkType::Data => {
-                                    if let Some(client) = self
-                                        .clients
-                                        .read()
-                                        .map_err(|e| {
-                                            io::Error::new(io::ErrorKind::BrokenPipe, e.to_string())
-                                        })?
-                                        .get(&client_id)
-                                    {
-                                        if client.send(chunk).is_err() {
-                                            crate::warn!(
-                                                "error sending to disconnected client {client_id:x}"
-                                            );
-                                        }
-                                    } else {
-                                        crate::debug!(
-                                            "discarding chunk for unknown client {client_id:x}"
-                                        );
-                                        let _ = self.send(api::Chunk::end(client_id));
-                                    }
+                                    self.route_data(client_id, chunk)?;
+                                }
+                                api::ChunkType::Credit => {
+                                    self.route_credit(client_id, chunk.credits())?;
                                 }
                                 api::ChunkType::End => {
                                     let value = self
@@ -177,12 +503,12 @@ impl Channel {
                                         })?
                                         .remove(&client_id);
                                     if let Some(client) = value {
-                                        if client.send(chunk).is_err() {
+                                        if client.to_stream.try_send(chunk).is_err() {
                                             crate::warn!(
                                                 "error sending to disconnected client {client_id:x}"
                                             );
                                         }
-                                    } else {
+                                    } else if !self.answer_probe(client_id, chunk.version()) {
                                         crate::debug!(
                                             "discarding chunk for unknown client {client_id:x}"
                                         );
@@ -197,15 +523,31 @@ impl Channel {
     }
 }
 
+/// Whether a peer of `version` understands the options and the data of
+/// the start chunks, which older ones take for the name of the service.
+#[cfg(feature = "frontend")]
+const fn extended_start(version: u8) -> bool {
+    1 <= version
+}
+
 enum RdpStreamState {
     Ready,
+    // connected but the start chunk is not sent yet, to send it with
+    // the first data of the stream
+    #[cfg(feature = "frontend")]
+    Pending,
     Connected,
     Disconnected,
 }
 
 impl RdpStreamState {
     const fn is_connected(&self) -> bool {
-        matches!(self, Self::Connected)
+        match self {
+            #[cfg(feature = "frontend")]
+            Self::Pending => true,
+            Self::Connected => true,
+            Self::Ready | Self::Disconnected => false,
+        }
     }
 }
 
@@ -214,6 +556,13 @@ struct RdpStreamCommon<'a> {
     service: &'a Service,
     client_id: api::ClientId,
     state: RdpStreamState,
+    limits: limits::Limits,
+    started: time::Instant,
+    active: time::Instant,
+    // announced by the other side when the stream was opened
+    version: u8,
+    // credits to send data, if the other side grants them
+    window: Option<sync::Arc<Window>>,
 }
 
 impl RdpStreamCommon<'_> {
@@ -225,6 +574,11 @@ impl RdpStreamCommon<'_> {
                 self.state = RdpStreamState::Connected;
                 Ok(())
             }
+            #[cfg(feature = "frontend")]
+            RdpStreamState::Pending => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already connected",
+            )),
             RdpStreamState::Connected => Err(io::Error::new(
                 io::ErrorKind::AlreadyExists,
                 "already connected",
@@ -239,14 +593,11 @@ impl RdpStreamCommon<'_> {
     fn connect(&mut self) -> Result<(), io::Error> {
         match &self.state {
             RdpStreamState::Ready => {
-                self.channel
-                    .send(api::Chunk::start(self.client_id, self.service)?)
-                    .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?;
                 crate::debug!("connect",);
-                self.state = RdpStreamState::Connected;
+                self.state = RdpStreamState::Pending;
                 Ok(())
             }
-            RdpStreamState::Connected => Err(io::Error::new(
+            RdpStreamState::Pending | RdpStreamState::Connected => Err(io::Error::new(
                 io::ErrorKind::AlreadyExists,
                 "already connected",
             )),
@@ -256,6 +607,42 @@ impl RdpStreamCommon<'_> {
         }
     }
 
+    /// Sends the pending start chunk, with `data` if it fits in.
+    /// Returns `true` if `data` was sent.
+    #[cfg(feature = "frontend")]
+    fn open(&mut self, data: &[u8]) -> Result<bool, io::Error> {
+        if !matches!(self.state, RdpStreamState::Pending) {
+            return Ok(false);
+        }
+        self.state = RdpStreamState::Connected;
+        let header = self.channel.header(self.service, self.version)?;
+        let chunk = if data.is_empty() || !extended_start(self.version) {
+            None
+        } else {
+            api::Chunk::start_with_data(self.client_id, &header, data)
+        };
+        let sent = chunk.is_some();
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => api::Chunk::start(self.client_id, &header)?,
+        };
+        self.channel
+            .send(chunk)
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?;
+        Ok(sent)
+    }
+
+    fn send_data(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.active = time::Instant::now();
+        #[cfg(feature = "frontend")]
+        if self.open(data)? {
+            return Ok(());
+        }
+        self.channel
+            .send(api::Chunk::data(self.client_id, data)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))
+    }
+
     fn disconnected(&mut self) {
         crate::debug!("disconnected",);
         self.channel.forget(self.client_id);
@@ -267,6 +654,11 @@ impl RdpStreamCommon<'_> {
             RdpStreamState::Ready => {
                 self.disconnected();
             }
+            // nothing was sent to the other side
+            #[cfg(feature = "frontend")]
+            RdpStreamState::Pending => {
+                self.disconnected();
+            }
             RdpStreamState::Connected => {
                 crate::debug!("disconnecting",);
                 let _ = self.channel.send(api::Chunk::end(self.client_id));
@@ -287,23 +679,53 @@ impl Drop for RdpStreamCommon<'_> {
 struct RdpStreamControl<'a>(sync::Arc<sync::RwLock<RdpStreamCommon<'a>>>);
 
 impl<'a> RdpStreamControl<'a> {
-    fn new(channel: &'a Channel, service: &'a Service, client_id: api::ClientId) -> Self {
+    fn new(
+        channel: &'a Channel,
+        service: &'a Service,
+        client_id: api::ClientId,
+        limits: limits::Limits,
+    ) -> Self {
+        let now = time::Instant::now();
         Self(sync::Arc::new(sync::RwLock::new(RdpStreamCommon {
             channel,
             service,
             client_id,
             state: RdpStreamState::Ready,
+            limits,
+            started: now,
+            active: now,
+            version: 0,
+            window: None,
         })))
     }
 
+    fn set_version(&self, version: u8, window: Option<sync::Arc<Window>>) {
+        let mut common = self.0.write().unwrap();
+        common.version = version;
+        common.window = window;
+    }
+
     fn client_id(&self) -> api::ClientId {
         self.0.read().unwrap().client_id
     }
 
+    fn version(&self) -> u8 {
+        self.0.read().unwrap().version
+    }
+
     fn is_connected(&self) -> bool {
         self.0.read().unwrap().state.is_connected()
     }
 
+    fn deadline(&self) -> Option<time::Instant> {
+        let common = self.0.read().unwrap();
+        common.limits.deadline(common.started, common.active)
+    }
+
+    fn received_data(&self) {
+        self.0.write().unwrap().active = time::Instant::now();
+    }
+
     #[cfg(feature = "backend")]
     fn accept(&self) -> Result<(), io::Error> {
         self.0.write().unwrap().accept()
@@ -314,13 +736,30 @@ impl<'a> RdpStreamControl<'a> {
         self.0.write().unwrap().connect()
     }
 
-    fn send(&self, chunk: api::Chunk) -> Result<(), io::Error> {
-        self.0
-            .write()
-            .unwrap()
-            .channel
-            .send(chunk)
-            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))
+    #[cfg(feature = "frontend")]
+    fn open(&self) -> Result<(), io::Error> {
+        self.0.write().unwrap().open(&[]).map(|_| ())
+    }
+
+    /// Sends `data` once the other side grants it, without holding the
+    /// stream meanwhile for its reader to make room.
+    fn send_data(&self, data: &[u8]) -> Result<(), io::Error> {
+        let window = self.0.read().unwrap().window.clone();
+        if window.is_some_and(|window| !window.take()) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "ended"));
+        }
+        self.0.write().unwrap().send_data(data)
+    }
+
+    /// Lets the other side send `count` more data chunks, if it waits
+    /// for it.
+    fn grant(&self, count: u16) {
+        let common = self.0.read().unwrap();
+        if common.window.is_some() && common.state.is_connected() {
+            let _ = common
+                .channel
+                .send(api::Chunk::credit(common.client_id, count));
+        }
     }
 
     fn disconnected(&self) {
@@ -336,6 +775,9 @@ pub struct RdpStream<'a> {
     reader: RdpReader<'a>,
     writer: RdpWriter<'a>,
     control: RdpStreamControl<'a>,
+    // sent by the frontend when opening the stream
+    #[cfg(feature = "backend")]
+    options: options::Options,
 }
 
 impl<'a> RdpStream<'a> {
@@ -344,8 +786,9 @@ impl<'a> RdpStream<'a> {
         service: &'a Service,
         client_id: api::ClientId,
         from_rdp: crossbeam_channel::Receiver<api::Chunk>,
+        limits: limits::Limits,
     ) -> Self {
-        let control = RdpStreamControl::new(channel, service, client_id);
+        let control = RdpStreamControl::new(channel, service, client_id, limits);
 
         let reader = RdpReader::new(control.clone(), from_rdp);
         let writer = RdpWriter::new(control.clone());
@@ -354,13 +797,37 @@ impl<'a> RdpStream<'a> {
             reader,
             writer,
             control,
+            #[cfg(feature = "backend")]
+            options: options::Options::None,
         }
     }
 
+    fn with_version(self, version: u8, window: Option<sync::Arc<Window>>) -> Self {
+        self.control.set_version(version, window);
+        self
+    }
+
+    #[cfg(feature = "backend")]
+    fn with_options(mut self, options: options::Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    #[cfg(feature = "backend")]
+    pub(crate) const fn options(&self) -> &options::Options {
+        &self.options
+    }
+
     pub(crate) fn client_id(&self) -> api::ClientId {
         self.control.client_id()
     }
 
+    /// Version announced by the other side, on which the protocol of the
+    /// service may depend.
+    pub(crate) fn version(&self) -> u8 {
+        self.control.version()
+    }
+
     #[cfg(feature = "backend")]
     fn accept(&self) -> Result<(), io::Error> {
         self.control.accept()
@@ -402,6 +869,8 @@ pub(crate) struct RdpReader<'a> {
     control: RdpStreamControl<'a>,
     from_rdp: crossbeam_channel::Receiver<api::Chunk>,
     last: Option<(api::Chunk, usize)>,
+    // data chunks read and not granted back yet
+    read: u16,
 }
 
 impl<'a> RdpReader<'a> {
@@ -413,12 +882,42 @@ impl<'a> RdpReader<'a> {
             control,
             from_rdp,
             last: None,
+            read: 0,
         }
     }
 
     pub(crate) fn disconnect(&self) {
         self.control.disconnect();
     }
+
+    /// Waits for the next chunk until the idle timeout or the maximum
+    /// lifetime of the stream is reached.
+    fn receive(&self) -> Result<api::Chunk, io::Error> {
+        loop {
+            let Some(deadline) = self.control.deadline() else {
+                return self
+                    .from_rdp
+                    .recv()
+                    .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e));
+            };
+            match self.from_rdp.recv_deadline(deadline) {
+                Ok(chunk) => return Ok(chunk),
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "disconnected"));
+                }
+                // data may have been sent in the meantime
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    if self
+                        .control
+                        .deadline()
+                        .is_some_and(|deadline| deadline <= time::Instant::now())
+                    {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "stream timed out"));
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl io::Read for RdpReader<'_> {
@@ -428,10 +927,12 @@ impl io::Read for RdpReader<'_> {
         }
 
         if self.last.is_none() {
-            let chunk = self
-                .from_rdp
-                .recv()
-                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+            // the other side must know the stream before answering
+            #[cfg(feature = "frontend")]
+            self.control.open()?;
+
+            let chunk = self.receive()?;
+            self.control.received_data();
             let chunk_type = chunk.chunk_type();
             let payload = chunk.payload();
             let payload_len = payload.len();
@@ -439,6 +940,11 @@ impl io::Read for RdpReader<'_> {
                 self.control.disconnected();
                 return Ok(0);
             }
+            self.read += 1;
+            if WINDOW_GRANT <= self.read {
+                self.control.grant(self.read);
+                self.read = 0;
+            }
             if payload_len == 0 {
                 return Ok(0);
             }
@@ -534,11 +1040,10 @@ impl io::Write for RdpWriter<'_> {
 
     fn flush(&mut self) -> Result<(), io::Error> {
         if 0 < self.buffer_len {
-            let chunk =
-                api::Chunk::data(self.control.client_id(), &self.buffer[0..self.buffer_len])?;
+            let res = self.control.send_data(&self.buffer[0..self.buffer_len]);
             self.buffer_len = 0;
 
-            if let Err(e) = self.control.send(chunk) {
+            if let Err(e) = res {
                 self.control.disconnected();
                 return Err(io::Error::new(io::ErrorKind::BrokenPipe, e));
             }
@@ -547,29 +1052,50 @@ impl io::Write for RdpWriter<'_> {
     }
 }
 
-pub(crate) fn stream_copy<R, W>(from: &mut R, to: &mut W) -> Result<(), io::Error>
+/// Copies `from` to `to` until the end of `from`, returning the number
+/// of bytes copied.
+pub(crate) fn stream_copy<R, W>(from: &mut R, to: &mut W) -> Result<u64, io::Error>
 where
     R: io::Read,
     W: io::Write,
 {
     let mut buf = vec![0u8; CLIENT_CHUNK_BUFFER_SIZE * api::Chunk::max_payload_length()];
+    let mut total = 0;
 
     loop {
         let read = from.read(&mut buf)?;
         if read == 0 {
-            return Ok(());
+            return Ok(total);
         }
         to.write_all(&buf[0..read])?;
         to.flush()?;
+        total += read as u64;
         thread::yield_now();
     }
 }
 
-pub(crate) fn double_stream_copy(
+/// A stream which can be cloned to be read and written from two
+/// threads, as done by [`double_stream_copy`].
+pub(crate) trait Duplex: io::Read + io::Write + Send + Sized {
+    fn try_clone(&self) -> Result<Self, io::Error>;
+    fn shutdown(&self, how: net::Shutdown) -> Result<(), io::Error>;
+}
+
+impl Duplex for net::TcpStream {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        net::TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: net::Shutdown) -> Result<(), io::Error> {
+        net::TcpStream::shutdown(self, how)
+    }
+}
+
+pub(crate) fn double_stream_copy<S: Duplex>(
     service_kind: Kind,
     service: &Service,
     rdp_stream: RdpStream<'_>,
-    tcp_stream: TcpStream,
+    tcp_stream: S,
 ) -> Result<(), io::Error> {
     let client_id = rdp_stream.client_id();
 
@@ -617,6 +1143,77 @@ pub(crate) fn double_stream_copy(
     })
 }
 
+/// Same as [`double_stream_copy`] but data of `tcp_stream` is
+/// forwarded right away, while `accept` reads the answer of the other
+/// side to the service command. Nothing is copied back to
+/// `tcp_stream` if `accept` refuses the connection.
+#[cfg(feature = "frontend")]
+pub(crate) fn pipelined_stream_copy<S, F>(
+    service_kind: Kind,
+    service: &Service,
+    rdp_stream: RdpStream<'_>,
+    tcp_stream: S,
+    accept: F,
+) -> Result<(), io::Error>
+where
+    S: Duplex,
+    F: FnOnce(&mut RdpReader<'_>, &mut S) -> Result<bool, io::Error>,
+{
+    let client_id = rdp_stream.client_id();
+
+    let (mut rdp_stream_read, rdp_stream_write) = rdp_stream.split();
+
+    let tcp_stream2 = tcp_stream.try_clone()?;
+
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .name(format!(
+                "{service_kind} {service} {client_id:x} stream copy"
+            ))
+            .spawn_scoped(scope, move || {
+                let mut tcp_stream2 = io::BufReader::new(tcp_stream2);
+                let mut rdp_stream_write = io::BufWriter::new(rdp_stream_write);
+                if let Err(e) = stream_copy(&mut tcp_stream2, &mut rdp_stream_write) {
+                    crate::debug!("error: {e}");
+                } else {
+                    crate::debug!("stopped");
+                }
+                let _ = rdp_stream_write.flush();
+                if let Ok(mut rdp_stream_write) = rdp_stream_write.into_inner() {
+                    let _ = rdp_stream_write.disconnect();
+                }
+                let tcp_stream2 = tcp_stream2.into_inner();
+                let _ = tcp_stream2.shutdown(net::Shutdown::Both);
+            })
+            .unwrap();
+
+        let mut tcp_stream = tcp_stream;
+        let accepted = accept(&mut rdp_stream_read, &mut tcp_stream);
+        if !matches!(accepted, Ok(true)) {
+            // stops the copy of tcp_stream which data are discarded
+            let _ = tcp_stream.shutdown(net::Shutdown::Both);
+            rdp_stream_read.disconnect();
+            return accepted.map(|_| ());
+        }
+
+        let mut rdp_stream_read = io::BufReader::new(rdp_stream_read);
+        let mut tcp_stream = io::BufWriter::new(tcp_stream);
+        if let Err(e) = stream_copy(&mut rdp_stream_read, &mut tcp_stream) {
+            crate::debug!("error: {e}");
+        } else {
+            crate::debug!("stopped");
+        }
+        let _ = tcp_stream.flush();
+        if let Ok(tcp_stream) = tcp_stream.into_inner() {
+            let _ = tcp_stream.shutdown(net::Shutdown::Both);
+        }
+        let rdp_stream_read = rdp_stream_read.into_inner();
+        rdp_stream_read.disconnect();
+
+        Ok(())
+    })
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Kind {
     #[cfg(feature = "backend")]
@@ -636,57 +1233,748 @@ impl fmt::Display for Kind {
     }
 }
 
+/// A client of a TCP frontend server, in clear or over TLS, or of a
+/// server listening on a Unix domain socket.
 #[cfg(feature = "frontend")]
-pub struct TcpFrontendServer {
-    service: &'static Service,
-    server: net::TcpListener,
-    pub(crate) ip: net::IpAddr,
+pub(crate) enum ClientStream {
+    Tcp(net::TcpStream),
+    Tls(tls::Stream),
+    #[cfg(unix)]
+    Unix(unix::net::UnixStream),
 }
 
 #[cfg(feature = "frontend")]
-impl TcpFrontendServer {
-    pub fn service(&self) -> &Service {
-        self.service
+impl ClientStream {
+    pub(crate) fn accept(
+        tls: Option<&tls::Config>,
+        client: net::TcpStream,
+    ) -> Result<Self, io::Error> {
+        match tls {
+            None => Ok(Self::Tcp(client)),
+            Some(tls) => Ok(Self::Tls(tls::Stream::accept(tls, client)?)),
+        }
     }
 
-    pub fn bind(service: &'static Service, tcp: net::SocketAddr) -> Result<Self, io::Error> {
-        crate::info!("accepting {service} clients on {tcp}");
-
-        let server = net::TcpListener::bind(tcp)?;
-        let ip = server.local_addr()?.ip();
-
-        Ok(Self {
+    pub(crate) fn tcp(&self) -> Result<&net::TcpStream, io::Error> {
+        match self {
+            Self::Tcp(stream) => Ok(stream),
+            Self::Tls(stream) => Ok(stream.tcp()),
+            #[cfg(unix)]
+            Self::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "client connected on a Unix domain socket",
+            )),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> Result<Self, io::Error> {
+        match self {
+            Self::Tcp(stream) => Ok(Self::Tcp(stream.try_clone()?)),
+            Self::Tls(stream) => Ok(Self::Tls(stream.try_clone()?)),
+            #[cfg(unix)]
+            Self::Unix(stream) => Ok(Self::Unix(stream.try_clone()?)),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: net::Shutdown) -> Result<(), io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            Self::Tls(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.tcp()?.local_addr()
+    }
+
+    pub(crate) fn peer_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.tcp()?.peer_addr()
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl io::Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl io::Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl Duplex for ClientStream {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        ClientStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: net::Shutdown) -> Result<(), io::Error> {
+        ClientStream::shutdown(self, how)
+    }
+}
+
+/// Address to connect to for waking up a server bound to `addr`.
+#[cfg(feature = "frontend")]
+fn wake_addr(addr: net::SocketAddr) -> net::SocketAddr {
+    let mut addr = addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            net::SocketAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+            net::SocketAddr::V6(_) => net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
+
+/// Address on which a frontend server accepts its clients.
+#[cfg(feature = "frontend")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(net::SocketAddr),
+    #[cfg(unix)]
+    Unix(path::PathBuf),
+}
+
+#[cfg(feature = "frontend")]
+impl ListenAddr {
+    pub const fn socket_addr(&self) -> Option<net::SocketAddr> {
+        match self {
+            Self::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(feature = "frontend")]
+enum Listener {
+    Tcp(net::TcpListener),
+    #[cfg(unix)]
+    Unix(unix::net::UnixListener),
+}
+
+#[cfg(feature = "frontend")]
+impl Listener {
+    /// Returns the client and its address, if it has one.
+    fn accept(&self) -> Result<(ClientStream, Option<net::SocketAddr>), io::Error> {
+        match self {
+            Self::Tcp(listener) => {
+                let (client, client_addr) = listener.accept()?;
+                Ok((ClientStream::Tcp(client), Some(client_addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (client, _) = listener.accept()?;
+                Ok((ClientStream::Unix(client), None))
+            }
+        }
+    }
+}
+
+/// Binds a Unix domain socket at `path` accessible according to `mode`,
+/// replacing the one left behind by a previous run but nothing else.
+#[cfg(all(feature = "frontend", unix))]
+fn bind_unix(path: &path::Path, mode: u32) -> Result<unix::net::UnixListener, io::Error> {
+    use unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is not a socket", path.display()),
+            ));
+        }
+        if unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // bound in a private directory then linked to its place, so that no
+    // one can connect before its permissions are set
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let mut private = path.to_path_buf();
+    private.set_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join(name);
+
+    let listener = unix::net::UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+        // fails rather than replacing a file created in the meantime
+        fs::hard_link(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
+
+    listener
+}
+
+#[cfg(feature = "frontend")]
+pub struct TcpFrontendServer {
+    service: &'static Service,
+    // released as soon as the server is stopped
+    server: sync::Mutex<Option<Listener>>,
+    local_addr: ListenAddr,
+    stopped: atomic::AtomicBool,
+    acl: acl::Acl,
+    token: Option<String>,
+    tls: Option<tls::Config>,
+    limits: limits::Limits,
+    connections: limits::Counter,
+    options: options::Options,
+    // loopback for a Unix domain socket
+    pub(crate) ip: net::IpAddr,
+}
+
+#[cfg(feature = "frontend")]
+impl TcpFrontendServer {
+    pub fn service(&self) -> &Service {
+        self.service
+    }
+
+    fn new(service: &'static Service, server: Listener, local_addr: ListenAddr) -> Self {
+        let ip = local_addr
+            .socket_addr()
+            .map_or(net::Ipv4Addr::LOCALHOST.into(), |addr| addr.ip());
+
+        Self {
             service,
-            server,
+            server: sync::Mutex::new(Some(server)),
+            local_addr,
+            stopped: atomic::AtomicBool::new(false),
+            acl: acl::Acl::default(),
+            token: None,
+            tls: None,
+            limits: limits::Limits::default(),
+            connections: limits::Counter::default(),
+            options: options::Options::None,
             ip,
-        })
+        }
     }
 
+    pub fn bind(service: &'static Service, tcp: net::SocketAddr) -> Result<Self, io::Error> {
+        crate::info!("accepting {service} clients on {tcp}");
+
+        let server = net::TcpListener::bind(tcp)?;
+        let local_addr = server.local_addr()?;
+
+        #[cfg(target_os = "linux")]
+        if service.is_transparent() {
+            transparent::frontend::set_transparent(&server);
+        }
+
+        Ok(Self::new(
+            service,
+            Listener::Tcp(server),
+            ListenAddr::Tcp(local_addr),
+        ))
+    }
+
+    /// Accepts clients on a Unix domain socket created at `path` with
+    /// the `mode` permissions. Clients have no address, the ACL does not
+    /// apply to them.
+    #[cfg(unix)]
+    pub fn bind_unix(
+        service: &'static Service,
+        path: &path::Path,
+        mode: u32,
+    ) -> Result<Self, io::Error> {
+        if service.is_transparent() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{service} cannot listen on a Unix domain socket"),
+            ));
+        }
+
+        crate::info!("accepting {service} clients on unix:{}", path.display());
+
+        let server = bind_unix(path, mode)?;
+
+        Ok(Self::new(
+            service,
+            Listener::Unix(server),
+            ListenAddr::Unix(path.to_path_buf()),
+        ))
+    }
+
+    /// Restricts the clients accepted by the server.
+    #[must_use]
+    pub fn with_acl(mut self, acl: acl::Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Requires clients to log in with `token` first, for services
+    /// supporting it.
+    #[must_use]
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub(crate) fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Terminates TLS on the connections of the clients.
+    #[must_use]
+    pub fn with_tls(mut self, tls: Option<tls::Config>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub(crate) fn tls(&self) -> Option<&tls::Config> {
+        self.tls.as_ref()
+    }
+
+    /// Bounds the number of clients and the streams they open.
+    #[must_use]
+    pub const fn with_limits(mut self, limits: limits::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Settings of the service, checked beforehand with
+    /// [`options::Options::parse`].
+    #[must_use]
+    pub fn with_options(mut self, options: options::Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub(crate) const fn options(&self) -> &options::Options {
+        &self.options
+    }
+
+    pub const fn local_addr(&self) -> &ListenAddr {
+        &self.local_addr
+    }
+
+    /// Stops accepting clients and releases the listening socket once
+    /// done. Clients already accepted are left running.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn stop(&self) {
+        if self.stopped.swap(true, atomic::Ordering::AcqRel) {
+            return;
+        }
+
+        crate::info!(
+            "stop accepting {} clients on {}",
+            self.service,
+            self.local_addr
+        );
+
+        // wakes up the accepting thread
+        match &self.local_addr {
+            ListenAddr::Tcp(addr) => {
+                let _ = net::TcpStream::connect_timeout(&wake_addr(*addr), WAKE_TIMEOUT);
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let _ = unix::net::UnixStream::connect(path);
+            }
+        }
+        // waits for the accepting thread to let go of the socket
+        self.server.lock().unwrap().take();
+
+        #[cfg(unix)]
+        if let ListenAddr::Unix(path) = &self.local_addr {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Accepts clients until the server is stopped, then returns when
+    /// all of them are done.
     pub fn start<'a>(&'a self, channel: &'a Channel) -> Result<(), io::Error> {
+        channel.set_limits(self.service, self.limits);
+        channel.set_options(self.service, &self.options);
+
         thread::scope(|scope| {
-            loop {
-                let (client, client_addr) = self.server.accept()?;
+            let mut server = self.server.lock().unwrap();
+
+            while let Some(listener) = server.as_ref() {
+                let (client, client_addr) = listener.accept()?;
+
+                if self.stopped.load(atomic::Ordering::Acquire) {
+                    *server = None;
+                    break;
+                }
+
+                let client_name =
+                    client_addr.map_or_else(|| "unix".to_string(), |addr| addr.to_string());
+
+                if client_addr.is_some_and(|addr| !self.acl.is_allowed(addr.ip())) {
+                    crate::warn!("rejected {} client {client_name}", self.service);
+                    let _ = client.shutdown(net::Shutdown::Both);
+                    continue;
+                }
+
+                let Some(slot) = self.connections.acquire(self.limits.max_connections) else {
+                    crate::warn!("too many {} clients, rejected {client_name}", self.service);
+                    let _ = client.shutdown(net::Shutdown::Both);
+                    continue;
+                };
 
-                crate::debug!("new client {client_addr}");
+                crate::debug!("new client {client_name}");
 
                 thread::Builder::new()
-                    .name(format!("{} {} {client_addr}", Kind::Frontend, self.service))
-                    .spawn_scoped(scope, move || match self.service.tcp_frontend.as_ref() {
-                        None => {
+                    .name(format!("{} {} {client_name}", Kind::Frontend, self.service))
+                    .spawn_scoped(scope, move || {
+                        let Some(frontend) = self.service.tcp_frontend.as_ref() else {
                             crate::error!("no TCP frontend for {}", self.service);
-                        }
-                        Some(frontend) => {
-                            if let Err(e) = (frontend.handler)(&self, scope, client, channel) {
-                                crate::debug!("error: {e}");
+                            return;
+                        };
+                        let client = match client {
+                            ClientStream::Tcp(client) => {
+                                match ClientStream::accept(self.tls(), client) {
+                                    Err(e) => {
+                                        crate::warn!(
+                                            "TLS handshake with {client_name} failed: {e}"
+                                        );
+                                        return;
+                                    }
+                                    Ok(client) => client,
+                                }
                             }
+                            client => client,
+                        };
+                        if let Err(e) = (frontend.handler)(&self, scope, client, channel) {
+                            crate::debug!("error: {e}");
                         }
+                        drop(slot);
                     })
                     .unwrap();
             }
+
+            drop(server);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "frontend")]
+const UDP_MAX_DATAGRAM_SIZE: usize = 65535;
+// datagrams handled at once when no connection limit is configured
+#[cfg(feature = "frontend")]
+const UDP_DEFAULT_MAX_PENDING: usize = 64;
+
+#[cfg(feature = "frontend")]
+pub struct UdpFrontendServer {
+    service: &'static Service,
+    // released as soon as the server is stopped
+    socket: sync::RwLock<Option<net::UdpSocket>>,
+    local_addr: net::SocketAddr,
+    stopped: atomic::AtomicBool,
+    acl: acl::Acl,
+    limits: limits::Limits,
+    pending: limits::Counter,
+}
+
+#[cfg(feature = "frontend")]
+impl UdpFrontendServer {
+    pub fn service(&self) -> &Service {
+        self.service
+    }
+
+    pub fn bind(service: &'static Service, udp: net::SocketAddr) -> Result<Self, io::Error> {
+        crate::info!("accepting {service} datagrams on {udp}");
+
+        let socket = net::UdpSocket::bind(udp)?;
+        let local_addr = socket.local_addr()?;
+
+        Ok(Self {
+            service,
+            socket: sync::RwLock::new(Some(socket)),
+            local_addr,
+            stopped: atomic::AtomicBool::new(false),
+            acl: acl::Acl::default(),
+            limits: limits::Limits::default(),
+            pending: limits::Counter::default(),
+        })
+    }
+
+    /// Restricts the peers served by the server.
+    #[must_use]
+    pub fn with_acl(mut self, acl: acl::Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Bounds the number of datagrams handled at once by the maximum
+    /// number of connections.
+    #[must_use]
+    pub const fn with_limits(mut self, limits: limits::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub(crate) fn send_to(&self, data: &[u8], peer: net::SocketAddr) -> Result<(), io::Error> {
+        self.socket
+            .read()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "server stopped"))?
+            .send_to(data, peer)?;
+        Ok(())
+    }
+
+    pub const fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops receiving datagrams and releases the socket.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn stop(&self) {
+        if self.stopped.swap(true, atomic::Ordering::AcqRel) {
+            return;
+        }
+
+        crate::info!(
+            "stop accepting {} datagrams on {}",
+            self.service,
+            self.local_addr
+        );
+
+        // wakes up the receiving thread
+        let wake = if self.local_addr.is_ipv4() {
+            net::UdpSocket::bind((net::Ipv4Addr::LOCALHOST, 0))
+        } else {
+            net::UdpSocket::bind((net::Ipv6Addr::LOCALHOST, 0))
+        };
+        if let Ok(wake) = wake {
+            let _ = wake.send_to(&[], wake_addr(self.local_addr));
+        }
+        // waits for the receiving thread to let go of the socket
+        self.socket.write().unwrap().take();
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    pub fn start<'a>(&'a self, channel: &'a Channel) -> Result<(), io::Error> {
+        thread::scope(|scope| {
+            let mut buf = vec![0u8; UDP_MAX_DATAGRAM_SIZE];
+
+            loop {
+                let guard = self.socket.read().unwrap();
+                let Some(socket) = guard.as_ref() else {
+                    return Ok(());
+                };
+                let (read, peer) = socket.recv_from(&mut buf)?;
+
+                if self.stopped.load(atomic::Ordering::Acquire) {
+                    return Ok(());
+                }
+
+                if !self.acl.is_allowed(peer.ip()) {
+                    crate::warn!("rejected {} datagram from {peer}", self.service);
+                    continue;
+                }
+
+                let max_pending = self
+                    .limits
+                    .max_connections
+                    .unwrap_or(UDP_DEFAULT_MAX_PENDING);
+                let Some(slot) = self.pending.acquire(Some(max_pending)) else {
+                    crate::debug!(
+                        "too many {} datagrams, dropped one from {peer}",
+                        self.service
+                    );
+                    continue;
+                };
+
+                let data = buf[0..read].to_vec();
+
+                crate::trace!("{read} bytes datagram from {peer}");
+
+                // the slot is released with the closure if spawning fails
+                let spawned = thread::Builder::new()
+                    .name(format!("{} {} {peer}", Kind::Frontend, self.service))
+                    .spawn_scoped(scope, move || {
+                        match self.service.udp_frontend.as_ref() {
+                            None => {
+                                crate::error!("no UDP frontend for {}", self.service);
+                            }
+                            Some(frontend) => {
+                                if let Err(e) =
+                                    (frontend.handler)(self, scope, (data, peer), channel)
+                                {
+                                    crate::debug!("error: {e}");
+                                }
+                            }
+                        }
+                        drop(slot);
+                    });
+                if let Err(e) = spawned {
+                    crate::error!("dropped {} datagram from {peer}: {e}", self.service);
+                }
+            }
         })
     }
 }
 
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+const TUN_DEVICE: &str = "/dev/net/tun";
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+const TUN_NAME: &str = "soxy%d";
+
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+pub struct TunFrontendServer {
+    service: &'static Service,
+    device: fs::File,
+    stopped: atomic::AtomicBool,
+}
+
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+impl TunFrontendServer {
+    pub fn service(&self) -> &Service {
+        self.service
+    }
+
+    /// Creates a TUN interface and routes the given `routes`, written
+    /// as `address/prefix`, through it.
+    pub fn bind(service: &'static Service, routes: &[String]) -> Result<Self, io::Error> {
+        for route in routes {
+            let valid = route.split_once('/').is_some_and(|(ip, prefix)| {
+                match (net::IpAddr::from_str(ip), u8::from_str(prefix)) {
+                    (Ok(net::IpAddr::V4(_)), Ok(prefix)) => prefix <= 32,
+                    (Ok(net::IpAddr::V6(_)), Ok(prefix)) => prefix <= 128,
+                    _ => false,
+                }
+            });
+            if !valid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid route {route:?}"),
+                ));
+            }
+        }
+
+        let device = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(TUN_DEVICE)?;
+
+        let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(TUN_NAME.bytes()) {
+            *dst = libc::c_char::from_ne_bytes([src]);
+        }
+        ifr.ifr_ifru.ifru_flags = libc::c_short::try_from(libc::IFF_TUN | libc::IFF_NO_PI)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+        let ret = unsafe { libc::ioctl(device.as_raw_fd(), libc::TUNSETIFF, &raw mut ifr) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let name = unsafe { ffi::CStr::from_ptr(ifr.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .to_string();
+
+        crate::info!("accepting {service} packets on {name}");
+
+        ip_command(&["link", "set", "dev", &name, "up"]);
+        for route in routes {
+            crate::info!("routing {route} through {name}");
+            ip_command(&["route", "add", route, "dev", &name]);
+        }
+
+        Ok(Self {
+            service,
+            device,
+            stopped: atomic::AtomicBool::new(false),
+        })
+    }
+
+    /// Stops reading packets, the interface is removed once the
+    /// connections relayed through it are done.
+    pub fn stop(&self) {
+        if !self.stopped.swap(true, atomic::Ordering::AcqRel) {
+            crate::info!("stop accepting {} packets", self.service);
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(atomic::Ordering::Acquire)
+    }
+
+    pub fn start<'a>(&'a self, channel: &'a Channel) -> Result<(), io::Error> {
+        let device = self.device.try_clone()?;
+
+        thread::scope(|scope| match self.service.tun_frontend.as_ref() {
+            None => {
+                crate::error!("no TUN frontend for {}", self.service);
+                Ok(())
+            }
+            Some(frontend) => (frontend.handler)(self, scope, device, channel),
+        })
+    }
+}
+
+/// Network configuration is left to the `ip` command, failures are
+/// only reported so that it can be done by hand.
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+fn ip_command(args: &[&str]) {
+    let command = format!("ip {}", args.join(" "));
+    match process::Command::new("ip").args(args).status() {
+        Err(e) => {
+            crate::warn!("failed to run {command:?}: {e}");
+        }
+        Ok(status) if !status.success() => {
+            crate::warn!("{command:?} failed: {status}");
+        }
+        Ok(_) => (),
+    }
+}
+
 #[cfg(feature = "frontend")]
 type FrontendHandler<S, C> = for<'a> fn(
     server: &S,
@@ -696,11 +1984,18 @@ type FrontendHandler<S, C> = for<'a> fn(
 ) -> Result<(), io::Error>;
 
 #[cfg(feature = "frontend")]
-type TcpFrontendHandler = FrontendHandler<TcpFrontendServer, net::TcpStream>;
+type TcpFrontendHandler = FrontendHandler<TcpFrontendServer, ClientStream>;
 
 #[cfg(feature = "frontend")]
 pub struct TcpFrontend {
     pub(crate) default_port: u16,
+    /// Whether the default configuration enables the service
+    pub(crate) enabled_by_default: bool,
+    /// Clients are connections redirected to the listener, which then
+    /// accepts `TPROXY` ones and cannot be a Unix domain socket
+    pub(crate) transparent: bool,
+    /// Clients can be required to log in with a token first
+    pub(crate) token: bool,
     pub(crate) handler: TcpFrontendHandler,
 }
 
@@ -709,6 +2004,34 @@ impl TcpFrontend {
     pub const fn default_port(&self) -> u16 {
         self.default_port
     }
+
+    pub const fn enabled_by_default(&self) -> bool {
+        self.enabled_by_default
+    }
+
+    pub const fn supports_token(&self) -> bool {
+        self.token
+    }
+}
+
+#[cfg(feature = "frontend")]
+type UdpFrontendHandler = FrontendHandler<UdpFrontendServer, (Vec<u8>, net::SocketAddr)>;
+
+/// A UDP frontend listens on the same address and port as the TCP
+/// frontend of the service.
+#[cfg(feature = "frontend")]
+pub struct UdpFrontend {
+    pub(crate) handler: UdpFrontendHandler,
+}
+
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+type TunFrontendHandler = FrontendHandler<TunFrontendServer, fs::File>;
+
+/// A TUN frontend receives the IP packets routed through the
+/// interface of its server.
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+pub struct TunFrontend {
+    pub(crate) handler: TunFrontendHandler,
 }
 
 #[cfg(feature = "backend")]
@@ -723,6 +2046,10 @@ pub struct Service {
     pub(crate) name: &'static str,
     #[cfg(feature = "frontend")]
     pub(crate) tcp_frontend: Option<TcpFrontend>,
+    #[cfg(feature = "frontend")]
+    pub(crate) udp_frontend: Option<UdpFrontend>,
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    pub(crate) tun_frontend: Option<TunFrontend>,
     #[cfg(feature = "backend")]
     pub(crate) backend: Backend,
 }
@@ -736,6 +2063,23 @@ impl Service {
     pub fn tcp_frontend(&self) -> Option<&TcpFrontend> {
         self.tcp_frontend.as_ref()
     }
+
+    #[cfg(all(feature = "frontend", unix))]
+    fn is_transparent(&self) -> bool {
+        self.tcp_frontend
+            .as_ref()
+            .is_some_and(|frontend| frontend.transparent)
+    }
+
+    #[cfg(feature = "frontend")]
+    pub fn udp_frontend(&self) -> Option<&UdpFrontend> {
+        self.udp_frontend.as_ref()
+    }
+
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    pub fn tun_frontend(&self) -> Option<&TunFrontend> {
+        self.tun_frontend.as_ref()
+    }
 }
 
 impl fmt::Display for Service {
@@ -754,10 +2098,13 @@ pub fn lookup(name: &str) -> Option<&'static Service> {
     SERVICES.iter().find(|s| s.name == name).map(|s| *s)
 }
 
-pub const SERVICES: [&Service; 5] = [
+pub const SERVICES: [&Service; 8] = [
     &clipboard::SERVICE,
     &command::SERVICE,
+    &dns::SERVICE,
     &ftp::SERVICE,
     &socks5::SERVICE,
     &stage0::SERVICE,
+    &transparent::SERVICE,
+    &tun::SERVICE,
 ];
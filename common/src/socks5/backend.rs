@@ -1,12 +1,18 @@
 use super::protocol;
-use crate::{service, util};
+use crate::{audit, connector, dns, policy, service, upstream, util};
 use std::{
     io::{self, Write},
-    net,
+    net::{self, ToSocketAddrs},
+    str::FromStr,
+    sync::atomic::{self, AtomicBool},
+    thread, time,
 };
 
 const SERVICE_KIND: service::Kind = service::Kind::Backend;
 
+const UDP_RELAY_READ_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+const UDP_MAX_DATAGRAM_SIZE: usize = 65535;
+
 fn encode_addr(addr: &net::SocketAddr) -> Result<Vec<u8>, io::Error> {
     let mut data = Vec::with_capacity(192);
 
@@ -25,25 +31,82 @@ fn encode_addr(addr: &net::SocketAddr) -> Result<Vec<u8>, io::Error> {
     Ok(data)
 }
 
+fn encode_name(name: &str) -> Result<Vec<u8>, io::Error> {
+    let len = u8::try_from(name.len())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let mut data = Vec::with_capacity(4 + name.len());
+    data.write_all(&[0x03, len])?;
+    data.write_all(name.as_bytes())?;
+    data.write_all(&[0x00; 2])?;
+
+    Ok(data)
+}
+
+fn send_connect_error(
+    stream: &mut service::RdpStream<'_>,
+    to_tcp: &str,
+    e: &io::Error,
+) -> Result<(), io::Error> {
+    crate::error!("failed to connect to {to_tcp:#?}: {e}");
+    if upstream::is_ttl_expired(e) {
+        return protocol::Response::TtlExpired.send(stream);
+    }
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::HostUnreachable => {
+            protocol::Response::HostUnreachable.send(stream)
+        }
+        io::ErrorKind::NetworkUnreachable => protocol::Response::NetworkUnreachable.send(stream),
+        io::ErrorKind::ConnectionRefused => protocol::Response::ConnectionRefused.send(stream),
+        io::ErrorKind::PermissionDenied => protocol::Response::ConnectionNotAllowed.send(stream),
+        _ => protocol::Response::GeneralFailure.send(stream),
+    }
+}
+
+fn split_host_port(to_tcp: &str) -> (&str, u16) {
+    let (host, port) = to_tcp.rsplit_once(':').unwrap_or((to_tcp, "0"));
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (host, port.parse().unwrap_or(0))
+}
+
+fn resolve(to_tcp: &str) -> Result<(Option<&str>, Vec<net::SocketAddr>), io::Error> {
+    let (host, _) = split_host_port(to_tcp);
+    let name = if net::IpAddr::from_str(host).is_ok() {
+        None
+    } else {
+        Some(host)
+    };
+    let addrs = to_tcp.to_socket_addrs()?.collect();
+    Ok((name, addrs))
+}
+
 fn command_connect(mut stream: service::RdpStream<'_>, to_tcp: &str) -> Result<(), io::Error> {
     crate::info!("connecting to {to_tcp:#?}");
 
-    match net::TcpStream::connect(to_tcp) {
-        Err(e) => {
-            crate::error!("failed to connect to {to_tcp:#?}: {e}");
-            match e.kind() {
-                io::ErrorKind::ConnectionAborted | io::ErrorKind::TimedOut => {
-                    protocol::Response::HostUnreachable.send(&mut stream)
-                }
-                io::ErrorKind::ConnectionRefused => {
-                    protocol::Response::ConnectionRefused.send(&mut stream)
-                }
-                _ => {
-                    crate::error!("failed to connect to {to_tcp:#?}: {e}");
-                    protocol::Response::NetworkUnreachable.send(&mut stream)
-                }
-            }
-        }
+    let (host, port) = split_host_port(to_tcp);
+
+    if let Some(proxy) = upstream::proxy_for(host) {
+        return connect_upstream(stream, to_tcp, host, port, proxy);
+    }
+
+    let (name, addrs) = match resolve(to_tcp) {
+        Err(e) => return send_connect_error(&mut stream, to_tcp, &e),
+        Ok(resolved) => resolved,
+    };
+
+    let allowed = addrs
+        .iter()
+        .filter(|addr| policy::check(name, **addr).is_ok())
+        .copied()
+        .collect::<Vec<_>>();
+
+    if allowed.is_empty() && !addrs.is_empty() {
+        crate::error!("connection to {to_tcp:#?} not allowed");
+        return protocol::Response::ConnectionNotAllowed.send(&mut stream);
+    }
+
+    match connector::connect(&allowed) {
+        Err(e) => send_connect_error(&mut stream, to_tcp, &e),
         Ok(server) => {
             crate::debug!("connected to {to_tcp:#?}");
 
@@ -57,6 +120,44 @@ fn command_connect(mut stream: service::RdpStream<'_>, to_tcp: &str) -> Result<(
     }
 }
 
+/// Connects through the upstream proxy, which resolves the name when
+/// the remote host cannot.
+fn connect_upstream(
+    mut stream: service::RdpStream<'_>,
+    to_tcp: &str,
+    host: &str,
+    port: u16,
+    proxy: &upstream::Proxy,
+) -> Result<(), io::Error> {
+    // the proxy resolves the name again and may pick any of the
+    // addresses
+    let allowed = match resolve(to_tcp) {
+        Ok((name, addrs)) if !addrs.is_empty() => {
+            addrs.iter().all(|addr| policy::check(name, *addr).is_ok())
+        }
+        _ => policy::check_unresolved(host, port).is_ok(),
+    };
+
+    if !allowed {
+        crate::error!("connection to {to_tcp:#?} not allowed");
+        return protocol::Response::ConnectionNotAllowed.send(&mut stream);
+    }
+
+    match proxy.connect(to_tcp) {
+        Err(e) => send_connect_error(&mut stream, to_tcp, &e),
+        Ok(server) => {
+            crate::debug!("connected to {to_tcp:#?} through upstream proxy");
+
+            let data = encode_addr(&server.local_addr()?)?;
+            protocol::Response::Ok(data).send(&mut stream)?;
+
+            crate::debug!("starting stream copy");
+
+            service::double_stream_copy(SERVICE_KIND, &super::SERVICE, stream, server)
+        }
+    }
+}
+
 fn command_bind(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
     match util::find_best_address() {
         Err(e) => {
@@ -80,16 +181,29 @@ fn command_bind(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
                     match net::TcpListener::bind(from_tcp) {
                         Err(e) => {
                             crate::error!("failed to bind to {from_tcp:#?}: {e}");
-                            protocol::Response::BindFailed.send(&mut stream)
+                            protocol::Response::GeneralFailure.send(&mut stream)
                         }
                         Ok(server) => {
-                            let data = encode_addr(&server.local_addr()?)?;
+                            let local_addr = server.local_addr()?;
+                            if policy::check(None, local_addr).is_err() {
+                                crate::error!("bind to {local_addr} not allowed");
+                                return protocol::Response::ConnectionNotAllowed.send(&mut stream);
+                            }
+
+                            let data = encode_addr(&local_addr)?;
                             protocol::Response::Ok(data).send(&mut stream)?;
 
                             match server.accept() {
                                 Err(e) => {
                                     crate::error!("failed to accept on {from_tcp:#?}: {e}");
-                                    protocol::Response::BindFailed.send(&mut stream)
+                                    protocol::Response::GeneralFailure.send(&mut stream)
+                                }
+                                Ok((client, client_addr))
+                                    if policy::check(None, client_addr).is_err() =>
+                                {
+                                    crate::error!("connection from {client_addr} not allowed");
+                                    let _ = client.shutdown(net::Shutdown::Both);
+                                    protocol::Response::ConnectionNotAllowed.send(&mut stream)
                                 }
                                 Ok((client, client_addr)) => {
                                     let data = encode_addr(&client_addr)?;
@@ -113,13 +227,209 @@ fn command_bind(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
     }
 }
 
+fn command_udp_relay(mut stream: service::RdpStream<'_>, to_udp: &str) -> Result<(), io::Error> {
+    crate::info!("relaying datagrams to {to_udp:#?}");
+
+    let (name, addrs) = match resolve(to_udp) {
+        Err(e) => return send_connect_error(&mut stream, to_udp, &e),
+        Ok(resolved) => resolved,
+    };
+
+    let Some(addr) = addrs
+        .iter()
+        .find(|addr| policy::check(name, **addr).is_ok())
+        .copied()
+    else {
+        crate::error!("relay to {to_udp:#?} not allowed");
+        return protocol::Response::ConnectionNotAllowed.send(&mut stream);
+    };
+
+    let local: net::SocketAddr = if addr.is_ipv4() {
+        (net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    let socket = match net::UdpSocket::bind(local).and_then(|socket| {
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(UDP_RELAY_READ_TIMEOUT))?;
+        Ok(socket)
+    }) {
+        Err(e) => return send_connect_error(&mut stream, to_udp, &e),
+        Ok(socket) => socket,
+    };
+
+    let data = encode_addr(&socket.local_addr()?)?;
+    protocol::Response::Ok(data).send(&mut stream)?;
+
+    let client_id = stream.client_id();
+    let (mut rdp_stream_read, mut rdp_stream_write) = stream.split();
+    let stopped = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .name(format!(
+                "{SERVICE_KIND} {} {client_id:x} udp relay",
+                super::SERVICE
+            ))
+            .spawn_scoped(scope, || {
+                let mut buf = vec![0u8; UDP_MAX_DATAGRAM_SIZE];
+                while !stopped.load(atomic::Ordering::Relaxed) {
+                    match socket.recv(&mut buf) {
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) => {}
+                        Err(e) => {
+                            crate::debug!("error: {e}");
+                            break;
+                        }
+                        Ok(read) => {
+                            if let Err(e) =
+                                protocol::write_datagram(&mut rdp_stream_write, &buf[0..read])
+                            {
+                                crate::debug!("error: {e}");
+                                break;
+                            }
+                        }
+                    }
+                }
+                let _ = rdp_stream_write.disconnect();
+            })
+            .unwrap();
+
+        loop {
+            match protocol::read_datagram(&mut rdp_stream_read) {
+                Err(e) => {
+                    crate::debug!("stopped: {e}");
+                    break;
+                }
+                Ok(datagram) => {
+                    if let Err(e) = socket.send(&datagram) {
+                        crate::debug!("failed to send datagram to {addr}: {e}");
+                    }
+                }
+            }
+        }
+        stopped.store(true, atomic::Ordering::Relaxed);
+        rdp_stream_read.disconnect();
+    });
+
+    Ok(())
+}
+
+fn command_resolve(mut stream: service::RdpStream<'_>, name: &str) -> Result<(), io::Error> {
+    crate::info!("resolving {name:#?}");
+
+    if policy::check_lookup(Some(name), None).is_err() {
+        crate::error!("resolution of {name:#?} not allowed");
+        protocol::Response::ConnectionNotAllowed.send(&mut stream)?;
+        return stream.disconnect();
+    }
+
+    let addrs = (name, 0).to_socket_addrs().map(|addrs| {
+        let addrs = addrs.collect::<Vec<_>>();
+        let allowed = addrs
+            .iter()
+            .filter(|addr| policy::check_lookup(Some(name), Some(addr.ip())).is_ok())
+            .copied()
+            .collect::<Vec<_>>();
+        (addrs.is_empty(), allowed)
+    });
+
+    match addrs {
+        Err(e) => {
+            crate::error!("failed to resolve {name:#?}: {e}");
+            protocol::Response::HostUnreachable.send(&mut stream)?;
+        }
+        Ok((true, _)) => {
+            crate::error!("no address for {name:#?}");
+            protocol::Response::HostUnreachable.send(&mut stream)?;
+        }
+        Ok((false, allowed)) if allowed.is_empty() => {
+            crate::error!("resolution of {name:#?} not allowed");
+            protocol::Response::ConnectionNotAllowed.send(&mut stream)?;
+        }
+        Ok((false, allowed)) => {
+            // like Tor, prefer an IPv4 address when there are both
+            let addr = allowed
+                .iter()
+                .find(|addr| addr.is_ipv4())
+                .unwrap_or(&allowed[0]);
+            crate::debug!("{name:#?} resolved to {}", addr.ip());
+            let data = encode_addr(addr)?;
+            protocol::Response::Ok(data).send(&mut stream)?;
+        }
+    }
+
+    stream.disconnect()
+}
+
+fn command_resolve_ptr(mut stream: service::RdpStream<'_>, ip: &str) -> Result<(), io::Error> {
+    crate::info!("resolving PTR {ip:#?}");
+
+    let ip = net::IpAddr::from_str(ip)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    if policy::check_lookup(None, Some(ip)).is_err() {
+        crate::error!("resolution of PTR {ip} not allowed");
+        protocol::Response::ConnectionNotAllowed.send(&mut stream)?;
+        return stream.disconnect();
+    }
+
+    match dns::backend::reverse(ip) {
+        None => {
+            crate::error!("failed to resolve PTR {ip}");
+            protocol::Response::HostUnreachable.send(&mut stream)?;
+        }
+        Some(name) => {
+            crate::debug!("{ip} resolved to {name:#?}");
+            let data = encode_name(&name)?;
+            protocol::Response::Ok(data).send(&mut stream)?;
+        }
+    }
+
+    stream.disconnect()
+}
+
 pub(crate) fn handler(mut stream: service::RdpStream<'_>) -> Result<(), io::Error> {
     crate::debug!("starting");
 
-    let cmd = protocol::Command::receive(&mut stream)?;
+    let request = match protocol::Request::receive(&mut stream) {
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            crate::error!("{e}");
+            protocol::Response::CommandNotSupported.send(&mut stream)?;
+            return stream.disconnect();
+        }
+        request => request?,
+    };
+
+    if let Some((event, destination)) = request.audited() {
+        let destination = destination.map(|dest| ("destination", audit::Value::Text(dest)));
+        audit::record(
+            SERVICE_KIND,
+            &super::SERVICE,
+            stream.client_id(),
+            None,
+            event,
+            destination.as_slice(),
+        );
+    }
 
-    match cmd {
-        protocol::Command::Connect(to_tcp) => command_connect(stream, &to_tcp),
-        protocol::Command::Bind => command_bind(stream),
+    match request {
+        protocol::Request::Command(protocol::Command::Connect(to_tcp)) => {
+            command_connect(stream, &to_tcp)
+        }
+        protocol::Request::Command(protocol::Command::Bind) => command_bind(stream),
+        protocol::Request::Command(protocol::Command::Resolve(name)) => {
+            command_resolve(stream, &name)
+        }
+        protocol::Request::Command(protocol::Command::ResolvePtr(ip)) => {
+            command_resolve_ptr(stream, &ip)
+        }
+        protocol::Request::UdpRelay(protocol::UdpRelay(to_udp)) => {
+            command_udp_relay(stream, &to_udp)
+        }
     }
 }
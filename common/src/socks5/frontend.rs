@@ -1,5 +1,5 @@
 use super::protocol;
-use crate::service;
+use crate::{audit, auth, options, service};
 use std::{
     fmt,
     io::{self, Read, Write},
@@ -12,6 +12,7 @@ const SERVICE_KIND: service::Kind = service::Kind::Frontend;
 enum Error {
     UnsupportedVersion(u8),
     UnsupportedAuthentication(u8),
+    AuthenticationFailed,
     Io(io::Error),
     UnsupportedCommand(u8),
     AddressTypeNotSupported(u8),
@@ -41,6 +42,7 @@ impl fmt::Display for Error {
             Self::UnsupportedAuthentication(v) => {
                 write!(f, "unsupported authentication {v}")
             }
+            Self::AuthenticationFailed => write!(f, "authentication failed"),
             Self::UnsupportedCommand(v) => write!(f, "unsupported command {v}"),
             Self::AddressTypeNotSupported(v) => write!(f, "address type not supported {v}"),
             Self::Io(e) => write!(f, "I/O error: {e}"),
@@ -48,7 +50,49 @@ impl fmt::Display for Error {
     }
 }
 
-fn handshake(stream: &mut net::TcpStream) -> Result<protocol::Command, Error> {
+fn read_string(stream: &mut service::ClientStream) -> Result<Vec<u8>, io::Error> {
+    let mut len = [0; 1];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; usize::from(len[0])];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Username/password sub-negotiation of RFC 1929.
+fn authenticate(
+    stream: &mut service::ClientStream,
+    (username, password): (&str, &str),
+) -> Result<(), Error> {
+    let mut version = [0; 1];
+    stream.read_exact(&mut version)?;
+    if version[0] != protocol::PASSWORD_VERSION {
+        return Err(Error::AuthenticationFailed);
+    }
+
+    let given_username = read_string(stream)?;
+    let given_password = read_string(stream)?;
+
+    // both are always compared, not to tell which one is wrong
+    let valid_username = auth::constant_time_eq(username.as_bytes(), &given_username);
+    let valid_password = auth::constant_time_eq(password.as_bytes(), &given_password);
+
+    if valid_username && valid_password {
+        stream.write_all(&[protocol::PASSWORD_VERSION, 0x00])?;
+        stream.flush()?;
+        return Ok(());
+    }
+
+    crate::warn!("authentication failed on {}", super::SERVICE);
+    thread::sleep(auth::FAILURE_DELAY);
+    stream.write_all(&[protocol::PASSWORD_VERSION, 0x01])?;
+    stream.flush()?;
+    Err(Error::AuthenticationFailed)
+}
+
+fn handshake(
+    stream: &mut service::ClientStream,
+    credentials: Option<(&str, &str)>,
+) -> Result<protocol::Command, Error> {
     // client greeting
     let mut buf = [0; 2];
     stream.read_exact(&mut buf)?;
@@ -64,38 +108,48 @@ fn handshake(stream: &mut net::TcpStream) -> Result<protocol::Command, Error> {
     let mut buf = vec![0; nb_auth as usize];
     stream.read_exact(&mut buf)?;
 
-    // server supports only 0x0 NO AUTHENTICATION
-    if !buf.into_iter().any(|b| b == protocol::AUTHENTICATION_NONE) {
-        return Err(Error::UnsupportedAuthentication(
-            protocol::AUTHENTICATION_NONE,
-        ));
+    // server requires USERNAME/PASSWORD when configured, NO
+    // AUTHENTICATION otherwise
+    let method = if credentials.is_some() {
+        protocol::AUTHENTICATION_PASSWORD
+    } else {
+        protocol::AUTHENTICATION_NONE
+    };
+    if !buf.into_iter().any(|b| b == method) {
+        return Err(Error::UnsupportedAuthentication(method));
     }
 
-    // server proposes NO AUTHENTICATION
-    let buf = [protocol::VERSION, protocol::AUTHENTICATION_NONE];
+    let buf = [protocol::VERSION, method];
     stream.write_all(&buf)?;
     stream.flush()?;
 
+    if let Some(credentials) = credentials {
+        authenticate(stream, credentials)?;
+    }
+
     Ok(protocol::Command::read(stream)?)
 }
 
 fn command_connect(
-    mut stream: net::TcpStream,
-    mut client_rdp: service::RdpStream<'_>,
+    stream: service::ClientStream,
+    client_rdp: service::RdpStream<'_>,
 ) -> Result<(), io::Error> {
-    let resp = protocol::Response::receive(&mut client_rdp)?;
-    resp.answer_to_client(&mut stream)?;
-
-    if !resp.is_ok() {
-        let _ = stream.shutdown(net::Shutdown::Both);
-        return Ok(());
-    }
-
-    service::double_stream_copy(SERVICE_KIND, &super::SERVICE, client_rdp, stream)
+    // data sent by the client before the answer is forwarded at once
+    service::pipelined_stream_copy(
+        SERVICE_KIND,
+        &super::SERVICE,
+        client_rdp,
+        stream,
+        |client_rdp, stream| {
+            let resp = protocol::Response::receive(client_rdp)?;
+            resp.answer_to_client(stream)?;
+            Ok(resp.is_ok())
+        },
+    )
 }
 
 fn command_bind(
-    mut stream: net::TcpStream,
+    mut stream: service::ClientStream,
     mut client_rdp: service::RdpStream<'_>,
 ) -> Result<(), io::Error> {
     // for the bind operation on the backend
@@ -119,13 +173,33 @@ fn command_bind(
     service::double_stream_copy(SERVICE_KIND, &super::SERVICE, client_rdp, stream)
 }
 
+fn command_resolve(
+    mut stream: service::ClientStream,
+    mut client_rdp: service::RdpStream<'_>,
+) -> Result<(), io::Error> {
+    let resp = protocol::Response::receive(&mut client_rdp)?;
+    resp.answer_to_client(&mut stream)?;
+
+    let _ = client_rdp.disconnect();
+    let _ = stream.shutdown(net::Shutdown::Both);
+
+    Ok(())
+}
+
 pub(crate) fn tcp_handler(
-    _server: &service::TcpFrontendServer,
+    server: &service::TcpFrontendServer,
     _scope: &thread::Scope,
-    mut stream: net::TcpStream,
+    mut stream: service::ClientStream,
     channel: &service::Channel,
 ) -> Result<(), io::Error> {
-    match handshake(&mut stream) {
+    let credentials = match server.options() {
+        options::Options::Socks5(options::Socks5 {
+            credentials: Some((username, password)),
+        }) => Some((username.as_str(), password.as_str())),
+        _ => None,
+    };
+
+    match handshake(&mut stream, credentials) {
         Err(e) => match e {
             Error::Io(e) => Err(e),
             Error::UnsupportedVersion(_) => {
@@ -140,6 +214,7 @@ pub(crate) fn tcp_handler(
                 stream.flush()?;
                 Ok(())
             }
+            Error::AuthenticationFailed => Ok(()),
             Error::UnsupportedCommand(_) => {
                 let buf = [
                     protocol::VERSION,
@@ -178,11 +253,26 @@ pub(crate) fn tcp_handler(
         Ok(command) => {
             let mut client_rdp = channel.connect(&super::SERVICE)?;
 
+            if let Some((event, destination)) = command.audited() {
+                let destination = destination.map(|dest| ("destination", audit::Value::Text(dest)));
+                audit::record(
+                    SERVICE_KIND,
+                    &super::SERVICE,
+                    client_rdp.client_id(),
+                    stream.peer_addr().ok(),
+                    event,
+                    destination.as_slice(),
+                );
+            }
+
             command.send(&mut client_rdp)?;
 
             match command {
                 protocol::Command::Connect(_) => command_connect(stream, client_rdp),
                 protocol::Command::Bind => command_bind(stream, client_rdp),
+                protocol::Command::Resolve(_) | protocol::Command::ResolvePtr(_) => {
+                    command_resolve(stream, client_rdp)
+                }
             }
         }
     }
@@ -1,18 +1,25 @@
 use crate::service;
 
 #[cfg(feature = "backend")]
-mod backend;
+pub(crate) mod backend;
 #[cfg(feature = "frontend")]
 mod frontend;
-mod protocol;
+pub(crate) mod protocol;
 
 pub(crate) static SERVICE: service::Service = service::Service {
     name: "socks5",
     #[cfg(feature = "frontend")]
     tcp_frontend: Some(service::TcpFrontend {
         default_port: 1080,
+        enabled_by_default: true,
+        transparent: false,
+        token: false,
         handler: frontend::tcp_handler,
     }),
+    #[cfg(feature = "frontend")]
+    udp_frontend: None,
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tun_frontend: None,
     #[cfg(feature = "backend")]
     backend: service::Backend {
         handler: backend::handler,
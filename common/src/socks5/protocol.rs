@@ -6,9 +6,20 @@ use std::net;
 pub const VERSION: u8 = 0x05;
 #[cfg(feature = "frontend")]
 pub const AUTHENTICATION_NONE: u8 = 0x00;
+#[cfg(feature = "frontend")]
+pub const AUTHENTICATION_PASSWORD: u8 = 0x02;
+// RFC 1929
+#[cfg(feature = "frontend")]
+pub const PASSWORD_VERSION: u8 = 0x01;
 
 const ID_CMD_CONNECT: u8 = 0x01;
 const ID_CMD_BIND: u8 = 0x02;
+const ID_CMD_RESOLVE: u8 = 0x03;
+const ID_CMD_RESOLVE_PTR: u8 = 0x04;
+const ID_CMD_UDP_RELAY: u8 = 0x05;
+
+// longest host name and port, with some margin
+const MAX_STRING_LEN: u32 = 1024;
 
 #[cfg(feature = "frontend")]
 pub enum Error {
@@ -29,9 +40,43 @@ impl From<io::Error> for Error {
 pub enum Command {
     Connect(String),
     Bind,
+    /// Tor extension: resolves a host name to an address
+    Resolve(String),
+    /// Tor extension: resolves an IP address to a host name
+    ResolvePtr(String),
+}
+
+/// Relays datagrams, framed with `write_datagram`, to a UDP
+/// destination. Asked by the frontends of other services, never by
+/// SOCKS clients.
+#[derive(Debug)]
+pub struct UdpRelay(pub String);
+
+/// Request starting a stream of the backend.
+#[cfg(feature = "backend")]
+#[derive(Debug)]
+pub enum Request {
+    Command(Command),
+    UdpRelay(UdpRelay),
+}
+
+#[cfg(feature = "frontend")]
+enum Address {
+    Ip(net::IpAddr),
+    Name(String),
 }
 
 impl Command {
+    /// Event and destination of the command in the audit log, if
+    /// recorded.
+    pub(crate) fn audited(&self) -> Option<(&'static str, Option<&str>)> {
+        match self {
+            Self::Connect(to_tcp) => Some(("connect", Some(to_tcp))),
+            Self::Bind => Some(("bind", None)),
+            Self::Resolve(_) | Self::ResolvePtr(_) => None,
+        }
+    }
+
     #[cfg(feature = "frontend")]
     pub(crate) fn read<R>(reader: &mut R) -> Result<Self, Error>
     where
@@ -55,22 +100,14 @@ impl Command {
         }
          */
 
-        let dest: String = match buf[3] {
+        let dest = match buf[3] {
             // ipv4
             0x01 => {
                 let mut buf = [0x0; 4];
                 reader.read_exact(&mut buf)?;
                 let ip = u32::from_be_bytes(buf);
                 let ip = net::Ipv4Addr::from_bits(ip);
-                let ip = net::IpAddr::V4(ip);
-
-                let mut buf = [0x0; 2];
-                reader.read_exact(&mut buf)?;
-                let port = u16::from_be_bytes(buf);
-
-                crate::info!("connect to {ip}:{port}");
-
-                format!("{ip}:{port}")
+                Address::Ip(net::IpAddr::V4(ip))
             }
             // domain name
             0x03 => {
@@ -78,15 +115,7 @@ impl Command {
                 reader.read_exact(&mut len)?;
                 let mut buf = vec![0x0; len[0] as usize];
                 reader.read_exact(&mut buf)?;
-                let name = String::from_utf8_lossy(&buf).to_string();
-
-                let mut buf = [0x0; 2];
-                reader.read_exact(&mut buf)?;
-                let port = u16::from_be_bytes(buf);
-
-                crate::info!("connect to {name}:{port}",);
-
-                format!("{name}:{port}")
+                Address::Name(String::from_utf8_lossy(&buf).to_string())
             }
             // ipv6
             0x04 => {
@@ -94,28 +123,50 @@ impl Command {
                 reader.read_exact(&mut buf)?;
                 let ip = u128::from_be_bytes(buf);
                 let ip = net::Ipv6Addr::from_bits(ip);
-                let ip = net::IpAddr::V6(ip);
-
-                let mut buf = [0x0; 2];
-                reader.read_exact(&mut buf)?;
-                let port = u16::from_be_bytes(buf);
-
-                crate::info!("connect to {ip}:{port}",);
-
-                format!("{ip}:{port}")
+                Address::Ip(net::IpAddr::V6(ip))
             }
             t => return Err(Error::AddressTypeNotSupported(t)),
         };
 
+        let mut port = [0x0; 2];
+        reader.read_exact(&mut port)?;
+        let port = u16::from_be_bytes(port);
+
         crate::trace!("READ {buf:?}");
 
         match buf[1] {
             // CONNECT
-            0x01 => Ok(Self::Connect(dest)),
+            0x01 => {
+                let dest = match dest {
+                    Address::Ip(ip) => net::SocketAddr::new(ip, port).to_string(),
+                    Address::Name(name) => format!("{name}:{port}"),
+                };
+                crate::info!("connect to {dest}");
+                Ok(Self::Connect(dest))
+            }
 
             // BIND
             0x02 => Ok(Self::Bind),
 
+            // RESOLVE
+            0xF0 => {
+                let name = match dest {
+                    Address::Ip(ip) => ip.to_string(),
+                    Address::Name(name) => name,
+                };
+                crate::info!("resolve {name}");
+                Ok(Self::Resolve(name))
+            }
+
+            // RESOLVE_PTR
+            0xF1 => match dest {
+                Address::Ip(ip) => {
+                    crate::info!("resolve PTR {ip}");
+                    Ok(Self::ResolvePtr(ip.to_string()))
+                }
+                Address::Name(_) => Err(Error::AddressTypeNotSupported(buf[3])),
+            },
+
             c => Err(Error::UnsupportedCommand(c)),
         }
     }
@@ -140,11 +191,60 @@ impl Command {
                 let buf = [ID_CMD_BIND; 1];
                 stream.write_all(&buf)?;
             }
+            Self::Resolve(name) => {
+                let buf = [ID_CMD_RESOLVE; 1];
+                stream.write_all(&buf)?;
+
+                let len = u32::try_from(name.len())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                stream.write_all(&len.to_le_bytes())?;
+
+                stream.write_all(name.as_bytes())?;
+            }
+            Self::ResolvePtr(ip) => {
+                let buf = [ID_CMD_RESOLVE_PTR; 1];
+                stream.write_all(&buf)?;
+
+                let len = u32::try_from(ip.len())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                stream.write_all(&len.to_le_bytes())?;
+
+                stream.write_all(ip.as_bytes())?;
+            }
         }
         stream.flush()
     }
+}
+
+impl UdpRelay {
+    #[cfg(feature = "frontend")]
+    pub(crate) fn send<W>(&self, stream: &mut W) -> Result<(), io::Error>
+    where
+        W: io::Write,
+    {
+        let buf = [ID_CMD_UDP_RELAY; 1];
+        stream.write_all(&buf)?;
+
+        let len = u32::try_from(self.0.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        stream.write_all(&len.to_le_bytes())?;
+
+        stream.write_all(self.0.as_bytes())?;
+        stream.flush()
+    }
+}
+
+#[cfg(feature = "backend")]
+impl Request {
+    /// Event and destination of the request in the audit log, if
+    /// recorded.
+    pub(crate) fn audited(&self) -> Option<(&'static str, Option<&str>)> {
+        match self {
+            Self::Command(command) => command.audited(),
+            Self::UdpRelay(UdpRelay(to_udp)) => Some(("udp_relay", Some(to_udp))),
+        }
+    }
 
-    #[cfg(feature = "backend")]
     pub(crate) fn receive<R>(stream: &mut R) -> Result<Self, io::Error>
     where
         R: io::Read,
@@ -152,29 +252,77 @@ impl Command {
         let mut buf = [0u8; 1];
         stream.read_exact(&mut buf)?;
 
-        match buf[0] {
-            ID_CMD_CONNECT => {
-                let mut buf = [0u8; 4];
-                stream.read_exact(&mut buf)?;
-                let len = u32::from_le_bytes(buf);
-
-                let mut buf = vec![0u8; len as usize];
-                stream.read_exact(&mut buf)?;
-                let to_tcp = String::from_utf8_lossy(&buf).to_string();
-
-                Ok(Self::Connect(to_tcp))
+        let command = match buf[0] {
+            ID_CMD_CONNECT => Command::Connect(receive_string(stream)?),
+            ID_CMD_BIND => Command::Bind,
+            ID_CMD_RESOLVE => Command::Resolve(receive_string(stream)?),
+            ID_CMD_RESOLVE_PTR => Command::ResolvePtr(receive_string(stream)?),
+            ID_CMD_UDP_RELAY => return Ok(Self::UdpRelay(UdpRelay(receive_string(stream)?))),
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported socks command {v}"),
+                ));
             }
-            ID_CMD_BIND => Ok(Self::Bind),
-            v => unimplemented!("unsupported socks command {v}"),
-        }
+        };
+        Ok(Self::Command(command))
+    }
+}
+
+#[cfg(feature = "backend")]
+fn receive_string<R>(stream: &mut R) -> Result<String, io::Error>
+where
+    R: io::Read,
+{
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    let len = u32::from_le_bytes(buf);
+    if MAX_STRING_LEN < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("socks request string too long ({len} bytes)"),
+        ));
     }
+
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf)?;
+
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+pub(crate) fn write_datagram<W>(stream: &mut W, datagram: &[u8]) -> Result<(), io::Error>
+where
+    W: io::Write,
+{
+    let len = u16::try_from(datagram.len())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(datagram)?;
+    stream.flush()
+}
+
+pub(crate) fn read_datagram<R>(stream: &mut R) -> Result<Vec<u8>, io::Error>
+where
+    R: io::Read,
+{
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    let len = u16::from_le_bytes(buf);
+
+    let mut datagram = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut datagram)?;
+
+    Ok(datagram)
 }
 
 const ID_RESP_OK: u8 = 0x00;
 const ID_RESP_NETWORK_UNREACHABLE: u8 = 0x01;
 const ID_RESP_HOST_UNREACHABLE: u8 = 0x02;
 const ID_RESP_CONNECTION_REFUSED: u8 = 0x03;
-const ID_RESP_BIND_FAILED: u8 = 0x04;
+const ID_RESP_GENERAL_FAILURE: u8 = 0x04;
+const ID_RESP_CONNECTION_NOT_ALLOWED: u8 = 0x05;
+const ID_RESP_TTL_EXPIRED: u8 = 0x06;
+const ID_RESP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
 
 #[derive(Debug)]
 pub enum Response {
@@ -182,22 +330,28 @@ pub enum Response {
     NetworkUnreachable,
     HostUnreachable,
     ConnectionRefused,
-    BindFailed,
+    GeneralFailure,
+    ConnectionNotAllowed,
+    TtlExpired,
+    CommandNotSupported,
 }
 
 #[cfg(feature = "frontend")]
 const RSP_OK: u8 = 0x00;
 #[cfg(feature = "frontend")]
 const RSP_GENERAL_SOCKS_SERVER_FAILURE: u8 = 0x01;
-//const RSP_CONNECTION_NOT_ALLOWED: u8 = 0x02;
+#[cfg(feature = "frontend")]
+const RSP_CONNECTION_NOT_ALLOWED: u8 = 0x02;
 #[cfg(feature = "frontend")]
 const RSP_NETWORK_UNREACHABLE: u8 = 0x03;
 #[cfg(feature = "frontend")]
 const RSP_HOST_UNREACHABLE: u8 = 0x04;
 #[cfg(feature = "frontend")]
 const RSP_CONNECTION_REFUSED: u8 = 0x05;
-//const RSP_TTL_EXPIRED: u8 = 0x06;
-//const RSP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+#[cfg(feature = "frontend")]
+const RSP_TTL_EXPIRED: u8 = 0x06;
+#[cfg(feature = "frontend")]
+const RSP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
 //const RSP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
 
 impl Response {
@@ -211,72 +365,24 @@ impl Response {
     where
         W: io::Write,
     {
-        match self {
-            Self::NetworkUnreachable => {
-                let buf = [
-                    VERSION,
-                    RSP_NETWORK_UNREACHABLE,
-                    0x00,
-                    0x01,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                ];
-                writer.write_all(&buf)?;
-            }
-            Self::HostUnreachable => {
-                let buf = [
-                    VERSION,
-                    RSP_HOST_UNREACHABLE,
-                    0x00,
-                    0x01,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                ];
-                writer.write_all(&buf)?;
-            }
-            Self::ConnectionRefused => {
-                let buf = [
-                    VERSION,
-                    RSP_CONNECTION_REFUSED,
-                    0x00,
-                    0x01,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                ];
-                writer.write_all(&buf)?;
-            }
-            Self::BindFailed => {
-                let buf = [
-                    VERSION,
-                    RSP_GENERAL_SOCKS_SERVER_FAILURE,
-                    0x00,
-                    0x01,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                    0x00,
-                ];
-                writer.write_all(&buf)?;
-            }
+        let code = match self {
             Self::Ok(data) => {
                 writer.write_all(&[VERSION, RSP_OK, 0x00])?;
                 writer.write_all(data)?;
+                return writer.flush();
             }
-        }
+            Self::NetworkUnreachable => RSP_NETWORK_UNREACHABLE,
+            Self::HostUnreachable => RSP_HOST_UNREACHABLE,
+            Self::ConnectionRefused => RSP_CONNECTION_REFUSED,
+            Self::GeneralFailure => RSP_GENERAL_SOCKS_SERVER_FAILURE,
+            Self::ConnectionNotAllowed => RSP_CONNECTION_NOT_ALLOWED,
+            Self::TtlExpired => RSP_TTL_EXPIRED,
+            Self::CommandNotSupported => RSP_COMMAND_NOT_SUPPORTED,
+        };
+        let buf = [
+            VERSION, code, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        writer.write_all(&buf)?;
         writer.flush()
     }
 
@@ -290,7 +396,10 @@ impl Response {
             Self::NetworkUnreachable => (ID_RESP_NETWORK_UNREACHABLE, None),
             Self::HostUnreachable => (ID_RESP_HOST_UNREACHABLE, None),
             Self::ConnectionRefused => (ID_RESP_CONNECTION_REFUSED, None),
-            Self::BindFailed => (ID_RESP_BIND_FAILED, None),
+            Self::GeneralFailure => (ID_RESP_GENERAL_FAILURE, None),
+            Self::ConnectionNotAllowed => (ID_RESP_CONNECTION_NOT_ALLOWED, None),
+            Self::TtlExpired => (ID_RESP_TTL_EXPIRED, None),
+            Self::CommandNotSupported => (ID_RESP_COMMAND_NOT_SUPPORTED, None),
         };
         let buf = [id; 1];
         stream.write_all(&buf)?;
@@ -317,6 +426,12 @@ impl Response {
                 let mut buf = [0u8; 4];
                 stream.read_exact(&mut buf)?;
                 let len = u32::from_le_bytes(buf);
+                if MAX_STRING_LEN < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("socks response too long ({len} bytes)"),
+                    ));
+                }
 
                 let mut data = vec![0u8; len as usize];
                 stream.read_exact(&mut data)?;
@@ -326,8 +441,14 @@ impl Response {
             ID_RESP_NETWORK_UNREACHABLE => Ok(Self::NetworkUnreachable),
             ID_RESP_HOST_UNREACHABLE => Ok(Self::HostUnreachable),
             ID_RESP_CONNECTION_REFUSED => Ok(Self::ConnectionRefused),
-            ID_RESP_BIND_FAILED => Ok(Self::BindFailed),
-            v => unimplemented!("unsupported socks response {v}"),
+            ID_RESP_GENERAL_FAILURE => Ok(Self::GeneralFailure),
+            ID_RESP_CONNECTION_NOT_ALLOWED => Ok(Self::ConnectionNotAllowed),
+            ID_RESP_TTL_EXPIRED => Ok(Self::TtlExpired),
+            ID_RESP_COMMAND_NOT_SUPPORTED => Ok(Self::CommandNotSupported),
+            v => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported socks response {v}"),
+            )),
         }
     }
 }
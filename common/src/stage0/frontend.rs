@@ -1,4 +1,4 @@
-use crate::{api, service};
+use crate::{api, audit, auth, service};
 use std::{
     fs,
     io::{self, BufRead, Read, Write},
@@ -6,16 +6,23 @@ use std::{
 };
 
 pub(crate) fn tcp_handler(
-    _server: &service::TcpFrontendServer,
+    server: &service::TcpFrontendServer,
     _scope: &thread::Scope,
-    stream: net::TcpStream,
+    stream: service::ClientStream,
     channel: &service::Channel,
 ) -> Result<(), io::Error> {
+    let peer = stream.peer_addr().ok();
     let lstream = stream.try_clone()?;
     let mut client_read = io::BufReader::new(lstream);
 
     let mut client_write = io::BufWriter::new(stream);
 
+    if !auth::auth_line(server, &mut client_read, &mut client_write)? {
+        let lstream = client_read.into_inner();
+        let _ = lstream.shutdown(net::Shutdown::Both);
+        return Ok(());
+    }
+
     let mut rdp = channel.connect(&super::SERVICE)?;
 
     let mut line = String::new();
@@ -44,7 +51,7 @@ pub(crate) fn tcp_handler(
 
     match command.as_str() {
         "CAT" | "PUSH" | "PUT" | "SEND" | "UPLOAD" => {
-            match fs::File::options().read(true).open(args) {
+            match fs::File::options().read(true).open(&args) {
                 Err(e) => {
                     writeln!(client_write, "failed to open file for reading: {e}")?;
                 }
@@ -67,6 +74,18 @@ pub(crate) fn tcp_handler(
                         total += read;
                     }
 
+                    audit::record(
+                        service::Kind::Frontend,
+                        &super::SERVICE,
+                        rdp.client_id(),
+                        peer,
+                        "push",
+                        &[
+                            ("path", audit::Value::Text(&args)),
+                            ("size", audit::Value::Size(total as u64)),
+                        ],
+                    );
+
                     writeln!(client_write, "file sent ({total} bytes)")?;
                 }
             }
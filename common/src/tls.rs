@@ -0,0 +1,225 @@
+//! TLS termination of the TCP frontend servers, so that clients can
+//! reach the services over an encrypted connection, optionally
+//! authenticated with a client certificate.
+
+use rustls::{
+    pki_types::{self, pem::PemObject},
+    server,
+};
+use std::{
+    fmt,
+    io::{self, Write},
+    net, sync, time,
+};
+
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const INCOMING_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    ClientVerifier(server::VerifierBuilderError),
+    NoCertificate(String),
+    Pem(String, pki_types::pem::Error),
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::ClientVerifier(e) => write!(f, "invalid client CA: {e}"),
+            Self::NoCertificate(path) => write!(f, "no certificate found in {path:?}"),
+            Self::Pem(path, e) => write!(f, "failed to load {path:?}: {e}"),
+            Self::Rustls(e) => write!(f, "TLS error: {e}"),
+        }
+    }
+}
+
+impl From<server::VerifierBuilderError> for Error {
+    fn from(e: server::VerifierBuilderError) -> Self {
+        Self::ClientVerifier(e)
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(e: rustls::Error) -> Self {
+        Self::Rustls(e)
+    }
+}
+
+fn load_certificates(path: &str) -> Result<Vec<pki_types::CertificateDer<'static>>, Error> {
+    let certificates = pki_types::CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| Error::Pem(path.to_string(), e))?;
+    if certificates.is_empty() {
+        return Err(Error::NoCertificate(path.to_string()));
+    }
+    Ok(certificates)
+}
+
+#[derive(Clone)]
+pub struct Config(sync::Arc<rustls::ServerConfig>);
+
+impl Config {
+    /// Loads the PEM certificate chain and private key of a server.
+    /// If `client_ca` is given, clients must present a certificate
+    /// issued by one of the authorities it contains.
+    pub fn load(certificate: &str, key: &str, client_ca: Option<&str>) -> Result<Self, Error> {
+        let certificates = load_certificates(certificate)?;
+        let key = pki_types::PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| Error::Pem(key.to_string(), e))?;
+
+        let provider = sync::Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = if let Some(client_ca) = client_ca {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in load_certificates(client_ca)? {
+                roots.add(certificate)?;
+            }
+            let verifier = server::WebPkiClientVerifier::builder_with_provider(
+                sync::Arc::new(roots),
+                provider,
+            )
+            .build()?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        Ok(Self(sync::Arc::new(
+            builder.with_single_cert(certificates, key)?,
+        )))
+    }
+}
+
+/// Server side of a TLS connection which, like a `TcpStream`, can be
+/// cloned to be read and written from different threads.
+pub(crate) struct Stream {
+    tls: sync::Arc<sync::Mutex<rustls::ServerConnection>>,
+    // keeps the records of the clones in order on the wire
+    writer: sync::Arc<sync::Mutex<net::TcpStream>>,
+    tcp: net::TcpStream,
+    incoming: Box<[u8]>,
+    incoming_start: usize,
+    incoming_end: usize,
+}
+
+impl Stream {
+    /// Performs the handshake with a newly accepted client.
+    pub(crate) fn accept(config: &Config, tcp: net::TcpStream) -> Result<Self, io::Error> {
+        let mut tls = rustls::ServerConnection::new(config.0.clone()).map_err(io::Error::other)?;
+
+        tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut socket = &tcp;
+        while tls.is_handshaking() {
+            tls.complete_io(&mut socket)?;
+        }
+        while tls.wants_write() {
+            tls.write_tls(&mut socket)?;
+        }
+        tcp.set_read_timeout(None)?;
+
+        Ok(Self {
+            tls: sync::Arc::new(sync::Mutex::new(tls)),
+            writer: sync::Arc::new(sync::Mutex::new(tcp.try_clone()?)),
+            tcp,
+            incoming: vec![0u8; INCOMING_BUFFER_SIZE].into_boxed_slice(),
+            incoming_start: 0,
+            incoming_end: 0,
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> Result<Self, io::Error> {
+        Ok(Self {
+            tls: self.tls.clone(),
+            writer: self.writer.clone(),
+            tcp: self.tcp.try_clone()?,
+            incoming: vec![0u8; INCOMING_BUFFER_SIZE].into_boxed_slice(),
+            incoming_start: 0,
+            incoming_end: 0,
+        })
+    }
+
+    pub(crate) const fn tcp(&self) -> &net::TcpStream {
+        &self.tcp
+    }
+
+    pub(crate) fn shutdown(&self, how: net::Shutdown) -> Result<(), io::Error> {
+        if how != net::Shutdown::Read {
+            let mut tls = self.tls.lock().unwrap();
+            tls.send_close_notify();
+            let _ = self.send_records(tls);
+        }
+        self.tcp.shutdown(how)
+    }
+
+    /// Sends the pending TLS records. The lock on the writer is taken
+    /// before releasing the lock on the connection, so that records
+    /// are sent in the order they were produced, but the connection
+    /// is not kept locked while writing to the socket.
+    fn send_records(
+        &self,
+        mut tls: sync::MutexGuard<'_, rustls::ServerConnection>,
+    ) -> Result<(), io::Error> {
+        let mut records = vec![];
+        while tls.wants_write() {
+            tls.write_tls(&mut records)?;
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        drop(tls);
+        writer.write_all(&records)
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            let mut tls = self.tls.lock().unwrap();
+            match tls.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+
+            if self.incoming_start == self.incoming_end {
+                // do not block writers while waiting for the client
+                drop(tls);
+                self.incoming_end = self.tcp.read(&mut self.incoming)?;
+                self.incoming_start = 0;
+                tls = self.tls.lock().unwrap();
+            }
+
+            // rustls may not take everything at once when a lot of
+            // data is waiting to be read
+            let mut incoming = &self.incoming[self.incoming_start..self.incoming_end];
+            let res = tls.read_tls(&mut incoming).and_then(|_| {
+                tls.process_new_packets()
+                    .map(|_| ())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            });
+            self.incoming_start = self.incoming_end - incoming.len();
+
+            // alerts or key updates
+            self.send_records(tls)?;
+            res?;
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let mut tls = self.tls.lock().unwrap();
+        let written = tls.writer().write(buf)?;
+        self.send_records(tls)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        let mut tls = self.tls.lock().unwrap();
+        tls.writer().flush()?;
+        self.send_records(tls)
+    }
+}
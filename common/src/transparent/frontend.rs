@@ -0,0 +1,157 @@
+//! Connections redirected with iptables/nftables `REDIRECT` or
+//! `TPROXY` targets are opened through the socks5 backend to their
+//! original destination.
+
+use crate::{audit, service, socks5::protocol};
+use std::{
+    io, mem,
+    net::{self, TcpListener},
+    os::fd::AsRawFd,
+    thread,
+};
+
+const SERVICE_KIND: service::Kind = service::Kind::Frontend;
+
+fn getsockopt<T, F>(fd: &F, level: libc::c_int, name: libc::c_int) -> Result<T, io::Error>
+where
+    F: AsRawFd,
+{
+    let mut value = mem::MaybeUninit::<T>::zeroed();
+    let mut len = libc::socklen_t::try_from(mem::size_of::<T>())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value.as_mut_ptr().cast(),
+            &raw mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Sets `IP_TRANSPARENT` on the listener so that it accepts
+/// connections diverted by `TPROXY` rules. Requires `CAP_NET_ADMIN`.
+pub(crate) fn set_transparent(server: &TcpListener) {
+    if let Err(e) = setsockopt_transparent(server) {
+        crate::warn!("failed to enable TPROXY support: {e}");
+    }
+}
+
+fn setsockopt_transparent(server: &TcpListener) -> Result<(), io::Error> {
+    let (level, name) = match server.local_addr()? {
+        net::SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        net::SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+
+    let value: libc::c_int = 1;
+    let len = libc::socklen_t::try_from(mem::size_of::<libc::c_int>())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            server.as_raw_fd(),
+            level,
+            name,
+            (&raw const value).cast(),
+            len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Destination of a connection before its `REDIRECT`, or the local
+/// address of the connection with `TPROXY`.
+fn original_destination(stream: &net::TcpStream) -> Result<net::SocketAddr, io::Error> {
+    let local_addr = stream.local_addr()?;
+
+    let is_ipv4 = match local_addr {
+        net::SocketAddr::V4(_) => true,
+        net::SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_some(),
+    };
+
+    let original = if is_ipv4 {
+        getsockopt::<libc::sockaddr_in, _>(stream, libc::SOL_IP, libc::SO_ORIGINAL_DST).map(
+            |addr| {
+                let ip = net::Ipv4Addr::from_bits(u32::from_be(addr.sin_addr.s_addr));
+                net::SocketAddr::from((ip, u16::from_be(addr.sin_port)))
+            },
+        )
+    } else {
+        getsockopt::<libc::sockaddr_in6, _>(stream, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST).map(
+            |addr| {
+                let ip = net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                net::SocketAddr::from((ip, u16::from_be(addr.sin6_port)))
+            },
+        )
+    };
+
+    match original {
+        Ok(addr) => Ok(addr),
+        Err(e) => {
+            crate::trace!("no original destination ({e}), using local address");
+            Ok(local_addr)
+        }
+    }
+}
+
+pub(crate) fn tcp_handler(
+    server: &service::TcpFrontendServer,
+    _scope: &thread::Scope,
+    stream: service::ClientStream,
+    channel: &service::Channel,
+) -> Result<(), io::Error> {
+    let dest = original_destination(stream.tcp()?)?;
+
+    // a client connected directly to the service, opening the
+    // connection would loop back
+    if dest == stream.local_addr()?
+        && server
+            .local_addr()
+            .socket_addr()
+            .is_some_and(|addr| addr.port() == dest.port())
+    {
+        crate::error!("connection to {dest} was not redirected");
+        let _ = stream.shutdown(net::Shutdown::Both);
+        return Ok(());
+    }
+
+    crate::info!("connect to {dest}");
+
+    let mut client_rdp = channel.connect(&super::SERVICE)?;
+
+    audit::record(
+        SERVICE_KIND,
+        &super::SERVICE,
+        client_rdp.client_id(),
+        stream.peer_addr().ok(),
+        "connect",
+        &[("destination", audit::Value::Text(&dest.to_string()))],
+    );
+    protocol::Command::Connect(dest.to_string()).send(&mut client_rdp)?;
+
+    // the client does not wait for the answer to send its data
+    service::pipelined_stream_copy(
+        SERVICE_KIND,
+        &super::SERVICE,
+        client_rdp,
+        stream,
+        |client_rdp, _| {
+            let resp = protocol::Response::receive(client_rdp)?;
+            if !resp.is_ok() {
+                crate::error!("failed to connect to {dest}: {resp:?}");
+            }
+            Ok(resp.is_ok())
+        },
+    )
+}
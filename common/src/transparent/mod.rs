@@ -0,0 +1,28 @@
+use crate::service;
+#[cfg(feature = "backend")]
+use crate::socks5;
+
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+pub(crate) mod frontend;
+
+pub(crate) static SERVICE: service::Service = service::Service {
+    name: "transparent",
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tcp_frontend: Some(service::TcpFrontend {
+        default_port: 1082,
+        enabled_by_default: false,
+        transparent: true,
+        token: false,
+        handler: frontend::tcp_handler,
+    }),
+    #[cfg(all(feature = "frontend", not(target_os = "linux")))]
+    tcp_frontend: None,
+    #[cfg(feature = "frontend")]
+    udp_frontend: None,
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tun_frontend: None,
+    #[cfg(feature = "backend")]
+    backend: service::Backend {
+        handler: socks5::backend::handler,
+    },
+};
@@ -0,0 +1,636 @@
+//! Userspace network stack terminating the TCP and UDP flows routed
+//! through the TUN interface. TCP flows are opened with socks5
+//! connects on the backend, UDP flows with socks5 UDP relays.
+
+use crate::{audit, service, socks5::protocol};
+use smoltcp::{iface, phy, socket::tcp, wire};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    net,
+    os::fd::AsRawFd,
+    thread, time,
+};
+
+const SERVICE_KIND: service::Kind = service::Kind::Frontend;
+
+const MTU: usize = 1500;
+const MAX_PACKET_SIZE: usize = 65535;
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const TCP_READ_SIZE: usize = 16 * 1024;
+const FLOW_CHANNEL_SIZE: usize = 16;
+const UDP_CHANNEL_SIZE: usize = 256;
+const POLL_TIMEOUT: time::Duration = time::Duration::from_millis(10);
+const LISTEN_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const UDP_IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+// addresses of the userspace stack, never seen by the host
+const STACK_IPV4: net::Ipv4Addr = net::Ipv4Addr::new(169, 254, 0, 1);
+const STACK_IPV6: net::Ipv6Addr = net::Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+type FlowKey = (net::SocketAddr, net::SocketAddr);
+
+#[derive(Default)]
+struct Queues {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let res = f(&mut packet);
+        self.0.push_back(packet);
+        res
+    }
+}
+
+impl phy::Device for Queues {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(
+        &mut self,
+        _timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> phy::DeviceCapabilities {
+        let mut caps = phy::DeviceCapabilities::default();
+        caps.medium = phy::Medium::Ip;
+        caps.max_transmission_unit = MTU;
+        caps
+    }
+}
+
+enum Flow {
+    TcpSyn(FlowKey),
+    Udp(FlowKey, Vec<u8>),
+}
+
+fn classify(packet: &[u8]) -> Option<Flow> {
+    let (protocol, src, dst, payload) = match packet.first()? >> 4 {
+        4 => {
+            let packet = wire::Ipv4Packet::new_checked(packet).ok()?;
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+            (
+                packet.next_header(),
+                net::IpAddr::V4(packet.src_addr()),
+                net::IpAddr::V4(packet.dst_addr()),
+                packet.payload(),
+            )
+        }
+        6 => {
+            let packet = wire::Ipv6Packet::new_checked(packet).ok()?;
+            (
+                packet.next_header(),
+                net::IpAddr::V6(packet.src_addr()),
+                net::IpAddr::V6(packet.dst_addr()),
+                packet.payload(),
+            )
+        }
+        _ => return None,
+    };
+
+    match protocol {
+        wire::IpProtocol::Tcp => {
+            let segment = wire::TcpPacket::new_checked(payload).ok()?;
+            (segment.syn() && !segment.ack()).then(|| {
+                Flow::TcpSyn((
+                    net::SocketAddr::new(src, segment.src_port()),
+                    net::SocketAddr::new(dst, segment.dst_port()),
+                ))
+            })
+        }
+        wire::IpProtocol::Udp => {
+            let datagram = wire::UdpPacket::new_checked(payload).ok()?;
+            Some(Flow::Udp(
+                (
+                    net::SocketAddr::new(src, datagram.src_port()),
+                    net::SocketAddr::new(dst, datagram.dst_port()),
+                ),
+                datagram.payload().to_vec(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn udp_packet(src: net::SocketAddr, dst: net::SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let udp_repr = wire::UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let udp_len = udp_repr.header_len() + payload.len();
+    let caps = phy::ChecksumCapabilities::default();
+
+    let (mut packet, header_len) = match (src.ip(), dst.ip()) {
+        (net::IpAddr::V4(src_addr), net::IpAddr::V4(dst_addr)) => {
+            let ip_repr = wire::Ipv4Repr {
+                src_addr,
+                dst_addr,
+                next_header: wire::IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            let mut packet = vec![0u8; ip_repr.buffer_len() + udp_len];
+            ip_repr.emit(&mut wire::Ipv4Packet::new_unchecked(&mut packet), &caps);
+            (packet, ip_repr.buffer_len())
+        }
+        (net::IpAddr::V6(src_addr), net::IpAddr::V6(dst_addr)) => {
+            let ip_repr = wire::Ipv6Repr {
+                src_addr,
+                dst_addr,
+                next_header: wire::IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            let mut packet = vec![0u8; ip_repr.buffer_len() + udp_len];
+            ip_repr.emit(&mut wire::Ipv6Packet::new_unchecked(&mut packet));
+            (packet, ip_repr.buffer_len())
+        }
+        _ => return None,
+    };
+
+    udp_repr.emit(
+        &mut wire::UdpPacket::new_unchecked(&mut packet[header_len..]),
+        &src.ip().into(),
+        &dst.ip().into(),
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &caps,
+    );
+
+    Some(packet)
+}
+
+enum Incoming {
+    Connected,
+    Data(Vec<u8>),
+}
+
+fn tcp_relay(
+    channel: &service::Channel,
+    (src, dst): FlowKey,
+    client_data: &crossbeam_channel::Receiver<Vec<u8>>,
+    server_data: crossbeam_channel::Sender<Incoming>,
+) -> Result<(), io::Error> {
+    let mut rdp = channel.connect(&super::SERVICE)?;
+
+    audit::record(
+        SERVICE_KIND,
+        &super::SERVICE,
+        rdp.client_id(),
+        Some(src),
+        "connect",
+        &[("destination", audit::Value::Text(&dst.to_string()))],
+    );
+    protocol::Command::Connect(dst.to_string()).send(&mut rdp)?;
+
+    let client_id = rdp.client_id();
+    let (mut rdp_read, mut rdp_write) = rdp.split();
+
+    thread::scope(|scope| {
+        // client data is forwarded without waiting for the answer
+        thread::Builder::new()
+            .name(format!(
+                "{SERVICE_KIND} {} {client_id:x} stream copy",
+                super::SERVICE
+            ))
+            .spawn_scoped(scope, move || {
+                for data in client_data {
+                    if let Err(e) = rdp_write.write_all(&data).and_then(|()| rdp_write.flush()) {
+                        crate::debug!("error: {e}");
+                        break;
+                    }
+                }
+                let _ = rdp_write.disconnect();
+            })
+            .unwrap();
+
+        match protocol::Response::receive(&mut rdp_read) {
+            Err(e) => {
+                crate::error!("failed to connect to {dst}: {e}");
+                rdp_read.disconnect();
+                return;
+            }
+            Ok(resp) if !resp.is_ok() => {
+                crate::error!("failed to connect to {dst}: {resp:?}");
+                rdp_read.disconnect();
+                return;
+            }
+            Ok(_) => (),
+        }
+
+        if server_data.send(Incoming::Connected).is_err() {
+            rdp_read.disconnect();
+            return;
+        }
+
+        let mut buf = vec![0u8; TCP_READ_SIZE];
+        loop {
+            match rdp_read.read(&mut buf) {
+                Err(e) => {
+                    crate::debug!("error: {e}");
+                    break;
+                }
+                Ok(0) => break,
+                Ok(read) => {
+                    if server_data
+                        .send(Incoming::Data(buf[0..read].to_vec()))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(server_data);
+        rdp_read.disconnect();
+    });
+
+    Ok(())
+}
+
+struct TcpFlow {
+    handle: iface::SocketHandle,
+    to_rdp: Option<crossbeam_channel::Sender<Vec<u8>>>,
+    from_rdp: crossbeam_channel::Receiver<Incoming>,
+    connected: bool,
+    pending: Vec<u8>,
+    pending_offset: usize,
+    rdp_closed: bool,
+    closing: bool,
+    created: time::Instant,
+}
+
+impl TcpFlow {
+    fn start<'a>(
+        scope: &'a thread::Scope<'a, '_>,
+        channel: &'a service::Channel,
+        handle: iface::SocketHandle,
+        (src, dst): FlowKey,
+    ) -> Self {
+        let (to_rdp, client_data) = crossbeam_channel::bounded(FLOW_CHANNEL_SIZE);
+        let (server_data, from_rdp) = crossbeam_channel::bounded(FLOW_CHANNEL_SIZE);
+
+        thread::Builder::new()
+            .name(format!("{SERVICE_KIND} {} {src} {dst}", super::SERVICE))
+            .spawn_scoped(scope, move || {
+                crate::info!("connect to {dst}");
+                if let Err(e) = tcp_relay(channel, (src, dst), &client_data, server_data) {
+                    crate::debug!("error: {e}");
+                }
+            })
+            .unwrap();
+
+        Self {
+            handle,
+            to_rdp: Some(to_rdp),
+            from_rdp,
+            connected: false,
+            pending: vec![],
+            pending_offset: 0,
+            rdp_closed: false,
+            closing: false,
+            created: time::Instant::now(),
+        }
+    }
+
+    /// Moves data between the socket of the flow and its relay
+    /// thread, returns `false` once the flow is over.
+    fn pump(&mut self, sockets: &mut iface::SocketSet<'_>) -> bool {
+        let socket = sockets.get_mut::<tcp::Socket>(self.handle);
+
+        let over = match socket.state() {
+            // packets sent by the previous pump have been dispatched
+            tcp::State::Closed => true,
+            tcp::State::Listen => LISTEN_TIMEOUT < self.created.elapsed(),
+            _ => false,
+        };
+        if over {
+            sockets.remove(self.handle);
+            return false;
+        }
+
+        if let Some(to_rdp) = &self.to_rdp {
+            while socket.can_recv() && !to_rdp.is_full() {
+                let mut buf = vec![0u8; TCP_READ_SIZE];
+                match socket.recv_slice(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => {
+                        buf.truncate(read);
+                        if to_rdp.send(buf).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let client_closed = matches!(
+            socket.state(),
+            tcp::State::CloseWait
+                | tcp::State::LastAck
+                | tcp::State::Closing
+                | tcp::State::TimeWait
+        );
+        if client_closed && !socket.can_recv() {
+            self.to_rdp = None;
+        }
+
+        loop {
+            if self.pending_offset < self.pending.len() {
+                if !socket.may_send() {
+                    break;
+                }
+                match socket.send_slice(&self.pending[self.pending_offset..]) {
+                    Err(_) => break,
+                    Ok(sent) => {
+                        self.pending_offset += sent;
+                        if self.pending_offset < self.pending.len() {
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+
+            match self.from_rdp.try_recv() {
+                Ok(Incoming::Connected) => self.connected = true,
+                Ok(Incoming::Data(data)) => {
+                    self.pending = data;
+                    self.pending_offset = 0;
+                }
+                Err(crossbeam_channel::TryRecvError::Empty) => break,
+                Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                    self.rdp_closed = true;
+                    break;
+                }
+            }
+        }
+
+        if self.rdp_closed && !self.closing && self.pending.len() <= self.pending_offset {
+            if self.connected {
+                socket.close();
+            } else {
+                socket.abort();
+            }
+            self.closing = true;
+        }
+
+        true
+    }
+}
+
+fn udp_relay(
+    channel: &service::Channel,
+    (src, dst): FlowKey,
+    datagrams: &crossbeam_channel::Receiver<Vec<u8>>,
+    to_tun: &crossbeam_channel::Sender<(FlowKey, Vec<u8>)>,
+) -> Result<(), io::Error> {
+    let mut rdp = channel.connect(&super::SERVICE)?;
+
+    audit::record(
+        SERVICE_KIND,
+        &super::SERVICE,
+        rdp.client_id(),
+        Some(src),
+        "udp_relay",
+        &[("destination", audit::Value::Text(&dst.to_string()))],
+    );
+    protocol::UdpRelay(dst.to_string()).send(&mut rdp)?;
+
+    let resp = protocol::Response::receive(&mut rdp)?;
+    if !resp.is_ok() {
+        crate::error!("failed to relay to {dst}: {resp:?}");
+        return rdp.disconnect();
+    }
+
+    let client_id = rdp.client_id();
+    let (mut rdp_read, mut rdp_write) = rdp.split();
+
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .name(format!(
+                "{SERVICE_KIND} {} {client_id:x} udp relay",
+                super::SERVICE
+            ))
+            .spawn_scoped(scope, move || {
+                for datagram in datagrams {
+                    if let Err(e) = protocol::write_datagram(&mut rdp_write, &datagram) {
+                        crate::debug!("error: {e}");
+                        break;
+                    }
+                }
+                let _ = rdp_write.disconnect();
+            })
+            .unwrap();
+
+        loop {
+            match protocol::read_datagram(&mut rdp_read) {
+                Err(e) => {
+                    crate::debug!("stopped: {e}");
+                    break;
+                }
+                Ok(datagram) => {
+                    // answers come from the destination of the flow
+                    if let Err(crossbeam_channel::TrySendError::Disconnected(_)) =
+                        to_tun.try_send(((dst, src), datagram))
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        rdp_read.disconnect();
+    });
+
+    Ok(())
+}
+
+struct UdpFlow {
+    to_rdp: crossbeam_channel::Sender<Vec<u8>>,
+    last: time::Instant,
+}
+
+impl UdpFlow {
+    fn start<'a>(
+        scope: &'a thread::Scope<'a, '_>,
+        channel: &'a service::Channel,
+        key: FlowKey,
+        to_tun: crossbeam_channel::Sender<(FlowKey, Vec<u8>)>,
+    ) -> Self {
+        let (to_rdp, datagrams) = crossbeam_channel::bounded(FLOW_CHANNEL_SIZE);
+
+        let (src, dst) = key;
+        thread::Builder::new()
+            .name(format!("{SERVICE_KIND} {} {src} {dst} udp", super::SERVICE))
+            .spawn_scoped(scope, move || {
+                crate::info!("relay to {dst}");
+                if let Err(e) = udp_relay(channel, key, &datagrams, &to_tun) {
+                    crate::debug!("error: {e}");
+                }
+            })
+            .unwrap();
+
+        Self {
+            to_rdp,
+            last: time::Instant::now(),
+        }
+    }
+}
+
+fn wait_readable(device: &fs::File, timeout: time::Duration) -> Result<(), io::Error> {
+    let mut fds = libc::pollfd {
+        fd: device.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+
+    let ret = unsafe { libc::poll(&raw mut fds, 1, timeout) };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn tun_handler<'a>(
+    server: &service::TunFrontendServer,
+    scope: &'a thread::Scope<'a, '_>,
+    mut device: fs::File,
+    channel: &'a service::Channel,
+) -> Result<(), io::Error> {
+    let mut queues = Queues::default();
+
+    let config = iface::Config::new(wire::HardwareAddress::Ip);
+    let mut iface = iface::Interface::new(config, &mut queues, smoltcp::time::Instant::now());
+    iface.set_any_ip(true);
+    iface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(wire::IpCidr::new(STACK_IPV4.into(), 32));
+        let _ = addrs.push(wire::IpCidr::new(STACK_IPV6.into(), 128));
+    });
+    // with AnyIP, packets to any destination routed through one of
+    // the stack addresses are accepted
+    let _ = iface.routes_mut().add_default_ipv4_route(STACK_IPV4);
+    let _ = iface.routes_mut().add_default_ipv6_route(STACK_IPV6);
+
+    let mut sockets = iface::SocketSet::new(vec![]);
+    let mut tcp_flows: HashMap<FlowKey, TcpFlow> = HashMap::new();
+    let mut udp_flows: HashMap<FlowKey, UdpFlow> = HashMap::new();
+    let (to_tun, from_udp_flows) = crossbeam_channel::bounded(UDP_CHANNEL_SIZE);
+
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+    while !server.is_stopped() {
+        let timeout = iface
+            .poll_delay(smoltcp::time::Instant::now(), &sockets)
+            .map_or(POLL_TIMEOUT, |delay| {
+                POLL_TIMEOUT.min(time::Duration::from_micros(delay.total_micros()))
+            });
+        wait_readable(&device, timeout)?;
+
+        loop {
+            let read = match device.read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+                Ok(0) => break,
+                Ok(read) => read,
+            };
+            let packet = buf[0..read].to_vec();
+
+            match classify(&packet) {
+                Some(Flow::Udp(key, datagram)) => {
+                    let flow = udp_flows
+                        .entry(key)
+                        .or_insert_with(|| UdpFlow::start(scope, channel, key, to_tun.clone()));
+                    flow.last = time::Instant::now();
+                    match flow.to_rdp.try_send(datagram) {
+                        Ok(()) => (),
+                        Err(crossbeam_channel::TrySendError::Full(_)) => {
+                            crate::trace!("dropping datagram from {} to {}", key.0, key.1);
+                        }
+                        Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
+                            udp_flows.remove(&key);
+                        }
+                    }
+                    continue;
+                }
+                Some(Flow::TcpSyn(key)) if !tcp_flows.contains_key(&key) => {
+                    let mut socket = tcp::Socket::new(
+                        tcp::SocketBuffer::new(vec![0u8; TCP_BUFFER_SIZE]),
+                        tcp::SocketBuffer::new(vec![0u8; TCP_BUFFER_SIZE]),
+                    );
+                    let (_, dst) = key;
+                    let endpoint = wire::IpListenEndpoint {
+                        addr: Some(dst.ip().into()),
+                        port: dst.port(),
+                    };
+                    if let Err(e) = socket.listen(endpoint) {
+                        crate::error!("failed to accept connection to {dst}: {e}");
+                    } else {
+                        let handle = sockets.add(socket);
+                        tcp_flows.insert(key, TcpFlow::start(scope, channel, handle, key));
+                    }
+                }
+                _ => (),
+            }
+
+            queues.rx.push_back(packet);
+        }
+
+        for ((src, dst), datagram) in from_udp_flows.try_iter() {
+            if let Some(packet) = udp_packet(src, dst, &datagram) {
+                queues.tx.push_back(packet);
+            }
+        }
+
+        let now = smoltcp::time::Instant::now();
+        iface.poll(now, &mut queues, &mut sockets);
+        tcp_flows.retain(|_, flow| flow.pump(&mut sockets));
+        iface.poll(now, &mut queues, &mut sockets);
+
+        udp_flows.retain(|_, flow| flow.last.elapsed() < UDP_IDLE_TIMEOUT);
+
+        while let Some(packet) = queues.tx.pop_front() {
+            if let Err(e) = device.write(&packet) {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    return Err(e);
+                }
+                // lost packets are retransmitted by TCP
+                crate::trace!("dropping packet: {e}");
+            }
+        }
+    }
+
+    Ok(())
+}
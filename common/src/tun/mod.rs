@@ -0,0 +1,22 @@
+use crate::service;
+#[cfg(feature = "backend")]
+use crate::socks5;
+
+#[cfg(all(feature = "frontend", target_os = "linux"))]
+mod frontend;
+
+pub(crate) static SERVICE: service::Service = service::Service {
+    name: "tun",
+    #[cfg(feature = "frontend")]
+    tcp_frontend: None,
+    #[cfg(feature = "frontend")]
+    udp_frontend: None,
+    #[cfg(all(feature = "frontend", target_os = "linux"))]
+    tun_frontend: Some(service::TunFrontend {
+        handler: frontend::tun_handler,
+    }),
+    #[cfg(feature = "backend")]
+    backend: service::Backend {
+        handler: socks5::backend::handler,
+    },
+};
@@ -1,15 +1,29 @@
-use common::service;
+use common::{acl, limits, options, service, tls};
 use std::{
     env, fmt, fs,
     io::{self, Read, Write},
-    string,
+    path,
+    str::FromStr,
+    string, time,
 };
 
 pub enum Error {
+    Acl(acl::Error),
     Deserialization(toml::de::Error),
+    InvalidProfile(String),
+    InvalidValue(String, String),
     Io(io::Error),
+    Options(String, options::Error),
     Serialization(toml::ser::Error),
+    Tls(String, tls::Error),
     UnknownService(String),
+    UnsupportedToken(String),
+}
+
+impl From<acl::Error> for Error {
+    fn from(e: acl::Error) -> Self {
+        Self::Acl(e)
+    }
 }
 
 impl From<toml::de::Error> for Error {
@@ -33,10 +47,16 @@ impl From<toml::ser::Error> for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
+            Self::Acl(e) => write!(f, "ACL error: {e}"),
             Self::Deserialization(e) => write!(f, "deserialization error: {e}"),
+            Self::InvalidProfile(reason) => write!(f, "invalid profile: {reason}"),
+            Self::InvalidValue(name, s) => write!(f, "invalid value {s:?} for {name}"),
             Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::Options(service, e) => write!(f, "invalid options for {service}: {e}"),
             Self::Serialization(e) => write!(f, "serialization error: {e}"),
+            Self::Tls(service, e) => write!(f, "TLS error for {service}: {e}"),
             Self::UnknownService(s) => write!(f, "unknown service {s:?}"),
+            Self::UnsupportedToken(s) => write!(f, "{s} does not support token authentication"),
         }
     }
 }
@@ -53,6 +73,8 @@ fn default_log_level() -> String {
 }
 
 static LOG_FILE: &str = "soxy.log";
+const DEFAULT_LOG_MAX_FILES: usize = 5;
+const DEFAULT_SOCKET_MODE: u32 = 0o600;
 
 fn default_log_file() -> Option<String> {
     let mut path = env::temp_dir();
@@ -64,12 +86,24 @@ const fn default_true() -> bool {
     true
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub(crate) struct Log {
     #[serde(default = "default_log_level")]
     level: String,
     #[serde(default = "default_log_file")]
     file: Option<String>,
+    /// "text" or "json"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    /// Size in bytes at which the file is rotated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_size: Option<u64>,
+    /// Time in seconds after which the file is rotated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rotate_interval: Option<u64>,
+    /// Number of rotated files kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_files: Option<usize>,
 }
 
 impl Default for Log {
@@ -77,11 +111,47 @@ impl Default for Log {
         Self {
             level: default_log_level(),
             file: None,
+            format: None,
+            max_size: None,
+            rotate_interval: None,
+            max_files: None,
         }
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Audit {
+    /// JSON lines file to which operations are appended
+    file: String,
+}
+
+fn default_management_ip() -> String {
+    "127.0.0.1".into()
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Management {
+    /// Loopback address of the management endpoint
+    #[serde(default = "default_management_ip")]
+    pub ip: String,
+    pub port: u16,
+    /// Shared secret expected in every request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Tls {
+    /// PEM certificate chain of the server
+    pub certificate: String,
+    /// PEM private key of the server
+    pub key: String,
+    /// PEM authorities issuing the certificates required from clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<String>,
+}
+
+#[derive(Clone, PartialEq, serde::Deserialize, serde::Serialize)]
 pub(crate) struct Service {
     pub name: String,
     #[serde(default = "default_true")]
@@ -90,48 +160,335 @@ pub(crate) struct Service {
     pub ip: Option<String>,
     #[serde(default)]
     pub port: Option<u16>,
+    /// Unix domain socket listened on instead of `ip` and `port`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+    /// Permissions of the Unix domain socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_mode: Option<u32>,
+    /// Subnets routed through the TUN interface of the `tun` service
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<String>,
+    /// Shared secret expected from clients of the telnet-like services
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Client networks allowed to use this service
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Client networks denied to use this service
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+    /// TLS termination of the client connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+    /// Maximum number of concurrent clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Seconds without data after which a stream is closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    /// Seconds after which a stream is closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lifetime: Option<u64>,
+    /// Settings specific to the service
+    #[serde(default, skip_serializing_if = "toml::Table::is_empty")]
+    pub options: toml::Table,
+}
+
+impl Service {
+    /// Access control list of the service, completed with the global
+    /// one.
+    pub fn acl(&self, global: &acl::Acl) -> Result<acl::Acl, Error> {
+        Ok(acl::Acl::new(&self.allow, &self.deny)?.or(global))
+    }
+
+    pub fn limits(&self) -> limits::Limits {
+        limits::Limits {
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout.map(time::Duration::from_secs),
+            max_lifetime: self.max_lifetime.map(time::Duration::from_secs),
+        }
+    }
+
+    pub fn tls(&self) -> Result<Option<tls::Config>, Error> {
+        self.tls
+            .as_ref()
+            .map(|t| tls::Config::load(&t.certificate, &t.key, t.client_ca.as_deref()))
+            .transpose()
+            .map_err(|e| Error::Tls(self.name.clone(), e))
+    }
+
+    /// Checked settings of `service`, configured by this entry.
+    pub fn options(&self, service: &service::Service) -> Result<options::Options, Error> {
+        let error = |e| Error::Options(self.name.clone(), e);
+
+        let raw = self
+            .options
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    toml::Value::String(s) => s.clone(),
+                    toml::Value::Integer(i) => i.to_string(),
+                    toml::Value::Float(f) => f.to_string(),
+                    toml::Value::Boolean(b) => b.to_string(),
+                    _ => {
+                        return Err(error(options::Error::Invalid(
+                            name.clone(),
+                            "must be a string, a number or a boolean",
+                        )));
+                    }
+                };
+                Ok((name.clone(), value))
+            })
+            .collect::<Result<options::Raw, Error>>()?;
+
+        options::Options::parse(service, &raw).map_err(error)
+    }
+
+    /// Token required by `service`, which must support it.
+    pub fn token(&self, service: &service::Service) -> Result<Option<String>, Error> {
+        match &self.token {
+            Some(_)
+                if !service
+                    .tcp_frontend()
+                    .is_some_and(service::TcpFrontend::supports_token) =>
+            {
+                Err(Error::UnsupportedToken(self.name.clone()))
+            }
+            token => Ok(token.clone()),
+        }
+    }
+
+    pub fn socket_mode(&self) -> u32 {
+        self.socket_mode.unwrap_or(DEFAULT_SOCKET_MODE)
+    }
+
+    /// Default configuration of a service listening on TCP.
+    pub fn default_for(service: &service::Service) -> Option<Self> {
+        service.tcp_frontend().map(|tcp_frontend| Self {
+            name: service.name().to_string(),
+            enabled: tcp_frontend.enabled_by_default(),
+            ip: None,
+            port: Some(tcp_frontend.default_port()),
+            socket: None,
+            socket_mode: None,
+            routes: vec![],
+            token: None,
+            allow: vec![],
+            deny: vec![],
+            tls: None,
+            max_connections: None,
+            idle_timeout: None,
+            max_lifetime: None,
+            options: toml::Table::new(),
+        })
+    }
 }
 
 fn default_services() -> Vec<Service> {
     service::SERVICES
         .iter()
-        .map(|s| Service {
-            name: s.name().to_string(),
-            enabled: true,
-            ip: None,
-            port: s.tcp_frontend().map(service::TcpFrontend::default_port),
-        })
+        .copied()
+        .filter_map(Service::default_for)
         .collect()
 }
 
+/// Matches `name` against `pattern`, where `*` stands for any sequence of
+/// characters and `?` for any single one, ignoring case.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // last star met and the position in name it currently stands up to
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Profile {
+    /// Name of the connected server, `*` and `?` wildcards allowed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// Regular expression matching the whole name of the connected
+    /// server, instead of `server`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_regex: Option<String>,
+    /// Client networks allowed to use the services, instead of the
+    /// global list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>,
+    /// Client networks denied to use the services, instead of the global
+    /// list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deny: Option<Vec<String>>,
+    /// Entries replacing the ones of the same services
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<Service>,
+}
+
+impl Profile {
+    /// Pattern of the server names the profile applies to.
+    pub fn pattern(&self) -> &str {
+        self.server
+            .as_deref()
+            .or(self.server_regex.as_deref())
+            .unwrap_or_default()
+    }
+
+    fn regex(pattern: &str) -> Result<regex::Regex, Error> {
+        regex::RegexBuilder::new(&format!("^(?:{pattern})$"))
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| Error::InvalidProfile(format!("server_regex {pattern:?}: {e}")))
+    }
+
+    /// Checks that the profile has a single valid pattern.
+    pub fn check(&self) -> Result<(), Error> {
+        match (&self.server, &self.server_regex) {
+            (Some(_), None) => Ok(()),
+            (None, Some(pattern)) => Self::regex(pattern).map(|_| ()),
+            (Some(_), Some(_)) => Err(Error::InvalidProfile(
+                "both server and server_regex are set".into(),
+            )),
+            (None, None) => Err(Error::InvalidProfile(
+                "one of server or server_regex is required".into(),
+            )),
+        }
+    }
+
+    /// Whether the profile applies to the server `name`, case being
+    /// ignored.
+    fn matches(&self, name: &str) -> bool {
+        match (&self.server, &self.server_regex) {
+            (Some(pattern), _) => glob_match(pattern, name),
+            (None, Some(pattern)) => Self::regex(pattern).is_ok_and(|regex| regex.is_match(name)),
+            (None, None) => false,
+        }
+    }
+}
+
 static CONFIG_FILE_NAME: &str = "soxy.toml";
 
-#[derive(serde::Deserialize, serde::Serialize)]
+const ENV_CONFIG: &str = "SOXY_CONFIG";
+const ENV_CONFIG_READONLY: &str = "SOXY_CONFIG_READONLY";
+
+fn env_string(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+/// Comma separated values.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env_string(name).map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(ToString::to_string)
+            .collect()
+    })
+}
+
+fn env_parse<T: FromStr>(name: &str) -> Result<Option<T>, Error> {
+    match env_string(name) {
+        None => Ok(None),
+        Some(value) => match value.trim().parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(Error::InvalidValue(name.into(), value)),
+        },
+    }
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>, Error> {
+    match env_string(name) {
+        None => Ok(None),
+        Some(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(Some(true)),
+            "0" | "false" | "no" | "off" => Ok(Some(false)),
+            _ => Err(Error::InvalidValue(name.into(), value)),
+        },
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub(crate) struct Config {
     pub ip: String,
+    /// Client networks allowed to use the services
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Client networks denied to use the services
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+    /// Listens only while a backend answers on the virtual channel
+    #[serde(default)]
+    pub bind_on_connect: bool,
     #[serde(default)]
     pub log: Log,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Audit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub management: Option<Management>,
     #[serde(default = "default_services")]
     pub services: Vec<Service>,
+    /// Overrides applied while connected to matching servers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<Profile>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             ip: "127.0.0.1".into(),
+            allow: vec![],
+            deny: vec![],
+            bind_on_connect: false,
             log: Log::default(),
+            audit: None,
+            management: None,
             services: default_services(),
+            profiles: vec![],
         }
     }
 }
 
 impl Config {
-    pub fn read() -> Result<Option<Self>, Error> {
+    fn path() -> Result<path::PathBuf, Error> {
+        if let Some(path) = env::var_os(ENV_CONFIG) {
+            return Ok(path.into());
+        }
+
         let mut path = dirs::config_dir()
             .ok_or_else(|| Error::Io(io::Error::other("missing configuration directory")))?;
 
         path.push(CONFIG_FILE_NAME);
 
+        Ok(path)
+    }
+
+    /// Last modification time of the configuration file, if any.
+    pub fn modified() -> Option<time::SystemTime> {
+        Self::path().ok()?.metadata().ok()?.modified().ok()
+    }
+
+    pub fn read() -> Result<Option<Self>, Error> {
+        let path = Self::path()?;
+
         common::debug!("try to read configuration file at {:?}", path.display());
 
         if !path.exists() {
@@ -145,14 +502,106 @@ impl Config {
         let mut data = String::new();
         file.read_to_string(&mut data)?;
 
-        Ok(Some(Config::parse(&data)?))
+        let mut config = Config::parse(&data)?;
+        config.apply_env()?;
+
+        Ok(Some(config))
     }
 
-    pub fn save(&self) -> Result<(), Error> {
-        let mut path = dirs::config_dir()
-            .ok_or_else(|| Error::Io(io::Error::other("missing configuration directory")))?;
+    /// Whether a default configuration file may be written when there is
+    /// none.
+    pub fn writable() -> Result<bool, Error> {
+        Ok(!env_bool(ENV_CONFIG_READONLY)?.unwrap_or(false))
+    }
 
-        path.push(CONFIG_FILE_NAME);
+    /// Overrides the values set by the `SOXY_*` environment variables.
+    pub fn apply_env(&mut self) -> Result<(), Error> {
+        if let Some(ip) = env_string("SOXY_IP") {
+            self.ip = ip;
+        }
+        if let Some(allow) = env_list("SOXY_ALLOW") {
+            self.allow = allow;
+        }
+        if let Some(deny) = env_list("SOXY_DENY") {
+            self.deny = deny;
+        }
+        if let Some(bind_on_connect) = env_bool("SOXY_BIND_ON_CONNECT")? {
+            self.bind_on_connect = bind_on_connect;
+        }
+
+        if let Some(level) = env_string("SOXY_LOG_LEVEL") {
+            self.log.level = level;
+        }
+        if let Some(file) = env_string("SOXY_LOG_FILE") {
+            self.log.file = Some(file);
+        }
+        if let Some(format) = env_string("SOXY_LOG_FORMAT") {
+            self.log.format = Some(format);
+        }
+
+        // same variable as the backend
+        if let Some(file) = env_string("SOXY_AUDIT_LOG") {
+            self.audit = Some(Audit { file });
+        }
+
+        if let Some(port) = env_parse("SOXY_MANAGEMENT_PORT")? {
+            match &mut self.management {
+                Some(management) => management.port = port,
+                None => {
+                    self.management = Some(Management {
+                        ip: default_management_ip(),
+                        port,
+                        token: None,
+                    });
+                }
+            }
+        }
+        if let (Some(token), Some(management)) =
+            (env_string("SOXY_MANAGEMENT_TOKEN"), &mut self.management)
+        {
+            management.token = Some(token);
+        }
+
+        for service in service::SERVICES {
+            let prefix = format!("SOXY_{}_", service.name().to_ascii_uppercase());
+            let enabled = env_bool(&format!("{prefix}ENABLED"))?;
+            let ip = env_string(&format!("{prefix}IP"));
+            let port = env_parse(&format!("{prefix}PORT"))?;
+            let socket = env_string(&format!("{prefix}SOCKET"));
+            if enabled.is_none() && ip.is_none() && port.is_none() && socket.is_none() {
+                continue;
+            }
+
+            let entry = match self.services.iter().position(|s| s.name == service.name()) {
+                Some(index) => &mut self.services[index],
+                None => match Service::default_for(service) {
+                    Some(entry) => {
+                        self.services.push(entry);
+                        self.services.last_mut().unwrap()
+                    }
+                    None => continue,
+                },
+            };
+
+            if let Some(enabled) = enabled {
+                entry.enabled = enabled;
+            }
+            if ip.is_some() {
+                entry.ip = ip;
+            }
+            if port.is_some() {
+                entry.port = port;
+            }
+            if socket.is_some() {
+                entry.socket = socket;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Self::path()?;
 
         common::debug!("try to write configuration file at {:?}", path.display());
 
@@ -166,12 +615,70 @@ impl Config {
         Ok(write!(file, "{}", self.to_string(true)?)?)
     }
 
+    pub fn acl(&self) -> Result<acl::Acl, Error> {
+        Ok(acl::Acl::new(&self.allow, &self.deny)?)
+    }
+
+    /// Index of the first profile matching the name of the connected
+    /// server.
+    pub fn profile(&self, server: Option<&str>) -> Option<usize> {
+        let server = server?;
+        self.profiles
+            .iter()
+            .position(|profile| profile.matches(server))
+    }
+
+    /// Configuration with the overrides of the profile at `index`.
+    pub fn with_profile(&self, index: Option<usize>) -> Self {
+        let mut config = self.clone();
+        let Some(profile) = index.and_then(|index| self.profiles.get(index)) else {
+            return config;
+        };
+
+        if let Some(allow) = &profile.allow {
+            config.allow.clone_from(allow);
+        }
+        if let Some(deny) = &profile.deny {
+            config.deny.clone_from(deny);
+        }
+        for service in &profile.services {
+            match config.services.iter_mut().find(|s| s.name == service.name) {
+                Some(entry) => *entry = service.clone(),
+                None => config.services.push(service.clone()),
+            }
+        }
+
+        config
+    }
+
     pub fn log_level(&self) -> common::Level {
         common::Level::try_from(self.log.level.as_str()).unwrap_or(common::Level::Info)
     }
 
-    pub fn log_file(&self) -> Option<&String> {
-        self.log.file.as_ref()
+    pub fn log_file(&self) -> Option<common::LogFile> {
+        let path = self.log.file.clone()?;
+        let format = self
+            .log
+            .format
+            .as_deref()
+            .and_then(|format| common::LogFormat::try_from(format).ok())
+            .unwrap_or(common::LogFormat::Text);
+
+        Some(common::LogFile {
+            path,
+            format,
+            max_size: self.log.max_size.filter(|max_size| 0 < *max_size),
+            rotate_interval: self
+                .log
+                .rotate_interval
+                .filter(|interval| 0 < *interval)
+                .map(time::Duration::from_secs),
+            max_files: self.log.max_files.unwrap_or(DEFAULT_LOG_MAX_FILES),
+        })
+    }
+
+    pub fn audit_file(&self) -> Option<&str> {
+        self.audit.as_ref().map(|audit| audit.file.as_str())
     }
 
     fn parse(config: &str) -> Result<Self, Error> {
@@ -186,3 +693,111 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Error, Profile, glob_match};
+
+    fn profile(server: Option<&str>, server_regex: Option<&str>) -> Profile {
+        Profile {
+            server: server.map(ToString::to_string),
+            server_regex: server_regex.map(ToString::to_string),
+            allow: None,
+            deny: None,
+            services: vec![],
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("vdi-*.example.org", "vdi-42.example.org"));
+        assert!(glob_match("VDI-*", "vdi-42.Example.org"));
+        assert!(glob_match("vdi-??", "vdi-42"));
+        assert!(!glob_match("vdi-??", "vdi-420"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**", "any"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "any"));
+        assert!(!glob_match("?", ""));
+        // backtracking over a partial match
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("*a*b*c", "xaxbxbxc"));
+        assert!(!glob_match("*a*b*c", "xaxcxb"));
+        // no special meaning besides the wildcards
+        assert!(!glob_match("vdi.[0-9]", "vdi.1"));
+        assert!(glob_match("vdi.[0-9]", "VDI.[0-9]"));
+    }
+
+    #[test]
+    fn matches() {
+        let glob = profile(Some("vdi-*.example.org"), None);
+        assert!(glob.matches("VDI-1.example.org"));
+        assert!(!glob.matches("vdi-1.example.org.evil"));
+
+        let regex = profile(None, Some(r"vdi-[0-9]+\.example\.org"));
+        assert!(regex.matches("vdi-42.example.org"));
+        assert!(regex.matches("VDI-42.EXAMPLE.ORG"));
+        // anchored, even with alternatives
+        assert!(!regex.matches("vdi-42.example.org.evil"));
+        assert!(!regex.matches("my-vdi-42.example.org"));
+        let alternatives = profile(None, Some("vdi-1|vdi-2"));
+        assert!(alternatives.matches("vdi-2"));
+        assert!(!alternatives.matches("vdi-10"));
+        assert!(!alternatives.matches("xvdi-1"));
+
+        assert!(!profile(None, Some("vdi-[")).matches("vdi-["));
+        assert!(!profile(None, None).matches("vdi-1"));
+    }
+
+    #[test]
+    fn check() {
+        assert!(profile(Some("vdi-*"), None).check().is_ok());
+        assert!(profile(None, Some("vdi-.*")).check().is_ok());
+        assert!(matches!(
+            profile(None, Some("vdi-[")).check(),
+            Err(Error::InvalidProfile(_))
+        ));
+        assert!(matches!(
+            profile(Some("vdi-*"), Some("vdi-.*")).check(),
+            Err(Error::InvalidProfile(_))
+        ));
+        assert!(matches!(
+            profile(None, None).check(),
+            Err(Error::InvalidProfile(_))
+        ));
+    }
+
+    #[test]
+    fn profiles() {
+        let config = Config::parse(
+            r#"
+            ip = "127.0.0.1"
+            allow = ["10.0.0.0/8"]
+
+            [[profiles]]
+            server_regex = 'vdi-[0-9]+\.example\.org'
+            allow = ["192.168.0.0/16"]
+
+            [[profiles]]
+            server = "*.example.org"
+            deny = ["10.1.0.0/16"]
+            "#,
+        )
+        .ok()
+        .unwrap();
+
+        assert_eq!(config.profile(None), None);
+        assert_eq!(config.profile(Some("vdi-1.example.org")), Some(0));
+        // the first matching profile applies
+        assert_eq!(config.profile(Some("www.example.org")), Some(1));
+        assert_eq!(config.profile(Some("vdi-1.example.com")), None);
+
+        let vdi = config.with_profile(Some(0));
+        assert_eq!(vdi.allow, ["192.168.0.0/16"]);
+        assert!(vdi.deny.is_empty());
+        let www = config.with_profile(Some(1));
+        assert_eq!(www.allow, ["10.0.0.0/8"]);
+        assert_eq!(www.deny, ["10.1.0.0/16"]);
+        assert_eq!(config.with_profile(None).allow, ["10.0.0.0/8"]);
+    }
+}
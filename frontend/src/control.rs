@@ -1,13 +1,12 @@
 use crate::svc;
 use common::api;
-use std::{mem, sync, thread};
+use std::{mem, thread};
 
 const TO_SVC_CHANNEL_SIZE: usize = 256;
 const FRONTEND_CHANNEL_SIZE: usize = 1;
 
 #[derive(Clone)]
 pub struct Control {
-    state: sync::Arc<sync::RwLock<svc::State>>,
     frontend_input: crossbeam_channel::Receiver<api::ChunkControl>,
     frontend_output: crossbeam_channel::Sender<api::ChunkControl>,
     svc_input: crossbeam_channel::Receiver<svc::Response>,
@@ -30,9 +29,10 @@ impl Control {
         let (to_frontend_sender, to_frontend_receiver) =
             crossbeam_channel::bounded(FRONTEND_CHANNEL_SIZE);
 
+        *svc::STATE.write().unwrap() = Some(svc::State::Disconnected);
+
         (
             Self {
-                state: sync::Arc::new(sync::RwLock::new(svc::State::Disconnected)),
                 frontend_input: from_frontend_receiver,
                 frontend_output: to_frontend_sender,
                 svc_input: from_svc_receiver,
@@ -50,16 +50,25 @@ impl Control {
         loop {
             match self.svc_input.recv()? {
                 svc::Response::ChangeState(new_state) => {
-                    let mut state = self.state.write().unwrap();
-                    common::info!("change state from \"{state:?}\" to \"{new_state:?}\"");
-                    *state = new_state.clone();
+                    if let Some(state) = svc::STATE.write().unwrap().replace(new_state.clone()) {
+                        common::info!("change state from \"{state:?}\" to \"{new_state:?}\"");
+                    }
                     match new_state {
                         svc::State::Initialized => (),
                         svc::State::Connected(name) => {
                             common::info!("connected to {name:?}");
+                            if let Some(services) = crate::SERVICES.get() {
+                                services.connected(name.as_deref());
+                            }
                             self.svc_output.send(svc::Command::Open)?;
+                            if let Some(services) = crate::SERVICES.get() {
+                                services.probe_now();
+                            }
                         }
                         svc::State::Disconnected | svc::State::Terminated => {
+                            if let Some(services) = crate::SERVICES.get() {
+                                services.set_backend(false);
+                            }
                             self.frontend_output.send(api::ChunkControl::Shutdown)?;
                             self.svc_output.send(svc::Command::Open)?;
                         }
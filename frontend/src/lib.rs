@@ -1,14 +1,17 @@
-use common::{api, service};
-use std::{fmt, io, net, str::FromStr, sync, thread};
+use common::{api, audit, service};
+use std::{fmt, io, sync, thread};
 
 mod config;
 mod control;
+mod management;
+mod services;
 mod svc;
 #[cfg(target_os = "windows")]
 mod windows;
 
 pub enum Error {
     Api(api::Error),
+    Audit(audit::Error),
     Config(config::Error),
     Io(io::Error),
     PipelineBroken,
@@ -19,6 +22,7 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             Self::Api(e) => write!(f, "API error: {e}"),
+            Self::Audit(e) => write!(f, "audit log error: {e}"),
             Self::Config(e) => write!(f, "configuration error: {e}"),
             Self::Io(e) => write!(f, "I/O error: {e}"),
             Self::PipelineBroken => write!(f, "broken pipeline"),
@@ -33,6 +37,12 @@ impl From<api::Error> for Error {
     }
 }
 
+impl From<audit::Error> for Error {
+    fn from(e: audit::Error) -> Self {
+        Self::Audit(e)
+    }
+}
+
 impl From<config::Error> for Error {
     fn from(e: config::Error) -> Self {
         Self::Config(e)
@@ -66,6 +76,9 @@ impl From<svc::Error> for Error {
 pub(crate) static SVC_TO_CONTROL: sync::OnceLock<crossbeam_channel::Sender<svc::Response>> =
     sync::OnceLock::new();
 
+// told about the connected server, to apply the matching profile
+pub(crate) static SERVICES: sync::OnceLock<sync::Arc<services::Services>> = sync::OnceLock::new();
+
 fn svc_commander(control: &crossbeam_channel::Receiver<svc::Command>) -> Result<(), Error> {
     loop {
         match control.recv()? {
@@ -105,69 +118,48 @@ pub fn init(
     frontend_channel: service::Channel,
     backend_to_frontend: crossbeam_channel::Receiver<api::ChunkControl>,
 ) -> Result<(), Error> {
-    let config = match config::Config::read()? {
+    let mut config = match config::Config::read()? {
         None => {
-            let config = config::Config::default();
-            config.save()?;
+            let mut config = config::Config::default();
+            if config::Config::writable()? {
+                config.save()?;
+            }
+            config.apply_env()?;
             config
         }
         Some(config) => config,
     };
 
-    common::init_logs(config.log_level(), config.log_file());
+    common::init_logs(config.log_level(), config.log_file().as_ref());
+
+    if let Some(file) = config.audit_file() {
+        audit::init(file)?;
+    }
 
     common::debug!("initializing frontend");
 
-    let servers = config.services.into_iter().filter(|s| s.enabled).try_fold(
-        vec![],
-        |mut servers, service| {
-            let ip = net::IpAddr::from_str(&service.ip.unwrap_or(config.ip.clone()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
-            let port = service.port;
-            let service = service::lookup(service.name.as_str())
-                .ok_or(Error::Config(config::Error::UnknownService(service.name)))?;
-            let port = port
-                .or(service
-                    .tcp_frontend()
-                    .map(service::TcpFrontend::default_port))
-                .ok_or(Error::Config(config::Error::UnknownService(
-                    service.name().to_string(),
-                )))?;
-
-            let sockaddr = net::SocketAddr::new(ip, port);
-            let server = common::service::TcpFrontendServer::bind(service, sockaddr)?;
-
-            servers.push(server);
-
-            Ok::<_, Error>(servers)
-        },
-    )?;
+    let frontend_channel = sync::Arc::new(frontend_channel);
+    let management = config.management.take();
+    let services = sync::Arc::new(services::Services::new(frontend_channel.clone(), config));
+
+    services.start()?;
+    services::watch(services.clone());
+    let _ = SERVICES.set(services.clone());
+
+    services::probe(services.clone());
+
+    if let Some(management) = management {
+        management::start(&management, services)?;
+    }
 
     thread::Builder::new()
         .name("frontend".into())
         .spawn(move || {
-            thread::scope(|scope| {
-                for server in &servers {
-                    thread::Builder::new()
-                        .name(server.service().name().to_string())
-                        .spawn_scoped(scope, || {
-                            if let Err(e) = server.start(&frontend_channel) {
-                                common::error!("{} error: {e}", server.service().name());
-                            } else {
-                                common::debug!("{} terminated", server.service().name());
-                            }
-                        })
-                        .unwrap();
-                }
-
-                if let Err(e) =
-                    frontend_channel.start(service::Kind::Frontend, &backend_to_frontend)
-                {
-                    common::error!("frontend error: {e}");
-                } else {
-                    common::debug!("frontend terminated");
-                }
-            });
+            if let Err(e) = frontend_channel.start(service::Kind::Frontend, &backend_to_frontend) {
+                common::error!("frontend error: {e}");
+            } else {
+                common::debug!("frontend terminated");
+            }
         })
         .unwrap();
 
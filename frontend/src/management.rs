@@ -0,0 +1,224 @@
+//! Local endpoint controlling the frontend at runtime. Each request is a
+//! JSON object on its own line, answered by a JSON object on its own
+//! line.
+
+use crate::{Error, config, services, svc};
+use common::service;
+use std::{
+    io::{self, BufRead, Write},
+    net,
+    str::FromStr,
+    sync, thread,
+};
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    Services,
+    Enable {
+        service: String,
+    },
+    Disable {
+        service: String,
+    },
+    Listen {
+        service: String,
+        #[serde(default)]
+        ip: Option<String>,
+        #[serde(default)]
+        port: Option<u16>,
+    },
+    LogLevel {
+        level: String,
+    },
+    State,
+    Streams,
+    Close {
+        /// Hexadecimal, as in the logs
+        client_id: String,
+    },
+}
+
+#[derive(serde::Deserialize)]
+struct Request {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+fn failure(error: &str) -> serde_json::Value {
+    serde_json::json!({ "ok": false, "error": error })
+}
+
+fn success() -> serde_json::Value {
+    serde_json::json!({ "ok": true })
+}
+
+fn from_result(result: Result<(), Error>) -> serde_json::Value {
+    result.map_or_else(|e| failure(&e.to_string()), |()| success())
+}
+
+fn state() -> serde_json::Value {
+    let (state, server) = match svc::STATE.read().unwrap().as_ref() {
+        None => (None, None),
+        Some(svc::State::Initialized) => (Some("initialized"), None),
+        Some(svc::State::Connected(server)) => (Some("connected"), server.clone()),
+        Some(svc::State::Disconnected) => (Some("disconnected"), None),
+        Some(svc::State::Terminated) => (Some("terminated"), None),
+    };
+    serde_json::json!({ "ok": true, "state": state, "server": server })
+}
+
+fn execute(services: &services::Services, command: Command) -> serde_json::Value {
+    match command {
+        Command::Services => {
+            let services: Vec<_> = services
+                .status()
+                .into_iter()
+                .map(|status| {
+                    serde_json::json!({
+                        "name": status.name,
+                        "enabled": status.enabled,
+                        "tcp": status
+                            .listen
+                            .as_ref()
+                            .and_then(service::ListenAddr::socket_addr)
+                            .map(|addr| addr.to_string()),
+                        "unix": status.listen.as_ref().and_then(|listen| match listen {
+                            service::ListenAddr::Tcp(_) => None,
+                            #[cfg(unix)]
+                            service::ListenAddr::Unix(path) => Some(path.display().to_string()),
+                        }),
+                        "udp": status.udp.map(|addr| addr.to_string()),
+                    })
+                })
+                .collect();
+            serde_json::json!({ "ok": true, "services": services })
+        }
+        Command::Enable { service } => from_result(services.enable(&service)),
+        Command::Disable { service } => from_result(services.disable(&service)),
+        Command::Listen { service, ip, port } => from_result(services.listen(&service, ip, port)),
+        Command::LogLevel { level } => match common::Level::try_from(level.as_str()) {
+            Ok(new_level) => {
+                common::set_log_level(new_level);
+                common::info!("log level set to {level}");
+                success()
+            }
+            Err(e) => failure(&format!("{e} {level:?}")),
+        },
+        Command::State => state(),
+        Command::Streams => {
+            let streams: Vec<_> = services
+                .channel()
+                .streams()
+                .into_iter()
+                .map(|(client_id, service)| {
+                    serde_json::json!({
+                        "client_id": format!("{client_id:x}"),
+                        "service": service,
+                    })
+                })
+                .collect();
+            serde_json::json!({ "ok": true, "streams": streams })
+        }
+        Command::Close { client_id } => match common::api::ClientId::from_str_radix(&client_id, 16)
+        {
+            Ok(client_id) if services.channel().close(client_id) => success(),
+            Ok(_) => failure(&format!("no stream {client_id}")),
+            Err(e) => failure(&format!("invalid client ID {client_id:?}: {e}")),
+        },
+    }
+}
+
+fn handle_client(
+    stream: &net::TcpStream,
+    token: &str,
+    services: &services::Services,
+) -> Result<(), io::Error> {
+    let mut writer = io::BufWriter::new(stream);
+
+    for line in io::BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Err(e) => failure(&format!("invalid request: {e}")),
+            Ok(request) => {
+                let authorized = request.token.as_ref().is_some_and(|given| {
+                    common::auth::constant_time_eq(token.as_bytes(), given.as_bytes())
+                });
+                if authorized {
+                    execute(services, request.command)
+                } else {
+                    common::error!("invalid token");
+                    thread::sleep(common::auth::FAILURE_DELAY);
+                    failure("invalid token")
+                }
+            }
+        };
+
+        writeln!(writer, "{response}")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Listens on the configured loopback address. A token is required, as
+/// any local user can connect.
+pub(crate) fn start(
+    config: &config::Management,
+    services: sync::Arc<services::Services>,
+) -> Result<(), Error> {
+    let ip = net::IpAddr::from_str(&config.ip)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if !ip.is_loopback() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("management address {ip} is not a loopback one"),
+        )));
+    }
+
+    let Some(token) = config.token.clone() else {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "management requires a token",
+        )));
+    };
+
+    let server = net::TcpListener::bind(net::SocketAddr::new(ip, config.port))?;
+    common::info!("management listening on {}", server.local_addr()?);
+
+    thread::Builder::new()
+        .name("management".into())
+        .spawn(move || {
+            for stream in server.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        common::error!("management accept error: {e}");
+                        continue;
+                    }
+                };
+                let services = services.clone();
+                let token = token.clone();
+                let name = stream
+                    .peer_addr()
+                    .map_or_else(|_| "management".into(), |peer| format!("management {peer}"));
+                thread::Builder::new()
+                    .name(name)
+                    .spawn(move || {
+                        if let Err(e) = handle_client(&stream, &token, &services) {
+                            common::debug!("error: {e}");
+                        }
+                    })
+                    .unwrap();
+            }
+        })
+        .unwrap();
+
+    Ok(())
+}
@@ -0,0 +1,671 @@
+//! Listeners of the services, which can be started, stopped and moved
+//! while the frontend is running.
+
+use crate::{Error, config, svc};
+use common::service;
+#[cfg(unix)]
+use std::path;
+use std::{
+    collections::HashMap,
+    io, net,
+    str::FromStr,
+    sync::{self, atomic},
+    thread, time,
+};
+
+#[derive(Default)]
+struct Servers {
+    tcp: Option<sync::Arc<service::TcpFrontendServer>>,
+    udp: Option<sync::Arc<service::UdpFrontendServer>>,
+    #[cfg(target_os = "linux")]
+    tun: Option<sync::Arc<service::TunFrontendServer>>,
+}
+
+impl Servers {
+    fn bind(
+        config: &config::Config,
+        acl: &common::acl::Acl,
+        service_config: &config::Service,
+    ) -> Result<Self, Error> {
+        let mut servers = Self::default();
+
+        let service = service::lookup(service_config.name.as_str()).ok_or_else(|| {
+            Error::Config(config::Error::UnknownService(service_config.name.clone()))
+        })?;
+
+        let options = service_config.options(service)?;
+        let token = service_config.token(service)?;
+
+        #[cfg(target_os = "linux")]
+        if service.tun_frontend().is_some() {
+            let server = service::TunFrontendServer::bind(service, &service_config.routes)?;
+            servers.tun = Some(sync::Arc::new(server));
+            return Ok(servers);
+        }
+
+        let service_acl = service_config.acl(acl)?;
+        let tls = service_config.tls()?;
+        let limits = service_config.limits();
+
+        let server = if let Some(socket) = &service_config.socket {
+            bind_unix(service, service_config, socket)?
+        } else {
+            let ip = net::IpAddr::from_str(service_config.ip.as_ref().unwrap_or(&config.ip))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            let port = service_config
+                .port
+                .or(service
+                    .tcp_frontend()
+                    .map(service::TcpFrontend::default_port))
+                .ok_or(Error::Config(config::Error::UnknownService(
+                    service.name().to_string(),
+                )))?;
+
+            service::TcpFrontendServer::bind(service, net::SocketAddr::new(ip, port))?
+        };
+
+        let server = server
+            .with_acl(service_acl.clone())
+            .with_token(token)
+            .with_tls(tls)
+            .with_limits(limits)
+            .with_options(options);
+
+        // the UDP frontend shares the port actually bound over TCP, there
+        // is none next to a Unix domain socket
+        if let (Some(_), Some(addr)) = (service.udp_frontend(), server.local_addr().socket_addr()) {
+            let server = service::UdpFrontendServer::bind(service, addr)?
+                .with_acl(service_acl)
+                .with_limits(limits);
+            servers.udp = Some(sync::Arc::new(server));
+        }
+
+        servers.tcp = Some(sync::Arc::new(server));
+
+        Ok(servers)
+    }
+
+    fn spawn(&self, channel: &sync::Arc<service::Channel>) {
+        if let Some(server) = &self.tcp {
+            let server = server.clone();
+            let channel = channel.clone();
+            thread::Builder::new()
+                .name(server.service().name().to_string())
+                .spawn(move || {
+                    if let Err(e) = server.start(&channel) {
+                        common::error!("{} error: {e}", server.service().name());
+                    } else {
+                        common::debug!("{} terminated", server.service().name());
+                    }
+                })
+                .unwrap();
+        }
+
+        if let Some(server) = &self.udp {
+            let server = server.clone();
+            let channel = channel.clone();
+            thread::Builder::new()
+                .name(format!("{} udp", server.service().name()))
+                .spawn(move || {
+                    if let Err(e) = server.start(&channel) {
+                        common::error!("{} error: {e}", server.service().name());
+                    } else {
+                        common::debug!("{} terminated", server.service().name());
+                    }
+                })
+                .unwrap();
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(server) = &self.tun {
+            let server = server.clone();
+            let channel = channel.clone();
+            thread::Builder::new()
+                .name(format!("{} tun", server.service().name()))
+                .spawn(move || {
+                    if let Err(e) = server.start(&channel) {
+                        common::error!("{} error: {e}", server.service().name());
+                    } else {
+                        common::debug!("{} terminated", server.service().name());
+                    }
+                })
+                .unwrap();
+        }
+    }
+
+    /// Stops listening, the streams already accepted are kept.
+    fn stop(&self) {
+        if let Some(server) = &self.tcp {
+            server.stop();
+        }
+        if let Some(server) = &self.udp {
+            server.stop();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(server) = &self.tun {
+            server.stop();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(
+    service: &'static service::Service,
+    service_config: &config::Service,
+    socket: &str,
+) -> Result<service::TcpFrontendServer, Error> {
+    if service_config.tls.is_some() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{service} cannot use TLS on a Unix domain socket"),
+        )));
+    }
+
+    Ok(service::TcpFrontendServer::bind_unix(
+        service,
+        path::Path::new(socket),
+        service_config.socket_mode(),
+    )?)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(
+    _service: &'static service::Service,
+    _service_config: &config::Service,
+    _socket: &str,
+) -> Result<service::TcpFrontendServer, Error> {
+    Err(Error::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported",
+    )))
+}
+
+pub(crate) struct Status {
+    pub name: &'static str,
+    pub enabled: bool,
+    pub listen: Option<service::ListenAddr>,
+    pub udp: Option<net::SocketAddr>,
+}
+
+/// Running services. Changes are not written back to the configuration
+/// file, they are replaced by the ones of the file when it changes or
+/// when another profile applies.
+pub(crate) struct Services {
+    channel: sync::Arc<service::Channel>,
+    // as read from the file, locked before server
+    file: sync::Mutex<config::Config>,
+    // name of the connected server, locked before config
+    server: sync::Mutex<Option<String>>,
+    // applied one, locked before running
+    config: sync::Mutex<config::Config>,
+    running: sync::Mutex<HashMap<&'static str, Servers>>,
+    // a backend answered on the virtual channel
+    backend: atomic::AtomicBool,
+    // wakes the probing of the backend up
+    probe_now: (
+        crossbeam_channel::Sender<()>,
+        crossbeam_channel::Receiver<()>,
+    ),
+}
+
+fn lookup(name: &str) -> Result<&'static service::Service, Error> {
+    service::lookup(name).ok_or_else(|| Error::Config(config::Error::UnknownService(name.into())))
+}
+
+/// Checks the configuration as applied with each of its profiles.
+fn check(config: &config::Config) -> Result<(), Error> {
+    for profile in &config.profiles {
+        profile.check()?;
+    }
+    for index in (0..config.profiles.len()).map(Some).chain([None]) {
+        let config = config.with_profile(index);
+        config.acl()?;
+        for service_config in &config.services {
+            service_config.options(lookup(&service_config.name)?)?;
+        }
+    }
+    Ok(())
+}
+
+impl Services {
+    pub(crate) fn new(channel: sync::Arc<service::Channel>, config: config::Config) -> Self {
+        Self {
+            channel,
+            config: sync::Mutex::new(config.with_profile(None)),
+            file: sync::Mutex::new(config),
+            server: sync::Mutex::new(None),
+            running: sync::Mutex::new(HashMap::new()),
+            backend: atomic::AtomicBool::new(false),
+            probe_now: crossbeam_channel::bounded(1),
+        }
+    }
+
+    /// Whether the enabled services are listening with `config`.
+    fn listening(&self, config: &config::Config) -> bool {
+        !config.bind_on_connect || self.backend.load(atomic::Ordering::Acquire)
+    }
+
+    pub(crate) fn channel(&self) -> &service::Channel {
+        &self.channel
+    }
+
+    /// Starts the enabled services, once all of them are bound.
+    pub(crate) fn start(&self) -> Result<(), Error> {
+        check(&self.file.lock().unwrap())?;
+
+        let config = self.config.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+
+        if !self.listening(&config) {
+            common::info!("listening once a backend answers");
+            return Ok(());
+        }
+
+        let acl = config.acl()?;
+
+        let mut bound = vec![];
+        for service in config.services.iter().filter(|s| s.enabled) {
+            let servers = Servers::bind(&config, &acl, service)?;
+            bound.push((lookup(&service.name)?.name(), servers));
+        }
+
+        for (name, servers) in bound {
+            servers.spawn(&self.channel);
+            running.insert(name, servers);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn status(&self) -> Vec<Status> {
+        let running = self.running.lock().unwrap();
+
+        service::SERVICES
+            .iter()
+            .filter(|service| {
+                #[cfg(target_os = "linux")]
+                if service.tun_frontend().is_some() {
+                    return true;
+                }
+                service.tcp_frontend().is_some()
+            })
+            .map(|service| {
+                let servers = running.get(service.name());
+                Status {
+                    name: service.name(),
+                    enabled: servers.is_some(),
+                    listen: servers
+                        .and_then(|servers| servers.tcp.as_ref())
+                        .map(|server| server.local_addr().clone()),
+                    udp: servers
+                        .and_then(|servers| servers.udp.as_ref())
+                        .map(|server| server.local_addr()),
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn enable(&self, name: &str) -> Result<(), Error> {
+        let service = lookup(name)?;
+
+        let mut config = self.config.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+
+        if running.contains_key(service.name()) {
+            return Ok(());
+        }
+
+        let index = if let Some(index) = config.services.iter().position(|s| s.name == name) {
+            index
+        } else {
+            let service_config = config::Service::default_for(service).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{name} has no default configuration"),
+                )
+            })?;
+            config.services.push(service_config);
+            config.services.len() - 1
+        };
+
+        if !self.listening(&config) {
+            config.services[index].enabled = true;
+            common::info!("{name} enabled, listening once a backend answers");
+            return Ok(());
+        }
+
+        let acl = config.acl()?;
+        let servers = Servers::bind(&config, &acl, &config.services[index])?;
+        servers.spawn(&self.channel);
+        running.insert(service.name(), servers);
+        config.services[index].enabled = true;
+
+        common::info!("{name} enabled");
+
+        Ok(())
+    }
+
+    /// Stops the service and closes its streams.
+    pub(crate) fn disable(&self, name: &str) -> Result<(), Error> {
+        let service = lookup(name)?;
+
+        let mut config = self.config.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+
+        if let Some(servers) = running.remove(service.name()) {
+            servers.stop();
+            self.channel.close_service(service);
+            common::info!("{name} disabled");
+        }
+
+        if let Some(service_config) = config.services.iter_mut().find(|s| s.name == name) {
+            service_config.enabled = false;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the listeners of the service, the streams already accepted
+    /// are kept. On failure the previous address is restored.
+    pub(crate) fn listen(
+        &self,
+        name: &str,
+        ip: Option<String>,
+        port: Option<u16>,
+    ) -> Result<(), Error> {
+        let service = lookup(name)?;
+        if service.tcp_frontend().is_none() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} does not listen on TCP"),
+            )));
+        }
+        if let Some(ip) = &ip {
+            net::IpAddr::from_str(ip)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        }
+
+        let mut config = self.config.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+
+        let index = config
+            .services
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{name} disabled")))?;
+        if config.services[index].socket.is_some() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} listens on a Unix domain socket"),
+            )));
+        }
+
+        let previous = config.services[index].clone();
+        if ip.is_some() {
+            config.services[index].ip = ip;
+        }
+        if port.is_some() {
+            config.services[index].port = port;
+        }
+
+        if !running.contains_key(service.name()) {
+            return Ok(());
+        }
+
+        let result = self.rebind(
+            &mut running,
+            service,
+            (&config, &config.services[index]),
+            Some((&config, &previous)),
+        );
+        if result.is_ok() {
+            common::info!("{name} moved");
+        } else {
+            config.services[index] = previous;
+        }
+        result
+    }
+
+    /// Stops the running servers of the service and binds new ones, or
+    /// the `previous` ones if that fails.
+    fn rebind(
+        &self,
+        running: &mut HashMap<&'static str, Servers>,
+        service: &'static service::Service,
+        (config, service_config): (&config::Config, &config::Service),
+        previous: Option<(&config::Config, &config::Service)>,
+    ) -> Result<(), Error> {
+        if let Some(servers) = running.remove(service.name()) {
+            servers.stop();
+        }
+
+        let bind = |config: &config::Config, service_config| {
+            Servers::bind(config, &config.acl()?, service_config)
+        };
+
+        match bind(config, service_config) {
+            Ok(servers) => {
+                servers.spawn(&self.channel);
+                running.insert(service.name(), servers);
+                Ok(())
+            }
+            Err(e) => {
+                if let Some((config, service_config)) = previous {
+                    match bind(config, service_config) {
+                        Ok(servers) => {
+                            servers.spawn(&self.channel);
+                            running.insert(service.name(), servers);
+                        }
+                        Err(e) => {
+                            common::error!("failed to restore {service}: {e}");
+                        }
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies a new configuration file, with the profile matching the
+    /// connected server.
+    pub(crate) fn reload(&self, new_config: config::Config) -> Result<(), Error> {
+        check(&new_config)?;
+
+        let mut file = self.file.lock().unwrap();
+        let server = self.server.lock().unwrap();
+
+        self.apply(new_config.with_profile(new_config.profile(server.as_deref())));
+        *file = new_config;
+
+        Ok(())
+    }
+
+    /// Applies the profile matching the server the frontend is now
+    /// connected to, if it is not already applied.
+    pub(crate) fn connected(&self, server: Option<&str>) {
+        let file = self.file.lock().unwrap();
+        let mut current = self.server.lock().unwrap();
+
+        let (previous, next) = (file.profile(current.as_deref()), file.profile(server));
+        *current = server.map(ToString::to_string);
+        if previous == next {
+            return;
+        }
+
+        match next {
+            Some(index) => {
+                common::info!("applying profile {:?}", file.profiles[index].pattern());
+            }
+            None => {
+                common::info!("no profile for {server:?}, applying the default one");
+            }
+        }
+        self.apply(file.with_profile(next));
+    }
+
+    /// Applies a checked configuration: newly enabled services are
+    /// started, disabled ones are stopped and changed ones are bound
+    /// again. Open streams are kept.
+    fn apply(&self, new_config: config::Config) {
+        let mut config = self.config.lock().unwrap();
+        let mut running = self.running.lock().unwrap();
+
+        common::set_log_level(new_config.log_level());
+
+        let listening = self.listening(&new_config);
+        let global_changed = config.ip != new_config.ip
+            || config.allow != new_config.allow
+            || config.deny != new_config.deny;
+
+        for service in service::SERVICES {
+            let name = service.name();
+            let enabled = |config: &'_ config::Config| {
+                config
+                    .services
+                    .iter()
+                    .find(|s| s.enabled && s.name == name)
+                    .cloned()
+            };
+            let (previous, next) = (enabled(&config), enabled(&new_config));
+            let waiting = next.is_some() && !listening;
+
+            match next.filter(|_| listening) {
+                None => {
+                    if let Some(servers) = running.remove(name) {
+                        servers.stop();
+                        if waiting {
+                            common::info!("{name} stopped until a backend answers");
+                        } else {
+                            common::info!("{name} disabled");
+                        }
+                    }
+                }
+                Some(next) => {
+                    let is_running = running.contains_key(name);
+                    if is_running && !global_changed && previous.as_ref() == Some(&next) {
+                        continue;
+                    }
+                    let previous = previous.as_ref().filter(|_| is_running);
+                    if let Err(e) = self.rebind(
+                        &mut running,
+                        service,
+                        (&new_config, &next),
+                        previous.map(|previous| (&*config, previous)),
+                    ) {
+                        common::error!("failed to apply {name} configuration: {e}");
+                    } else if is_running {
+                        common::info!("{name} updated");
+                    } else {
+                        common::info!("{name} enabled");
+                    }
+                }
+            }
+        }
+
+        *config = new_config;
+    }
+
+    /// Probes the backend without waiting for the next periodic probe.
+    pub(crate) fn probe_now(&self) {
+        let _ = self.probe_now.0.try_send(());
+    }
+
+    /// Records whether a backend answers on the virtual channel, binding
+    /// or stopping the listeners accordingly with `bind_on_connect`.
+    pub(crate) fn set_backend(&self, answers: bool) {
+        if self.backend.swap(answers, atomic::Ordering::AcqRel) == answers {
+            return;
+        }
+
+        if answers {
+            common::info!("backend answered");
+        } else {
+            common::info!("backend no longer answers");
+        }
+
+        let config = self.config.lock().unwrap().clone();
+        if config.bind_on_connect {
+            self.apply(config);
+        }
+    }
+}
+
+const PROBE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+const PROBE_INTERVAL: time::Duration = time::Duration::from_secs(2);
+// answers are delayed by the streams sharing the channel
+const PROBE_FAILURES: usize = 3;
+
+fn connected() -> bool {
+    // unset when running standalone, next to the backend
+    matches!(
+        *svc::STATE.read().unwrap(),
+        None | Some(svc::State::Connected(_))
+    )
+}
+
+/// Probes the backend while the virtual channel is connected until it
+/// answers, then periodically with `bind_on_connect` since the backend
+/// can exit while the channel stays connected. It is deemed gone after a
+/// few unanswered probes.
+pub(crate) fn probe(services: sync::Arc<Services>) {
+    let wake = services.probe_now.1.clone();
+    thread::Builder::new()
+        .name("probe".into())
+        .spawn(move || {
+            let mut failures = 0;
+            loop {
+                let watch = services.config.lock().unwrap().bind_on_connect;
+                if !connected() {
+                    failures = 0;
+                } else if !watch && services.channel.knows_backend() {
+                    // nothing to learn
+                } else if services.channel.probe(PROBE_TIMEOUT) {
+                    failures = 0;
+                    services.set_backend(true);
+                } else {
+                    failures += 1;
+                    if PROBE_FAILURES <= failures {
+                        services.set_backend(false);
+                    }
+                }
+                let _ = wake.recv_timeout(PROBE_INTERVAL);
+            }
+        })
+        .unwrap();
+}
+
+const WATCH_INTERVAL: time::Duration = time::Duration::from_secs(2);
+
+/// Applies the changes of the configuration file as soon as they are
+/// noticed.
+pub(crate) fn watch(services: sync::Arc<Services>) {
+    thread::Builder::new()
+        .name("config".into())
+        .spawn(move || {
+            let mut modified = config::Config::modified();
+            loop {
+                thread::sleep(WATCH_INTERVAL);
+
+                let current = config::Config::modified();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match config::Config::read() {
+                    Ok(None) => (),
+                    Ok(Some(config)) => {
+                        common::info!("configuration file changed");
+                        if let Err(e) = services.reload(config) {
+                            common::error!("configuration not applied: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        common::error!("configuration not applied: {e}");
+                    }
+                }
+            }
+        })
+        .unwrap();
+}
@@ -75,4 +75,7 @@ impl Svc {
 
 pub static SVC: sync::RwLock<Option<Svc>> = sync::RwLock::new(None);
 
+/// State of the virtual channel, unset when running standalone.
+pub static STATE: sync::RwLock<Option<State>> = sync::RwLock::new(None);
+
 const MAX_CHUNKS_IN_FLIGHT: usize = 64;
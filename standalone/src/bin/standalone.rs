@@ -1,4 +1,4 @@
-use common::service;
+use common::{audit, connector, limits, policy, service, upstream};
 
 const CHANNEL_SIZE: usize = 256;
 
@@ -17,6 +17,35 @@ fn main() {
         return;
     }
 
+    if let Err(e) = policy::init_from_env() {
+        common::error!("policy error: {e}");
+        return;
+    }
+
+    if let Err(e) = connector::init_from_env() {
+        common::error!("connector error: {e}");
+        return;
+    }
+
+    if let Err(e) = limits::init_from_env() {
+        common::error!("limits error: {e}");
+        return;
+    }
+
+    // both sides share the audit log of the frontend when it has one
+    match audit::init_from_env() {
+        Ok(()) | Err(audit::Error::AlreadyInitialized) => (),
+        Err(e) => {
+            common::error!("audit log error: {e}");
+            return;
+        }
+    }
+
+    if let Err(e) = upstream::init_from_env() {
+        common::error!("upstream proxy error: {e}");
+        return;
+    }
+
     if let Err(e) = backend_channel.start(service::Kind::Backend, &frontend_to_backend_receive) {
         common::error!("error: {e}");
     } else {